@@ -0,0 +1,269 @@
+/*
+
+ACPI tables
+-----------
+
+The RSDP is searched where the BIOS puts it: in the first KiB of the EBDA, then in 0xE0000..0x100000,
+on 16 bytes boundaries. It points to the RSDT (32-bit table pointers) or, from ACPI 2.0 on, to the XSDT
+(64-bit table pointers), which list every other table.
+Tables are read in place through the physical memory mapping and checked with their checksum.
+The DSDT is not in the root table, the FADT points to it.
+*/
+
+pub mod dsdt;
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+use conquer_once::spin::OnceCell;
+use core::{fmt, mem, str};
+use x86_64::PhysAddr;
+use crate::memory::phys_to_virt;
+
+#[derive(Debug)]
+pub enum AcpiError {
+    RsdpNotFound,
+    BadChecksum([u8; 4]), // signature of the table
+    AlreadyInitialized,
+}
+
+// Header common to every table but the RSDP.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32, // of the whole table, header included
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+pub const SDT_HEADER_SIZE: u64 = mem::size_of::<SdtHeader>() as u64;
+
+// Address spaces of a generic address
+pub const SYSTEM_MEMORY: u8 = 0;
+pub const SYSTEM_IO: u8 = 1;
+
+// Register location used by the FADT and the HPET table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8, // 1: byte, 2: word, 3: dword, 4: qword, 0: undefined
+    pub address: u64,
+}
+
+impl GenericAddress {
+    // None for a null address, which stands for a missing register.
+    pub(crate) fn parse(at: PhysAddr) -> Option<GenericAddress> {
+        let address = GenericAddress {
+            address_space: unsafe { read(at) },
+            bit_width: unsafe { read(at + 1u64) },
+            bit_offset: unsafe { read(at + 2u64) },
+            access_size: unsafe { read(at + 3u64) },
+            address: unsafe { read(at + 4u64) },
+        };
+        Some(address).filter(|address| address.address != 0)
+    }
+
+    // Unsafe because writing a register can do anything. False in an address space other than I/O and memory.
+    pub unsafe fn write(&self, value: u64) -> bool {
+        use x86_64::instructions::port::Port;
+
+        let port = self.address as u16;
+        match (self.address_space, self.width()) {
+            (SYSTEM_IO, 8) => Port::<u8>::new(port).write(value as u8),
+            (SYSTEM_IO, 16) => Port::<u16>::new(port).write(value as u16),
+            (SYSTEM_IO, _) => Port::<u32>::new(port).write(value as u32),
+            (SYSTEM_MEMORY, width) => {
+                let register = phys_to_virt(PhysAddr::new(self.address));
+                match width {
+                    8 => register.as_mut_ptr::<u8>().write_volatile(value as u8),
+                    16 => register.as_mut_ptr::<u16>().write_volatile(value as u16),
+                    32 => register.as_mut_ptr::<u32>().write_volatile(value as u32),
+                    _ => register.as_mut_ptr::<u64>().write_volatile(value),
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn read(&self) -> Option<u64> {
+        use x86_64::instructions::port::Port;
+
+        let port = self.address as u16;
+        let value = unsafe {
+            match (self.address_space, self.width()) {
+                (SYSTEM_IO, 8) => u64::from(Port::<u8>::new(port).read()),
+                (SYSTEM_IO, 16) => u64::from(Port::<u16>::new(port).read()),
+                (SYSTEM_IO, _) => u64::from(Port::<u32>::new(port).read()),
+                (SYSTEM_MEMORY, width) => {
+                    let register = phys_to_virt(PhysAddr::new(self.address));
+                    match width {
+                        8 => u64::from(register.as_ptr::<u8>().read_volatile()),
+                        16 => u64::from(register.as_ptr::<u16>().read_volatile()),
+                        32 => u64::from(register.as_ptr::<u32>().read_volatile()),
+                        _ => register.as_ptr::<u64>().read_volatile(),
+                    }
+                }
+                _ => return None,
+            }
+        };
+        Some(value)
+    }
+
+    // Bits of one access: the access size when given, the register width otherwise.
+    fn width(&self) -> u8 {
+        match self.access_size {
+            1..=4 => 8 << (self.access_size - 1),
+            _ => self.bit_width.clamp(8, 64).next_power_of_two(),
+        }
+    }
+
+    // I/O port block given by an ACPI 1.0 field.
+    pub(crate) fn io(port: u64, bit_width: u8) -> GenericAddress {
+        GenericAddress { address_space: SYSTEM_IO, bit_width, bit_offset: 0, access_size: 0, address: port }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0 and later
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_SIZE: u64 = 20;
+
+// The RSDT or the XSDT.
+struct RootTable {
+    address: PhysAddr,
+    entry_size: u64, // 4 for the RSDT, 8 for the XSDT
+}
+
+static ROOT_TABLE: OnceCell<RootTable> = OnceCell::uninit();
+
+// Find the root table. Needs `memory::init`.
+pub fn init() -> Result<(), AcpiError> {
+    let rsdp_address = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    let rsdp: Rsdp = unsafe { read(rsdp_address) };
+    let root = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        RootTable { address: PhysAddr::new(rsdp.xsdt_address), entry_size: 8 }
+    } else {
+        RootTable { address: PhysAddr::new(u64::from(rsdp.rsdt_address)), entry_size: 4 }
+    };
+    let header = read_header(root.address);
+    if !checksum_ok(root.address, u64::from(header.length)) {
+        return Err(AcpiError::BadChecksum(header.signature));
+    }
+    ROOT_TABLE.try_init_once(|| root).map_err(|_| AcpiError::AlreadyInitialized)
+}
+
+// Address of every table listed by the root table, nothing before `init`.
+pub fn tables() -> impl Iterator<Item = PhysAddr> {
+    let (address, entry_size, count) = match ROOT_TABLE.get() {
+        Some(root) => {
+            let length = u64::from(read_header(root.address).length);
+            (root.address, root.entry_size, (length - SDT_HEADER_SIZE) / root.entry_size)
+        }
+        None => (PhysAddr::zero(), 8, 0),
+    };
+    (0..count).map(move |index| {
+        let entry = address + SDT_HEADER_SIZE + index * entry_size;
+        let table = match entry_size {
+            4 => u64::from(unsafe { read::<u32>(entry) }),
+            _ => unsafe { read::<u64>(entry) },
+        };
+        PhysAddr::new(table)
+    })
+}
+
+// First table with this signature and a valid checksum.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    tables().find(|&table| {
+        let header = read_header(table);
+        header.signature == *signature && checksum_ok(table, u64::from(header.length))
+    })
+}
+
+// Every table found, the DSDT included, with a bad checksum too.
+pub fn list() -> impl Iterator<Item = TableInfo> {
+    let dsdt = fadt::Fadt::get().map(|fadt| fadt.dsdt).filter(|dsdt| !dsdt.is_null());
+    tables().chain(dsdt).map(|address| {
+        let header = read_header(address);
+        TableInfo { address, header, checksum_ok: checksum_ok(address, u64::from(header.length)) }
+    })
+}
+
+pub struct TableInfo {
+    pub address: PhysAddr,
+    pub header: SdtHeader,
+    pub checksum_ok: bool,
+}
+
+impl fmt::Display for TableInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let SdtHeader { signature, length, revision, oem_id, .. } = self.header;
+        let (signature, oem_id) = (text(&signature), text(&oem_id));
+        write!(f, "{} at {:#x}, {} bytes, revision {}, OEM {}", signature, self.address.as_u64(), length, revision, oem_id)?;
+        if !self.checksum_ok {
+            write!(f, " (bad checksum)")?;
+        }
+        Ok(())
+    }
+}
+
+fn text(bytes: &[u8]) -> &str {
+    str::from_utf8(bytes).map(str::trim_end).unwrap_or("?")
+}
+
+pub fn read_header(table: PhysAddr) -> SdtHeader {
+    unsafe { read(table) }
+}
+
+// Read a `T` at a physical address, tables have no alignment guarantee.
+// Unsafe because the memory must hold a valid `T`.
+pub(crate) unsafe fn read<T: Copy>(address: PhysAddr) -> T {
+    phys_to_virt(address).as_ptr::<T>().read_unaligned()
+}
+
+// The bytes of a table sum up to 0.
+fn checksum_ok(address: PhysAddr, length: u64) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(phys_to_virt(address).as_ptr::<u8>(), length as usize) };
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = u64::from(unsafe { read::<u16>(PhysAddr::new(0x40e)) }) << 4; // segment stored by the BIOS
+    let areas = [(ebda, ebda + 1024), (0xe0000, 0x100000)];
+    areas.iter()
+        .filter(|&&(start, _)| start != 0)
+        .flat_map(|&(start, end)| (start..end).step_by(16))
+        .map(PhysAddr::new)
+        .find(|&address| is_rsdp(address))
+}
+
+fn is_rsdp(address: PhysAddr) -> bool {
+    let signature: [u8; 8] = unsafe { read(address) };
+    if signature != *RSDP_SIGNATURE || !checksum_ok(address, RSDP_V1_SIZE) {
+        return false;
+    }
+    let rsdp: Rsdp = unsafe { read(address) };
+    rsdp.revision < 2 || checksum_ok(address, u64::from(rsdp.length))
+}
@@ -0,0 +1,51 @@
+// Differentiated System Description Table: AML code. There is no interpreter, only the `\_S5` object
+// (the sleep type values for soft-off) is looked up, the way it is compiled by every ACPI compiler:
+// `Name (_S5, Package () { SLP_TYPa, SLP_TYPb, ... })`.
+
+use super::{fadt::Fadt, read, read_header, SDT_HEADER_SIZE};
+
+const NAME_OP: u8 = 0x08;
+const ROOT_PREFIX: u8 = 0x5c; // `\`
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+
+// SLP_TYPa and SLP_TYPb of the S5 state, None before `acpi::init` or without `\_S5`.
+pub fn sleep_type_s5() -> Option<(u16, u16)> {
+    let dsdt = Fadt::get()?.dsdt;
+    let header = read_header(dsdt);
+    if header.signature != *b"DSDT" {
+        return None;
+    }
+    let aml = |offset: u64| unsafe { read::<u8>(dsdt + SDT_HEADER_SIZE + offset) };
+    let length = u64::from(header.length).saturating_sub(SDT_HEADER_SIZE);
+
+    let name = (2..length.saturating_sub(4)).find(|&offset| {
+        [aml(offset), aml(offset + 1), aml(offset + 2), aml(offset + 3)] == *b"_S5_"
+            && (aml(offset - 1) == NAME_OP || (aml(offset - 1) == ROOT_PREFIX && aml(offset - 2) == NAME_OP))
+    })?;
+    let mut offset = name + 4;
+    if aml(offset) != PACKAGE_OP {
+        return None;
+    }
+    offset += 1;
+    offset += 1 + u64::from(aml(offset) >> 6); // package length: the top bits give the bytes following the first
+    offset += 1; // number of elements
+
+    let mut integer = || {
+        let (value, size) = match aml(offset) {
+            ZERO_OP => (0, 1),
+            ONE_OP => (1, 1),
+            BYTE_PREFIX => (u16::from(aml(offset + 1)), 2),
+            WORD_PREFIX => (u16::from(aml(offset + 1)) | u16::from(aml(offset + 2)) << 8, 3),
+            _ => return None,
+        };
+        offset += size;
+        (offset <= length).then(|| value)
+    };
+    let a = integer()?;
+    let b = integer()?;
+    Some((a, b))
+}
@@ -0,0 +1,90 @@
+// Fixed ACPI Description Table: the power management registers, the reset register and the DSDT.
+
+use super::{find_table, read, GenericAddress};
+use x86_64::PhysAddr;
+
+const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+
+const RESET_REG_SUP: u32 = 1 << 10; // flags
+const BOOT_ARCH_8042: u16 = 1 << 1; // IA-PC boot architecture flags
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub revision: u8,
+    pub dsdt: PhysAddr,
+    pub sci_interrupt: u16, // ISA IRQ of the system control interrupt
+    pub smi_command: u16, // port to switch to ACPI mode, 0 if always in ACPI mode
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event: Option<GenericAddress>,
+    pub pm1b_event: Option<GenericAddress>,
+    pub pm1a_control: Option<GenericAddress>,
+    pub pm1b_control: Option<GenericAddress>,
+    pub pm_timer: Option<GenericAddress>,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    pub century: u8, // CMOS register of the century, 0 if none
+    pub boot_architecture: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    // None before `acpi::init` or without a valid FADT.
+    pub fn get() -> Option<Fadt> {
+        let table = find_table(FADT_SIGNATURE)?;
+        let header = super::read_header(table);
+        let length = u64::from(header.length);
+        // fields past the ACPI 1.0 table are read only when present
+        let field = |offset: u64, size: u64| offset + size <= length;
+        let read_u8 = |offset: u64| if field(offset, 1) { unsafe { read::<u8>(table + offset) } } else { 0 };
+        let read_u16 = |offset: u64| if field(offset, 2) { unsafe { read::<u16>(table + offset) } } else { 0 };
+        let read_u32 = |offset: u64| if field(offset, 4) { unsafe { read::<u32>(table + offset) } } else { 0 };
+        let read_u64 = |offset: u64| if field(offset, 8) { unsafe { read::<u64>(table + offset) } } else { 0 };
+        let generic = |offset: u64| {
+            if field(offset, 12) { GenericAddress::parse(table + offset) } else { None }
+        };
+        // the 64-bit X_ block wins over the 32-bit port of the same block
+        let block = |port_offset: u64, length_offset: u64, x_offset: u64| {
+            generic(x_offset).or_else(|| match read_u32(port_offset) {
+                0 => None,
+                port => Some(GenericAddress::io(u64::from(port), read_u8(length_offset) * 8)),
+            })
+        };
+
+        let dsdt = match read_u64(140) {
+            0 => u64::from(read_u32(40)),
+            x_dsdt => x_dsdt,
+        };
+        Some(Fadt {
+            revision: header.revision,
+            dsdt: PhysAddr::new(dsdt),
+            sci_interrupt: read_u16(46),
+            smi_command: read_u32(48) as u16,
+            acpi_enable: read_u8(52),
+            acpi_disable: read_u8(53),
+            pm1a_event: block(56, 88, 148),
+            pm1b_event: block(60, 88, 160),
+            pm1a_control: block(64, 89, 172),
+            pm1b_control: block(68, 89, 184),
+            pm_timer: block(76, 91, 208),
+            pm1_event_length: read_u8(88),
+            pm1_control_length: read_u8(89),
+            century: read_u8(108),
+            boot_architecture: if header.revision >= 2 { read_u16(109) } else { BOOT_ARCH_8042 },
+            flags: read_u32(112),
+            reset_register: generic(116),
+            reset_value: read_u8(128),
+        })
+    }
+
+    // ACPI 1.0 tables say nothing about the 8042 and every PC had one.
+    pub fn has_8042(&self) -> bool {
+        self.boot_architecture & BOOT_ARCH_8042 != 0
+    }
+
+    pub fn supports_reset_register(&self) -> bool {
+        self.flags & RESET_REG_SUP != 0 && self.reset_register.is_some()
+    }
+}
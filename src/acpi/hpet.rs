@@ -0,0 +1,38 @@
+// High Precision Event Timer description table: where the HPET registers are and what the timer block supports.
+
+use super::{find_table, read, GenericAddress, SDT_HEADER_SIZE, SYSTEM_MEMORY};
+use x86_64::PhysAddr;
+
+const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+
+#[derive(Debug, Clone, Copy)]
+pub struct HpetTable {
+    pub base_address: PhysAddr, // of the registers
+    pub hpet_number: u8,
+    pub minimum_tick: u16, // smallest periodic interval the hardware supports, in counter ticks
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    pub legacy_replacement: bool, // can replace the PIT and the RTC interrupts
+    pub pci_vendor_id: u16,
+}
+
+impl HpetTable {
+    // None before `acpi::init`, without a valid HPET table or with registers not in memory space.
+    pub fn get() -> Option<HpetTable> {
+        let address = find_table(HPET_SIGNATURE)?;
+        let block_id: u32 = unsafe { read(address + SDT_HEADER_SIZE) };
+        let registers = GenericAddress::parse(address + SDT_HEADER_SIZE + 4u64)?;
+        if registers.address_space != SYSTEM_MEMORY {
+            return None;
+        }
+        Some(HpetTable {
+            base_address: PhysAddr::new(registers.address),
+            hpet_number: unsafe { read(address + SDT_HEADER_SIZE + 16u64) },
+            minimum_tick: unsafe { read(address + SDT_HEADER_SIZE + 17u64) },
+            comparators: ((block_id >> 8) & 0x1f) as u8 + 1,
+            counter_64bit: block_id & (1 << 13) != 0,
+            legacy_replacement: block_id & (1 << 15) != 0,
+            pci_vendor_id: (block_id >> 16) as u16,
+        })
+    }
+}
@@ -0,0 +1,102 @@
+// Multiple APIC Description Table: the local APICs, I/O APICs and how ISA IRQs are wired to them.
+
+use super::{find_table, read, SdtHeader, SDT_HEADER_SIZE};
+use x86_64::PhysAddr;
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const PCAT_COMPAT: u32 = 1 << 0; // the machine also has the 8259 PICs
+
+// Polarity and trigger mode of an interrupt source override (MPS INTI flags).
+pub const POLARITY_MASK: u16 = 0b11;
+pub const POLARITY_ACTIVE_LOW: u16 = 0b11;
+pub const TRIGGER_MASK: u16 = 0b11 << 2;
+pub const TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+#[derive(Debug, Clone, Copy)]
+pub enum MadtEntry {
+    LocalApic { processor_id: u8, apic_id: u8, flags: u32 },
+    IoApic { id: u8, address: u32, gsi_base: u32 },
+    InterruptSourceOverride { bus: u8, source: u8, gsi: u32, flags: u16 }, // ISA IRQ `source` is wired to `gsi`
+    LocalApicNmi { processor_id: u8, flags: u16, lint: u8 },
+    LocalApicAddressOverride { address: u64 },
+    Other { entry_type: u8 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    address: PhysAddr,
+    length: u64,
+    local_apic_address: u32,
+    flags: u32,
+}
+
+impl Madt {
+    // None before `acpi::init` or without a valid MADT.
+    pub fn get() -> Option<Madt> {
+        let address = find_table(MADT_SIGNATURE)?;
+        let header: SdtHeader = unsafe { read(address) };
+        Some(Madt {
+            address,
+            length: u64::from(header.length),
+            local_apic_address: unsafe { read(address + SDT_HEADER_SIZE) },
+            flags: unsafe { read(address + SDT_HEADER_SIZE + 4u64) },
+        })
+    }
+
+    pub fn has_legacy_pics(&self) -> bool {
+        self.flags & PCAT_COMPAT != 0
+    }
+
+    pub fn local_apic_address(&self) -> PhysAddr {
+        let overridden = self.entries().find_map(|entry| match entry {
+            MadtEntry::LocalApicAddressOverride { address } => Some(address),
+            _ => None,
+        });
+        PhysAddr::new(overridden.unwrap_or_else(|| u64::from(self.local_apic_address)))
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = MadtEntry> {
+        let end = self.address + self.length;
+        let mut next = self.address + SDT_HEADER_SIZE + 8u64;
+        core::iter::from_fn(move || {
+            if next + 2u64 > end {
+                return None;
+            }
+            let (entry_type, length): (u8, u8) = unsafe { (read(next), read(next + 1u64)) };
+            if length < 2 || next + u64::from(length) > end {
+                return None; // malformed table, stop there
+            }
+            let entry = unsafe { parse_entry(next, entry_type) };
+            next += u64::from(length);
+            Some(entry)
+        })
+    }
+}
+
+unsafe fn parse_entry(entry: PhysAddr, entry_type: u8) -> MadtEntry {
+    match entry_type {
+        0 => MadtEntry::LocalApic {
+            processor_id: read(entry + 2u64),
+            apic_id: read(entry + 3u64),
+            flags: read(entry + 4u64),
+        },
+        1 => MadtEntry::IoApic {
+            id: read(entry + 2u64),
+            address: read(entry + 4u64),
+            gsi_base: read(entry + 8u64),
+        },
+        2 => MadtEntry::InterruptSourceOverride {
+            bus: read(entry + 2u64),
+            source: read(entry + 3u64),
+            gsi: read(entry + 4u64),
+            flags: read(entry + 8u64),
+        },
+        4 => MadtEntry::LocalApicNmi {
+            processor_id: read(entry + 2u64),
+            flags: read(entry + 3u64),
+            lint: read(entry + 5u64),
+        },
+        5 => MadtEntry::LocalApicAddressOverride { address: read(entry + 4u64) },
+        entry_type => MadtEntry::Other { entry_type },
+    }
+}
@@ -0,0 +1,37 @@
+// PCI Express memory mapped configuration table: where the ECAM region of each PCI segment is.
+
+use super::{find_table, read, read_header, SDT_HEADER_SIZE};
+use x86_64::PhysAddr;
+
+const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
+const ENTRY_SIZE: u64 = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    pub base_address: PhysAddr, // of bus 0, even when `start_bus` is not 0
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+// Nothing before `acpi::init` or without a valid MCFG (e.g. QEMU's i440FX machine, q35 has one).
+pub fn entries() -> impl Iterator<Item = McfgEntry> {
+    let (table, count) = match find_table(MCFG_SIGNATURE) {
+        Some(table) => {
+            let length = u64::from(read_header(table).length);
+            (table, length.saturating_sub(SDT_HEADER_SIZE + 8) / ENTRY_SIZE)
+        }
+        None => (PhysAddr::zero(), 0),
+    };
+    (0..count).map(move |index| {
+        let entry = table + SDT_HEADER_SIZE + 8u64 + index * ENTRY_SIZE; // after 8 reserved bytes
+        unsafe {
+            McfgEntry {
+                base_address: PhysAddr::new(read(entry)),
+                segment: read(entry + 8u64),
+                start_bus: read(entry + 10u64),
+                end_bus: read(entry + 11u64),
+            }
+        }
+    })
+}
@@ -1,14 +1,29 @@
+pub mod debug;
 pub mod fixed_size_block;
+pub mod slab;
 
+#[cfg(not(feature = "slab_allocator"))]
 use fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "slab_allocator")]
+use slab::SlabAllocator;
 
+#[cfg(not(feature = "slab_allocator"))]
 #[global_allocator]
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new()); // SpinLock to avoid deadlock when multiple threads are allocating memory
 
+#[cfg(feature = "slab_allocator")]
+#[global_allocator]
+static ALLOCATOR: Locked<SlabAllocator> = Locked::new(SlabAllocator::new()); // cargo feature `slab_allocator`
+
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB, mapped by init_heap
+pub const HEAP_MAX_SIZE: usize = 256 * 1024 * 1024; // default limit of the growth, see set_heap_limit
+pub const HEAP_GROW_SIZE: usize = 64 * 1024; // the heap grows by at least this much at once
 
 
+use alloc::alloc::Layout;
+use core::fmt;
+use core::ptr::{self, NonNull};
 use x86_64::{
     structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB},
     VirtAddr
@@ -18,10 +33,33 @@ use x86_64::{
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    map_heap_pages(HEAP_START, HEAP_SIZE, mapper, frame_allocator)?;
+
+    unsafe  {
+        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    }
+
+    Ok(())
+}
+
+// Let the heap grow up to `HEAP_START + max_size`. It never shrinks below its current size.
+pub fn set_heap_limit(max_size: usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        ALLOCATOR.lock().set_limit(HEAP_START + max_size);
+    });
+}
+
+// Map [start, start + size) for the heap.
+fn map_heap_pages(
+    start: usize,
+    size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
     let page_range =  { // Create a new page range
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64; // -1 because heap_end is inclusive
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64; // -1 because heap_end is inclusive
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
@@ -33,12 +71,186 @@ pub fn init_heap(
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE; // Access both read and write
         unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() }; // create the mapping in the active page table
     }
+    Ok(())
+}
 
-    unsafe  {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+// Map up to `size` more heap bytes with the kernel memory, called by the allocator when it runs out.
+// Return how many bytes were mapped: less than `size` if physical memory is exhausted,
+// 0 if the kernel memory is busy (e.g. the heap is used while it is locked) or not registered yet.
+fn grow_heap(start: usize, size: usize) -> usize {
+    crate::memory::try_with_kernel_memory(|memory| {
+        let mut mapped = 0;
+        while mapped < size {
+            let page_start = start + mapped;
+            if map_heap_pages(page_start, 4096, &mut memory.mapper, &mut memory.frame_allocator).is_err() {
+                break;
+            }
+            mapped += 4096;
+        }
+        mapped
+    }).unwrap_or(0)
+}
+
+// Heap mapped from HEAP_START, used by the allocators for what their blocks can't serve.
+// When it is full, more pages are mapped at its end, up to the limit.
+pub struct GrowableHeap {
+    heap: linked_list_allocator::Heap,
+    heap_end: usize, // first address after the mapped heap
+    heap_limit: usize, // the heap never grows past this address
+}
+
+impl GrowableHeap {
+    pub const fn empty() -> Self {
+        GrowableHeap {
+            heap: linked_list_allocator::Heap::empty(),
+            heap_end: 0,
+            heap_limit: 0,
+        }
     }
 
-    Ok(())
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap.init(heap_start, heap_size);
+        self.heap_end = heap_start + heap_size;
+        self.heap_limit = heap_start + HEAP_MAX_SIZE;
+    }
+
+    pub fn set_limit(&mut self, heap_limit: usize) {
+        self.heap_limit = heap_limit.max(self.heap_end);
+    }
+
+    // Null if the heap is full and can't grow.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.heap.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+        if !self.grow(layout) { // out of heap: map more pages and try again
+            return ptr::null_mut();
+        }
+        match self.heap.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        self.heap.deallocate(NonNull::new(ptr).unwrap(), layout);
+    }
+
+    // Map pages after the end of the heap, enough for `layout` even if the free space at the end is tiny.
+    // True if the heap grew.
+    fn grow(&mut self, layout: Layout) -> bool {
+        let needed = layout.size() + layout.align();
+        let size = (needed.max(HEAP_GROW_SIZE) + 4095) & !4095; // whole pages
+        if self.heap_end == 0 || self.heap_end + size > self.heap_limit {
+            return false;
+        }
+        let mapped = grow_heap(self.heap_end, size);
+        if mapped > 0 {
+            unsafe { self.heap.extend(mapped) }; // the new pages follow the current top of the heap
+            self.heap_end += mapped;
+        }
+        // Physical memory may run out before `size`: the pages mapped so far stay in the heap,
+        // the caller tries again and fails only if they are not enough.
+        mapped > 0
+    }
+}
+
+// Snapshot of the heap usage, see `stats`.
+pub fn stats() -> HeapStats {
+    x86_64::instructions::interrupts::without_interrupts(|| ALLOCATOR.lock().stats())
+}
+
+pub const MAX_SIZE_CLASSES: usize = 9; // size classes of the allocator with the most
+
+// Counters of one size class, or of the fallback heap.
+// Bytes are block sizes for a size class (what the heap really uses), requested sizes for the fallback heap.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllocationStats {
+    pub current_bytes: usize,
+    pub peak_bytes: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    pub failed: u64, // allocations that returned null
+}
+
+impl AllocationStats {
+    const fn new() -> Self {
+        AllocationStats { current_bytes: 0, peak_bytes: 0, allocations: 0, frees: 0, failed: 0 }
+    }
+
+    pub fn live(&self) -> u64 {
+        self.allocations - self.frees
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub class_sizes: &'static [usize], // block size of each entry of `classes`
+    pub classes: [AllocationStats; MAX_SIZE_CLASSES], // entries after class_sizes.len() stay empty
+    pub fallback: AllocationStats,
+    pub peak_bytes: usize, // of all classes and the fallback heap together
+}
+
+impl HeapStats {
+    pub const fn new(class_sizes: &'static [usize]) -> Self {
+        assert!(class_sizes.len() <= MAX_SIZE_CLASSES);
+        HeapStats {
+            class_sizes,
+            classes: [AllocationStats::new(); MAX_SIZE_CLASSES],
+            fallback: AllocationStats::new(),
+            peak_bytes: 0,
+        }
+    }
+
+    pub fn live_allocations(&self) -> u64 {
+        self.classes.iter().map(AllocationStats::live).sum::<u64>() + self.fallback.live()
+    }
+
+    pub fn current_bytes(&self) -> usize {
+        self.classes.iter().map(|c| c.current_bytes).sum::<usize>() + self.fallback.current_bytes
+    }
+
+    fn counters(&mut self, class: Option<usize>) -> &mut AllocationStats {
+        match class {
+            Some(index) => &mut self.classes[index],
+            None => &mut self.fallback,
+        }
+    }
+
+    // `size` is the block size for a class, the layout size for the fallback heap.
+    pub fn record_alloc(&mut self, class: Option<usize>, size: usize, ptr: *mut u8) {
+        let counters = self.counters(class);
+        if ptr.is_null() {
+            counters.failed += 1;
+            return;
+        }
+        counters.allocations += 1;
+        counters.current_bytes += size;
+        counters.peak_bytes = counters.peak_bytes.max(counters.current_bytes);
+        self.peak_bytes = self.peak_bytes.max(self.current_bytes());
+    }
+
+    pub fn record_free(&mut self, class: Option<usize>, size: usize) {
+        let counters = self.counters(class);
+        counters.frees += 1;
+        counters.current_bytes -= size;
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:>8} {:>10} {:>10} {:>8} {:>8} {:>6}", "class", "bytes", "peak", "allocs", "frees", "failed")?;
+        let classes = self.class_sizes.iter().map(|size| Some(*size)).zip(self.classes.iter());
+        for (size, counters) in classes.chain(core::iter::once((None, &self.fallback))) {
+            match size {
+                Some(size) => write!(f, "{:>8}", size)?,
+                None => write!(f, "{:>8}", "fallback")?,
+            }
+            writeln!(f, " {:>10} {:>10} {:>8} {:>8} {:>6}", counters.current_bytes, counters.peak_bytes,
+                counters.allocations, counters.frees, counters.failed)?;
+        }
+        write!(f, "live allocations: {}, bytes: {}, peak bytes: {}", self.live_allocations(), self.current_bytes(), self.peak_bytes)
+    }
 }
 
 pub struct Locked<A> {
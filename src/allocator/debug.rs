@@ -0,0 +1,85 @@
+/*
+
+Debug allocator checks
+----------------------
+
+With the `debug_allocator` feature, every allocation gets guard bytes before and after it (red zones),
+checked when it is freed. Freed blocks are filled with a poison pattern, checked again when the
+block is handed out, so a write after free is caught on the next allocation of that size.
+Double frees are detected by the fixed-size block allocator by looking for the block in its free list.
+
+Without the feature every function here is a no-op and the layouts are unchanged.
+Violations panic with the layout and address of the allocation.
+*/
+
+use alloc::alloc::Layout;
+
+pub const ENABLED: bool = cfg!(feature = "debug_allocator");
+
+const GUARD_SIZE: usize = 16; // bytes of red zone on each side
+const GUARD_BYTE: u8 = 0xab;
+const POISON_BYTE: u8 = 0xdd; // freed memory
+
+// Bytes before the allocation: at least GUARD_SIZE and keeps the alignment.
+fn front_size(layout: &Layout) -> usize {
+    GUARD_SIZE.max(layout.align())
+}
+
+// Layout of the block holding the allocation and its red zones.
+pub fn padded(layout: Layout) -> Layout {
+    if !ENABLED {
+        return layout;
+    }
+    let size = front_size(&layout) + layout.size() + GUARD_SIZE;
+    Layout::from_size_align(size, layout.align()).unwrap()
+}
+
+// Address of the block holding the allocation at `ptr`.
+pub fn block_start(ptr: *mut u8, layout: &Layout) -> *mut u8 {
+    if !ENABLED {
+        return ptr;
+    }
+    ptr.wrapping_sub(front_size(layout))
+}
+
+// Write the red zones around the allocation and return its address, `block` is a `padded(layout)` block.
+pub unsafe fn arm(block: *mut u8, layout: &Layout) -> *mut u8 {
+    if !ENABLED || block.is_null() {
+        return block;
+    }
+    let front = front_size(layout);
+    block.write_bytes(GUARD_BYTE, front);
+    block.add(front + layout.size()).write_bytes(GUARD_BYTE, GUARD_SIZE);
+    block.add(front)
+}
+
+// Panic if a red zone around the allocation at `ptr` was overwritten.
+pub unsafe fn check_guards(ptr: *mut u8, layout: &Layout) {
+    if !ENABLED {
+        return;
+    }
+    let front = front_size(layout);
+    if !all(ptr.sub(front), front, GUARD_BYTE) {
+        panic!("heap corruption: guard bytes before {:p} overwritten, layout {:?}", ptr, layout);
+    }
+    if !all(ptr.add(layout.size()), GUARD_SIZE, GUARD_BYTE) {
+        panic!("heap corruption: write past the end of {:p}, layout {:?}", ptr, layout);
+    }
+}
+
+pub unsafe fn poison(block: *mut u8, size: usize) {
+    if ENABLED {
+        block.write_bytes(POISON_BYTE, size);
+    }
+}
+
+// Panic if the poisoned bytes [skip, size) of a free block were written.
+pub unsafe fn check_poison(block: *mut u8, size: usize, skip: usize) {
+    if ENABLED && !all(block.add(skip), size - skip, POISON_BYTE) {
+        panic!("use after free: free block {:p} of {} bytes was written", block, size);
+    }
+}
+
+unsafe fn all(start: *const u8, len: usize, byte: u8) -> bool {
+    core::slice::from_raw_parts(start, len).iter().all(|&b| b == byte)
+}
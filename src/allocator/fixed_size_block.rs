@@ -7,7 +7,7 @@ Round up the requested allocation size to the next block size. For example, when
 Retrieve the head pointer for the list, e.g. from an array. For block size 16, we need to use head_16.
 Remove the first block from the list and return it.
 
-TODO: Try implementing a slab allocator.
+See slab.rs for the slab allocator alternative, and debug.rs for the checks of the `debug_allocator` feature.
 */
 
 struct ListNode  {
@@ -18,7 +18,8 @@ const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
 pub struct FixedSizeBlockAllocator  {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()], // array of head pointers
-    fallback_allocator: linked_list_allocator::Heap, // fallback allocator, if no block is available
+    fallback_allocator: GrowableHeap, // fallback allocator, if no block is available
+    stats: HeapStats,
 }
 
 impl FixedSizeBlockAllocator  {
@@ -27,24 +28,41 @@ impl FixedSizeBlockAllocator  {
         const EMPTY: Option<&'static mut ListNode> = None;
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
-            fallback_allocator: linked_list_allocator::Heap::empty(),
+            fallback_allocator: GrowableHeap::empty(),
+            stats: HeapStats::new(BLOCK_SIZES),
         }
     }
 
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.fallback_allocator.init(heap_start, heap_size);
     }
+
+    pub fn set_limit(&mut self, heap_limit: usize) {
+        self.fallback_allocator.set_limit(heap_limit);
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.stats
+    }
 }
 
 use alloc::alloc::Layout;
-use core::ptr;
 
 impl FixedSizeBlockAllocator {
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        self.fallback_allocator.allocate(layout)
+    }
+
+    // Is `block` on the free list of size class `index`, walks the list.
+    fn is_free(&self, index: usize, block: *mut u8) -> bool {
+        let mut node = self.list_heads[index].as_deref();
+        while let Some(current) = node {
+            if current as *const ListNode as *mut u8 == block {
+                return true;
+            }
+            node = current.next.as_deref();
         }
+        false
     }
 }
 
@@ -54,19 +72,41 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
-use super::Locked;
-use core::{mem, ptr::NonNull};
+// Bytes counted in the stats: the whole block for a size class.
+fn recorded_size(index: Option<usize>, layout: &Layout) -> usize {
+    index.map_or(layout.size(), |index| BLOCK_SIZES[index])
+}
+
+use super::{debug, GrowableHeap, HeapStats, Locked};
+use core::mem;
 use alloc::alloc::GlobalAlloc;
+use x86_64::instructions::interrupts;
 
+// Interrupts are disabled while the lock is held: a thread preempted inside the allocator
+// would otherwise block every other thread (and interrupt handler) that allocates.
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout:Layout) -> *mut u8  {
+        interrupts::without_interrupts(|| self.alloc_locked(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| self.dealloc_locked(ptr, layout))
+    }
+}
+
+impl Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc_locked(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock(); // get a mutable reference to the allocator
-        match list_index(&layout) { // calculate the appropriate block size for the given layout
+        let block_layout = debug::padded(layout); // room for the red zones with the debug allocator
+        let index = list_index(&block_layout);
+        let block = match index { // calculate the appropriate block size for the given layout
             Some(index) => {
                match allocator.list_heads[index].take() {
                    Some(node) => {
                        allocator.list_heads[index] = node.next.take(); // Try to remove the first block from the list
-                       node as *mut ListNode as *mut u8
+                       let block = node as *mut ListNode as *mut u8;
+                       debug::check_poison(block, BLOCK_SIZES[index], mem::size_of::<ListNode>());
+                       block
                    },
                    None => {  // Need to construct a new block
                        let block_size = BLOCK_SIZES[index]; // No block exists in list, allocate a new block
@@ -77,30 +117,45 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                }
             }
             None => { // no block available, allocate from the fallback allocator
-                allocator.fallback_alloc(layout)
+                allocator.fallback_alloc(block_layout)
             }
-        }
-        
+        };
+        let ptr = debug::arm(block, &layout);
+        allocator.stats.record_alloc(index, recorded_size(index, &layout), ptr);
+        ptr
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn dealloc_locked(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock(); // get a mutable reference to the allocator
-        match list_index(&layout) {
+        let block_layout = debug::padded(layout);
+        let index = list_index(&block_layout);
+        let block = debug::block_start(ptr, &layout);
+        if debug::ENABLED {
+            if let Some(index) = index {
+                if allocator.is_free(index, block) {
+                    panic!("double free of {:p}, layout {:?}", ptr, layout);
+                }
+            }
+            debug::check_guards(ptr, &layout);
+        }
+        allocator.stats.record_free(index, recorded_size(index, &layout));
+        match index {
             Some(index) => {
+                debug::poison(block, BLOCK_SIZES[index]);
                 let new_node = ListNode {
                     next: allocator.list_heads[index].take(),
                 };
                 assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]); // Make sure the block size is large enough to hold the ListNode
                 assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]); // Make sure the block size is aligned to the ListNode
-                let new_node_ptr = ptr as *mut ListNode;
+                let new_node_ptr = block as *mut ListNode;
                 new_node_ptr.write(new_node); // write the new node to the list
                 allocator.list_heads[index] = Some(&mut *new_node_ptr);
             }
             None => { // Not created by our implementation, give it back to the fallback allocator 
-                let ptr = NonNull::new(ptr).unwrap();
-                allocator.fallback_allocator.deallocate(ptr, layout);
+                debug::poison(block, block_layout.size());
+                allocator.fallback_allocator.deallocate(block, block_layout);
             }
         }
     }
     
-}
\ No newline at end of file
+}
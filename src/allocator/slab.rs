@@ -0,0 +1,329 @@
+/*
+
+Slab Allocator
+--------------
+
+Objects of one size are carved out of page-sized slabs. A slab starts with a `Slab` header,
+followed by as many objects as fit, aligned to the object size. Free objects of a slab are linked
+together like the blocks of the fixed-size block allocator.
+
+A cache keeps its slabs in two lists: partial (at least one free object) and full.
+When the last object of a slab is freed, the page is given back to the page allocator.
+The slab of an object is found by rounding its address down to the page.
+
+`SlabAllocator` has one cache per size class and can be the #[global_allocator] (feature `slab_allocator`).
+`ObjectCache` is a named cache for one kind of hot kernel object, it takes its pages from the global allocator.
+*/
+
+use super::{GrowableHeap, HeapStats, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr::{self, NonNull}};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const SLAB_SIZE: usize = 4096;
+
+// Bigger objects go to the fallback allocator, at most 3 of them would fit in a slab.
+const SLAB_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024];
+
+// Where slabs come from and go back to.
+pub trait PageSource {
+    fn allocate_page(&mut self) -> *mut u8; // SLAB_SIZE bytes aligned to SLAB_SIZE, null if out of memory
+    unsafe fn deallocate_page(&mut self, page: *mut u8);
+}
+
+fn page_layout() -> Layout {
+    Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+}
+
+impl PageSource for GrowableHeap {
+    fn allocate_page(&mut self) -> *mut u8 {
+        self.allocate(page_layout())
+    }
+
+    unsafe fn deallocate_page(&mut self, page: *mut u8) {
+        self.deallocate(page, page_layout());
+    }
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+// Header at the start of every slab page.
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    free_objects: *mut FreeObject,
+    in_use: usize,
+}
+
+pub struct SlabCache {
+    object_size: usize, // size of one object, rounded up to its alignment
+    first_object: usize, // offset of the first object in the slab
+    capacity: usize, // objects per slab
+    partial: *mut Slab, // slabs with free objects
+    full: *mut Slab,
+    slabs: usize,
+    objects_in_use: usize,
+}
+
+// The raw pointers only point into slab pages owned by the cache.
+unsafe impl Send for SlabCache {}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
+}
+
+impl SlabCache {
+    // Cache for objects of `size` bytes aligned to `align` (a power of two).
+    pub const fn new(size: usize, align: usize) -> Self {
+        let align = max(align, mem::align_of::<FreeObject>());
+        let object_size = align_up(max(size, mem::size_of::<FreeObject>()), align);
+        let first_object = align_up(mem::size_of::<Slab>(), align);
+        assert!(first_object + object_size <= SLAB_SIZE, "object too big for a slab");
+        SlabCache {
+            object_size,
+            first_object,
+            capacity: (SLAB_SIZE - first_object) / object_size,
+            partial: ptr::null_mut(),
+            full: ptr::null_mut(),
+            slabs: 0,
+            objects_in_use: 0,
+        }
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    pub fn slabs(&self) -> usize {
+        self.slabs
+    }
+
+    pub fn objects_in_use(&self) -> usize {
+        self.objects_in_use
+    }
+
+    // Null if no page could be allocated for a new slab.
+    pub fn allocate(&mut self, pages: &mut impl PageSource) -> *mut u8 {
+        if self.partial.is_null() {
+            let slab = self.new_slab(pages);
+            if slab.is_null() {
+                return ptr::null_mut();
+            }
+            unsafe { push(&mut self.partial, slab) };
+        }
+
+        unsafe {
+            let slab = self.partial;
+            let object = (*slab).free_objects;
+            (*slab).free_objects = (*object).next;
+            (*slab).in_use += 1;
+            if (*slab).in_use == self.capacity { // no free object left
+                remove(&mut self.partial, slab);
+                push(&mut self.full, slab);
+            }
+            self.objects_in_use += 1;
+            object as *mut u8
+        }
+    }
+
+    // Caller must guarantee that `ptr` was returned by `allocate` of this cache.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, pages: &mut impl PageSource) {
+        let slab = (ptr as usize & !(SLAB_SIZE - 1)) as *mut Slab;
+        let object = ptr as *mut FreeObject;
+        object.write(FreeObject { next: (*slab).free_objects });
+        (*slab).free_objects = object;
+        if (*slab).in_use == self.capacity {
+            remove(&mut self.full, slab);
+            push(&mut self.partial, slab);
+        }
+        (*slab).in_use -= 1;
+        self.objects_in_use -= 1;
+
+        if (*slab).in_use == 0 { // empty slab, give the page back
+            remove(&mut self.partial, slab);
+            pages.deallocate_page(slab as *mut u8);
+            self.slabs -= 1;
+        }
+    }
+
+    fn new_slab(&mut self, pages: &mut impl PageSource) -> *mut Slab {
+        let page = pages.allocate_page();
+        if page.is_null() {
+            return ptr::null_mut();
+        }
+        assert_eq!(page as usize % SLAB_SIZE, 0, "page source returned an unaligned page");
+
+        unsafe {
+            // link every object, the first one ends up at the head of the list
+            let mut free_objects: *mut FreeObject = ptr::null_mut();
+            for index in (0..self.capacity).rev() {
+                let object = page.add(self.first_object + index * self.object_size) as *mut FreeObject;
+                object.write(FreeObject { next: free_objects });
+                free_objects = object;
+            }
+            let slab = page as *mut Slab;
+            slab.write(Slab {
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                free_objects,
+                in_use: 0,
+            });
+            self.slabs += 1;
+            slab
+        }
+    }
+}
+
+unsafe fn push(list: &mut *mut Slab, slab: *mut Slab) {
+    (*slab).prev = ptr::null_mut();
+    (*slab).next = *list;
+    if !list.is_null() {
+        (**list).prev = slab;
+    }
+    *list = slab;
+}
+
+unsafe fn remove(list: &mut *mut Slab, slab: *mut Slab) {
+    if (*slab).prev.is_null() {
+        *list = (*slab).next;
+    } else {
+        (*(*slab).prev).next = (*slab).next;
+    }
+    if !(*slab).next.is_null() {
+        (*(*slab).next).prev = (*slab).prev;
+    }
+}
+
+pub struct SlabAllocator {
+    caches: [SlabCache; SLAB_SIZES.len()], // one cache per size class
+    fallback_allocator: GrowableHeap, // pages for the slabs and allocations bigger than a size class
+    stats: HeapStats,
+}
+
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        SlabAllocator {
+            caches: [
+                SlabCache::new(8, 8),
+                SlabCache::new(16, 16),
+                SlabCache::new(32, 32),
+                SlabCache::new(64, 64),
+                SlabCache::new(128, 128),
+                SlabCache::new(256, 256),
+                SlabCache::new(512, 512),
+                SlabCache::new(1024, 1024),
+            ],
+            fallback_allocator: GrowableHeap::empty(),
+            stats: HeapStats::new(SLAB_SIZES),
+        }
+    }
+
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    pub fn set_limit(&mut self, heap_limit: usize) {
+        self.fallback_allocator.set_limit(heap_limit);
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.stats
+    }
+}
+
+// Same size classes as the fixed-size block allocator: the alignment is the block size.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    SLAB_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+fn recorded_size(index: Option<usize>, layout: &Layout) -> usize {
+    index.map_or(layout.size(), |index| SLAB_SIZES[index])
+}
+
+// Interrupts are disabled while the lock is held, see fixed_size_block.rs.
+unsafe impl GlobalAlloc for Locked<SlabAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| {
+            let mut allocator = self.lock();
+            let SlabAllocator { caches, fallback_allocator, stats } = &mut *allocator;
+            let index = list_index(&layout);
+            let ptr = match index {
+                Some(index) => caches[index].allocate(fallback_allocator),
+                None => fallback_allocator.allocate(layout),
+            };
+            stats.record_alloc(index, recorded_size(index, &layout), ptr);
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| {
+            let mut allocator = self.lock();
+            let SlabAllocator { caches, fallback_allocator, stats } = &mut *allocator;
+            let index = list_index(&layout);
+            stats.record_free(index, recorded_size(index, &layout));
+            match index {
+                Some(index) => caches[index].deallocate(ptr, fallback_allocator),
+                None => fallback_allocator.deallocate(ptr, layout),
+            }
+        })
+    }
+}
+
+// Pages taken from whatever #[global_allocator] is in use.
+struct GlobalPages;
+
+impl PageSource for GlobalPages {
+    fn allocate_page(&mut self) -> *mut u8 {
+        unsafe { alloc::alloc::alloc(page_layout()) }
+    }
+
+    unsafe fn deallocate_page(&mut self, page: *mut u8) {
+        alloc::alloc::dealloc(page, page_layout());
+    }
+}
+
+// Named cache for a frequently allocated kernel object, e.g.
+// `static THREAD_CACHE: ObjectCache = ObjectCache::new("thread", size_of::<Thread>(), align_of::<Thread>());`
+pub struct ObjectCache {
+    name: &'static str,
+    cache: Mutex<SlabCache>, // taken with interrupts disabled, before the global allocator lock
+}
+
+impl ObjectCache {
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        ObjectCache {
+            name,
+            cache: Mutex::new(SlabCache::new(size, align)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn allocate(&self) -> Option<NonNull<u8>> {
+        interrupts::without_interrupts(|| NonNull::new(self.cache.lock().allocate(&mut GlobalPages)))
+    }
+
+    // Caller must guarantee that `ptr` was returned by `allocate` of this cache and is not used anymore.
+    pub unsafe fn deallocate(&self, ptr: NonNull<u8>) {
+        interrupts::without_interrupts(|| self.cache.lock().deallocate(ptr.as_ptr(), &mut GlobalPages));
+    }
+
+    pub fn objects_in_use(&self) -> usize {
+        interrupts::without_interrupts(|| self.cache.lock().objects_in_use())
+    }
+
+    pub fn slabs(&self) -> usize {
+        interrupts::without_interrupts(|| self.cache.lock().slabs())
+    }
+}
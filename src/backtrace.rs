@@ -0,0 +1,98 @@
+/*
+
+Kernel backtraces
+-----------------
+
+The kernel is built with frame pointers (see .cargo/config.toml): every function starts with
+`push rbp; mov rbp, rsp`, so rbp points to the saved rbp of the caller, with the return address right above it.
+Following that chain from the current rbp gives the return address of every caller.
+
+The chain ends with rbp = 0 (first frame of a thread) or at an address that is not mapped,
+not aligned or not above the previous frame, so a corrupted stack can't fault again.
+Addresses are resolved with the symbol table embedded after linking, see backtrace/symbols.rs.
+*/
+
+pub mod symbols;
+
+use core::arch::asm;
+use core::fmt;
+use x86_64::VirtAddr;
+use crate::memory;
+
+pub const MAX_FRAMES: usize = 32;
+
+// Return addresses of a call chain, the innermost first. Captured without heap allocation.
+#[derive(Clone, Copy)]
+pub struct Backtrace {
+    addresses: [u64; MAX_FRAMES],
+    len: usize,
+    exact_first: bool, // the first address is where a fault happened, not a return address
+}
+
+impl Backtrace {
+    // Call chain of the caller.
+    #[inline(never)]
+    pub fn capture() -> Self {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        let mut backtrace = Backtrace { addresses: [0; MAX_FRAMES], len: 0, exact_first: false };
+        backtrace.walk(rbp); // the first return address is in the caller
+        backtrace
+    }
+
+    // Call chain of interrupted code, from its instruction pointer and rbp.
+    pub fn from_fault(instruction_pointer: u64, rbp: u64) -> Self {
+        let mut backtrace = Backtrace { addresses: [0; MAX_FRAMES], len: 0, exact_first: true };
+        backtrace.push(instruction_pointer);
+        backtrace.walk(rbp);
+        backtrace
+    }
+
+    pub fn addresses(&self) -> &[u64] {
+        &self.addresses[..self.len]
+    }
+
+    fn push(&mut self, address: u64) -> bool {
+        if self.len == MAX_FRAMES {
+            return false;
+        }
+        self.addresses[self.len] = address;
+        self.len += 1;
+        true
+    }
+
+    fn walk(&mut self, mut rbp: u64) {
+        while rbp != 0 && rbp % 8 == 0 && is_readable(rbp) && is_readable(rbp + 8) {
+            let (next_rbp, return_address) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+            if return_address == 0 || !self.push(return_address) {
+                break;
+            }
+            if next_rbp <= rbp { // callers are higher on the stack, anything else is garbage
+                break;
+            }
+            rbp = next_rbp;
+        }
+    }
+}
+
+fn is_readable(address: u64) -> bool {
+    match VirtAddr::try_new(address) {
+        Ok(address) => memory::is_mapped(address),
+        Err(_) => false,
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "backtrace:")?;
+        for (index, &address) in self.addresses().iter().enumerate() {
+            // a return address is right after the call, look up the call itself
+            let lookup = if index == 0 && self.exact_first { address } else { address - 1 };
+            write!(f, "\n  #{:<2} {:#018x}", index, address)?;
+            if let Some(symbol) = symbols::resolve(lookup) {
+                write!(f, " {}+{:#x}", symbol.name, address - symbol.address)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+/*
+
+Embedded symbol table
+---------------------
+
+A zeroed table with a magic header is reserved in the `.rusty_symbols` section of the kernel.
+After linking, tools/embed_symbols.py (run by tools/runner.sh before `bootimage runner`) writes the
+function symbols of the kernel ELF into it, in place:
+
+    magic "RUSTYSYM", count: u64
+    count entries { address: u64, size: u64, name_offset: u32, name_len: u32 }, sorted by address
+    demangled names, name_offset is from the start of the table
+
+Without the script the table stays empty and backtraces only show addresses.
+*/
+
+use core::{mem, ptr, str};
+
+pub const SYMBOL_TABLE_SIZE: usize = 512 * 1024; // keep in sync with tools/embed_symbols.py
+const MAGIC: [u8; 8] = *b"RUSTYSYM";
+const HEADER_SIZE: usize = 16;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Entry {
+    address: u64,
+    size: u64, // 0 if unknown
+    name_offset: u32,
+    name_len: u32,
+}
+
+#[repr(C, align(8))]
+struct SymbolTable([u8; SYMBOL_TABLE_SIZE]);
+
+const fn empty_table() -> SymbolTable {
+    let mut bytes = [0; SYMBOL_TABLE_SIZE];
+    let mut index = 0;
+    while index < MAGIC.len() {
+        bytes[index] = MAGIC[index];
+        index += 1;
+    }
+    SymbolTable(bytes)
+}
+
+// Mutable so the compiler can't fold reads to the empty table: its content changes after compilation.
+#[used]
+#[link_section = ".rusty_symbols"]
+static mut SYMBOL_TABLE: SymbolTable = empty_table();
+
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub address: u64,
+}
+
+fn table() -> &'static [u8] {
+    unsafe { &*ptr::addr_of!(SYMBOL_TABLE.0) } // never written at run time
+}
+
+fn entries() -> &'static [Entry] {
+    let table = table();
+    let mut count_bytes = [0; 8];
+    count_bytes.copy_from_slice(&table[8..HEADER_SIZE]);
+    let count = u64::from_le_bytes(count_bytes) as usize;
+    let max_count = (SYMBOL_TABLE_SIZE - HEADER_SIZE) / mem::size_of::<Entry>();
+    if table[..8] != MAGIC || count > max_count {
+        return &[];
+    }
+    unsafe { core::slice::from_raw_parts(table.as_ptr().add(HEADER_SIZE) as *const Entry, count) }
+}
+
+// Number of symbols embedded, 0 if tools/embed_symbols.py did not run on this kernel.
+pub fn count() -> usize {
+    entries().len()
+}
+
+// Function containing `address`.
+pub fn resolve(address: u64) -> Option<Symbol> {
+    let entries = entries();
+    let index = match entries.binary_search_by_key(&address, |entry| entry.address) {
+        Ok(index) => index,
+        Err(0) => return None,
+        Err(index) => index - 1, // last symbol starting before the address
+    };
+    let entry = entries[index];
+    if entry.size != 0 && address >= entry.address + entry.size {
+        return None;
+    }
+    let start = entry.name_offset as usize;
+    let name = table().get(start..start + entry.name_len as usize)?;
+    Some(Symbol {
+        name: str::from_utf8(name).ok()?,
+        address: entry.address,
+    })
+}
@@ -0,0 +1,73 @@
+/*
+
+Block devices
+-------------
+
+Disks are read and written in whole sectors through the `BlockDevice` trait, whatever the driver.
+Drivers register every disk they find with `register`, and the disks are then found with `devices`.
+*/
+
+pub mod ata;
+pub mod virtio_blk;
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    OutOfRange, // past the last sector
+    BadBufferSize, // not a whole number of sectors
+    Timeout,
+    Device { status: u8, error: u8 }, // the device reported an error, in its own registers
+    ReadOnly,
+}
+
+pub trait BlockDevice: Send {
+    // Identifies the disk in messages, e.g. "ata0-master".
+    fn name(&self) -> &str;
+
+    fn sector_size(&self) -> usize;
+
+    fn sector_count(&self) -> u64;
+
+    // Bytes.
+    fn capacity(&self) -> u64 {
+        self.sector_count() * self.sector_size() as u64
+    }
+
+    // Read `buffer.len() / sector_size()` sectors from `sector` on.
+    fn read_sectors(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    // Write `buffer.len() / sector_size()` sectors from `sector` on, on the disk when it returns.
+    fn write_sectors(&mut self, sector: u64, buffer: &[u8]) -> Result<(), BlockError>;
+}
+
+// Number of sectors of a request, after checking it fits the device.
+pub fn check_request<D: BlockDevice + ?Sized>(device: &D, sector: u64, bytes: usize) -> Result<u64, BlockError> {
+    if bytes % device.sector_size() != 0 {
+        return Err(BlockError::BadBufferSize);
+    }
+    let count = (bytes / device.sector_size()) as u64;
+    match sector.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+pub type SharedBlockDevice = Arc<Mutex<dyn BlockDevice>>;
+
+static DEVICES: Mutex<Vec<SharedBlockDevice>> = Mutex::new(Vec::new());
+
+pub fn register(device: SharedBlockDevice) {
+    DEVICES.lock().push(device);
+}
+
+// Every registered disk, in the order they were found.
+pub fn devices() -> Vec<SharedBlockDevice> {
+    DEVICES.lock().clone()
+}
+
+// First disk whose name is `name`.
+pub fn find(name: &str) -> Option<SharedBlockDevice> {
+    DEVICES.lock().iter().find(|device| device.lock().name() == name).cloned()
+}
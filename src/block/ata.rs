@@ -0,0 +1,327 @@
+/*
+
+ATA PIO driver
+--------------
+
+Disks of the IDE controller found on PCI, on its primary and secondary channels, in compatibility mode
+(the legacy ports 0x1F0 and 0x170) or native mode (the ports in the BARs). Every transfer is programmed I/O:
+the CPU moves each 16-bit word through the data port and polls the status register, with interrupts
+disabled on the device (nIEN). LBA48 commands are used only when the request needs them.
+ATAPI and SATA devices answer IDENTIFY with a signature and are skipped.
+*/
+
+use alloc::{format, string::String, sync::Arc};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use super::{check_request, BlockDevice, BlockError};
+use crate::pci::{self, Bar, DeviceMatch, PciDevice, PciDriver, ProbeError};
+use crate::time::{Duration, Instant};
+
+pub const SECTOR_SIZE: usize = 512;
+
+// Command block registers, offsets from the I/O base
+const DATA: u16 = 0;
+const ERROR: u16 = 1;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+const STATUS: u16 = 7; // COMMAND when written
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3; // data ready to be transferred
+const STATUS_DF: u8 = 1 << 5; // device fault
+const STATUS_BSY: u8 = 1 << 7;
+const CONTROL_NIEN: u8 = 1 << 1; // no interrupts
+
+const DRIVE_LBA: u8 = 0xe0; // LBA mode, bits 5 and 7 are always set
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+const COMMAND_READ: u8 = 0x20;
+const COMMAND_READ_EXT: u8 = 0x24;
+const COMMAND_WRITE: u8 = 0x30;
+const COMMAND_WRITE_EXT: u8 = 0x34;
+const COMMAND_FLUSH: u8 = 0xe7;
+const COMMAND_FLUSH_EXT: u8 = 0xea;
+const COMMAND_IDENTIFY: u8 = 0xec;
+
+const LBA28_LIMIT: u64 = 1 << 28;
+const LBA28_MAX_SECTORS: u64 = 256;
+const LBA48_MAX_SECTORS: u64 = 65536;
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Native mode of a channel in the programming interface of the controller
+const PRIMARY_NATIVE: u8 = 1 << 0;
+const SECONDARY_NATIVE: u8 = 1 << 2;
+
+const LEGACY_CHANNELS: [(u16, u16); 2] = [(0x1f0, 0x3f6), (0x170, 0x376)];
+
+// The two drives of a channel share its registers.
+struct Channel {
+    base: u16,
+    control: u16, // alternate status when read, device control when written
+}
+
+impl Channel {
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.base + register).write(value) };
+    }
+
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.control).read() }
+    }
+
+    fn disable_interrupts(&self) {
+        unsafe { Port::<u8>::new(self.control).write(CONTROL_NIEN) };
+    }
+
+    // The status is valid 400 ns after selecting a drive: the time of 4 reads.
+    fn delay_400ns(&self) {
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    fn select(&self, slave: bool, high_bits: u8) {
+        self.write(DRIVE, DRIVE_LBA | if slave { DRIVE_SLAVE } else { 0 } | (high_bits & 0x0f));
+        self.delay_400ns();
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, BlockError> {
+        let start = Instant::now();
+        loop {
+            let status = self.alternate_status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            if start.elapsed() > COMMAND_TIMEOUT {
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    // Wait until the device is ready to transfer a sector.
+    fn wait_data(&self) -> Result<(), BlockError> {
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(BlockError::Device { status, error: self.read(ERROR) });
+        }
+        if status & STATUS_DRQ == 0 {
+            return Err(BlockError::Device { status, error: 0 }); // no data although no error
+        }
+        Ok(())
+    }
+
+    fn wait_done(&self) -> Result<(), BlockError> {
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(BlockError::Device { status, error: self.read(ERROR) });
+        }
+        Ok(())
+    }
+
+    // Program the drive for `count` sectors from `lba` and send the command: the LBA28 one whenever the request
+    // fits, it is all a drive without LBA48 has.
+    fn start(&self, slave: bool, lba: u64, count: u64, lba48: bool, command_28: u8, command_48: u8) -> Result<(), BlockError> {
+        self.wait_not_busy()?;
+        if !lba48 || (lba + count <= LBA28_LIMIT && count <= LBA28_MAX_SECTORS) {
+            self.select(slave, (lba >> 24) as u8);
+            self.write(SECTOR_COUNT, count as u8); // 0 stands for 256
+            self.write(LBA_LOW, lba as u8);
+            self.write(LBA_MID, (lba >> 8) as u8);
+            self.write(LBA_HIGH, (lba >> 16) as u8);
+            self.write(STATUS, command_28);
+        } else {
+            self.select(slave, 0);
+            // high bytes first, the registers are FIFOs of two bytes
+            self.write(SECTOR_COUNT, (count >> 8) as u8); // 0 stands for 65536
+            self.write(LBA_LOW, (lba >> 24) as u8);
+            self.write(LBA_MID, (lba >> 32) as u8);
+            self.write(LBA_HIGH, (lba >> 40) as u8);
+            self.write(SECTOR_COUNT, count as u8);
+            self.write(LBA_LOW, lba as u8);
+            self.write(LBA_MID, (lba >> 8) as u8);
+            self.write(LBA_HIGH, (lba >> 16) as u8);
+            self.write(STATUS, command_48);
+        }
+        Ok(())
+    }
+
+    fn read_words(&self, buffer: &mut [u8]) {
+        let mut data = Port::<u16>::new(self.base + DATA);
+        for word in buffer.chunks_exact_mut(2) {
+            word.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_words(&self, buffer: &[u8]) {
+        let mut data = Port::<u16>::new(self.base + DATA);
+        for word in buffer.chunks_exact(2) {
+            unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+
+    // None without an ATA drive there.
+    fn identify(&self, slave: bool) -> Option<[u8; SECTOR_SIZE]> {
+        if self.alternate_status() == 0xff {
+            return None; // floating bus: no drive on the channel
+        }
+        self.select(slave, 0);
+        self.write(SECTOR_COUNT, 0);
+        self.write(LBA_LOW, 0);
+        self.write(LBA_MID, 0);
+        self.write(LBA_HIGH, 0);
+        self.write(STATUS, COMMAND_IDENTIFY);
+        if self.alternate_status() == 0 {
+            return None; // no drive
+        }
+        self.wait_not_busy().ok()?;
+        if self.read(LBA_MID) != 0 || self.read(LBA_HIGH) != 0 {
+            return None; // ATAPI or SATA signature
+        }
+        self.wait_data().ok()?;
+        let mut data = [0; SECTOR_SIZE];
+        self.read_words(&mut data);
+        Some(data)
+    }
+}
+
+pub struct AtaDrive {
+    name: String,
+    channel: Arc<Mutex<Channel>>,
+    slave: bool,
+    sectors: u64,
+    lba48: bool,
+    model: String,
+}
+
+impl AtaDrive {
+    fn new(name: String, channel: Arc<Mutex<Channel>>, slave: bool, identify: &[u8; SECTOR_SIZE]) -> AtaDrive {
+        let word = |index: usize| u16::from_le_bytes([identify[2 * index], identify[2 * index + 1]]);
+        let lba48 = word(83) & (1 << 10) != 0;
+        let sectors = if lba48 {
+            (100..104).rev().fold(0, |sectors, index| sectors << 16 | u64::from(word(index)))
+        } else {
+            u64::from(word(60)) | u64::from(word(61)) << 16
+        };
+        // the model is in words 27..47, the first character in the high byte of each word
+        let model: String = (27..47).flat_map(|index| word(index).to_be_bytes()).map(char::from).collect();
+        AtaDrive { name, channel, slave, sectors, lba48, model: String::from(model.trim_end()) }
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn supports_lba48(&self) -> bool {
+        self.lba48
+    }
+
+    fn max_sectors(&self) -> u64 {
+        if self.lba48 { LBA48_MAX_SECTORS } else { LBA28_MAX_SECTORS }
+    }
+
+    fn flush(&self, channel: &Channel) -> Result<(), BlockError> {
+        channel.wait_not_busy()?;
+        channel.select(self.slave, 0);
+        channel.write(STATUS, if self.lba48 { COMMAND_FLUSH_EXT } else { COMMAND_FLUSH });
+        channel.wait_done()
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buffer.len())?;
+        let channel = self.channel.lock();
+        let mut lba = sector;
+        for chunk in buffer.chunks_mut(self.max_sectors() as usize * SECTOR_SIZE) {
+            let count = (chunk.len() / SECTOR_SIZE) as u64;
+            channel.start(self.slave, lba, count, self.lba48, COMMAND_READ, COMMAND_READ_EXT)?;
+            for data in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                channel.wait_data()?;
+                channel.read_words(data);
+            }
+            lba += count;
+        }
+        channel.wait_done()
+    }
+
+    fn write_sectors(&mut self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buffer.len())?;
+        let channel = self.channel.lock();
+        let mut lba = sector;
+        for chunk in buffer.chunks(self.max_sectors() as usize * SECTOR_SIZE) {
+            let count = (chunk.len() / SECTOR_SIZE) as u64;
+            channel.start(self.slave, lba, count, self.lba48, COMMAND_WRITE, COMMAND_WRITE_EXT)?;
+            for data in chunk.chunks_exact(SECTOR_SIZE) {
+                channel.wait_data()?;
+                channel.write_words(data);
+            }
+            channel.wait_done()?;
+            lba += count;
+        }
+        self.flush(&channel) // the drive may keep the data in its cache
+    }
+}
+
+static ATA_DRIVER: PciDriver = PciDriver {
+    name: "ata",
+    matches: &[DeviceMatch::Class { class: 0x01, subclass: 0x01, prog_if: None }],
+    probe,
+};
+
+// Register the driver: the disks are found when the IDE controller is probed. Needs `pci::init` for that.
+pub fn init() {
+    pci::register_driver(&ATA_DRIVER);
+}
+
+fn native_ports(device: &PciDevice, command_bar: usize) -> Option<(u16, u16)> {
+    match (device.bars[command_bar], device.bars[command_bar + 1]) {
+        (Some(Bar::Io { port: base, .. }), Some(Bar::Io { port: control, .. })) => Some((base, control + 2)),
+        _ => None,
+    }
+}
+
+fn probe(device: &PciDevice) -> Result<(), ProbeError> {
+    device.enable(pci::COMMAND_IO);
+    let mut found = false;
+    for (index, &legacy) in LEGACY_CHANNELS.iter().enumerate() {
+        let native = device.prog_if & if index == 0 { PRIMARY_NATIVE } else { SECONDARY_NATIVE } != 0;
+        let (base, control) = match native {
+            true => native_ports(device, 2 * index).ok_or(ProbeError::Failed("native channel without I/O BARs"))?,
+            false => legacy,
+        };
+        let channel = Channel { base, control };
+        channel.disable_interrupts(); // polled
+        let channel = Arc::new(Mutex::new(channel));
+        for (slave, position) in [(false, "master"), (true, "slave")] {
+            let identify = channel.lock().identify(slave);
+            if let Some(identify) = identify {
+                let name = format!("ata{}-{}", index, position);
+                let drive = AtaDrive::new(name, channel.clone(), slave, &identify);
+                super::register(Arc::new(Mutex::new(drive)));
+                found = true;
+            }
+        }
+    }
+    if found { Ok(()) } else { Err(ProbeError::NotSupported) }
+}
@@ -0,0 +1,386 @@
+/*
+
+Virtio block driver
+-------------------
+
+One request queue. A request is a chain of a header (type and first sector), the data buffers and a status
+byte written by the device. Headers and status bytes have a slot per descriptor in one DMA area: a request
+uses the slots of its head descriptor. Data buffers are given to the device where they are, one descriptor
+per physically contiguous piece.
+
+`batch` makes every request it can available before one notification, then waits for the used ring,
+sleeping with `hlt` until the MSI-X interrupt of the queue when there is one. The sectors of the protocol
+are always 512 bytes.
+After a timeout the device is reset, so it stops writing to the buffers, and every later request fails.
+*/
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{PageSize, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use super::{check_request, BlockDevice, BlockError};
+use crate::memory::{self, phys_to_virt};
+use crate::pci::{self, msi::MsiX, DeviceMatch, PciDevice, PciDriver, ProbeError};
+use crate::time::{Duration, Instant};
+use crate::virtio::{queue::{Buffer, Virtqueue}, Transport, VirtioError, NO_VECTOR, VENDOR_ID};
+
+pub const SECTOR_SIZE: usize = 512;
+
+const TRANSITIONAL_DEVICE_ID: u16 = 0x1001; // legacy and modern
+const MODERN_DEVICE_ID: u16 = 0x1042; // 0x1040 + device type 2
+
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+const CONFIG_CAPACITY: u16 = 0; // in 512-byte sectors
+
+const TYPE_IN: u32 = 0;
+const TYPE_OUT: u32 = 1;
+const TYPE_FLUSH: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+const STATUS_PENDING: u8 = 0xff; // written before the request, the device overwrites it
+const HEADER_SIZE: u64 = 16;
+
+const MAX_DISKS: usize = 8;
+const MAX_REQUEST_BYTES: usize = 64 * 1024; // `read_sectors` and `write_sectors` split bigger buffers
+const REQUEST_SECTORS: usize = MAX_REQUEST_BYTES / SECTOR_SIZE;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Used ring interrupts of each disk, counted by `used_interrupt`.
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static INTERRUPTS: [AtomicU64; MAX_DISKS] = [ZERO; MAX_DISKS];
+static NEXT_DISK: AtomicUsize = AtomicUsize::new(0);
+
+static DISKS: Mutex<Vec<Arc<Mutex<VirtioBlk>>>> = Mutex::new(Vec::new());
+
+// The buffers are used by the device until `batch` returns.
+pub enum Request<'a> {
+    Read { sector: u64, buffer: &'a mut [u8] },
+    Write { sector: u64, buffer: &'a [u8] },
+    Flush, // nothing to do without the FLUSH feature: the device has no write cache then
+}
+
+impl Request<'_> {
+    // Type and first sector in the header, the data pieces.
+    fn describe(&self) -> (u32, u64, Option<(VirtAddr, usize, bool)>) {
+        match self {
+            Request::Read { sector, buffer } => (TYPE_IN, *sector, Some((VirtAddr::from_ptr(buffer.as_ptr()), buffer.len(), true))),
+            Request::Write { sector, buffer } => (TYPE_OUT, *sector, Some((VirtAddr::from_ptr(buffer.as_ptr()), buffer.len(), false))),
+            Request::Flush => (TYPE_FLUSH, 0, None),
+        }
+    }
+}
+
+pub struct VirtioBlk {
+    name: String,
+    disk: usize, // index in INTERRUPTS
+    transport: Transport,
+    queue: Virtqueue,
+    msi_x: Option<(MsiX, u8)>, // the used ring raises entry 0, on this vector
+    slots: PhysAddr, // a header per descriptor, then a status byte per descriptor
+    sectors: u64,
+    read_only: bool,
+    can_flush: bool,
+    failed: bool,
+}
+
+impl VirtioBlk {
+    // On error the device is left failed and everything given to it is freed.
+    fn new(device: &PciDevice, disk: usize) -> Result<VirtioBlk, VirtioError> {
+        let transport = Transport::new(device)?;
+        device.enable(pci::COMMAND_IO | pci::COMMAND_MEMORY | pci::COMMAND_BUS_MASTER);
+        let features = match transport.negotiate(FEATURE_READ_ONLY | FEATURE_FLUSH) {
+            Ok(features) => features,
+            Err(error) => {
+                transport.unmap();
+                return Err(error);
+            }
+        };
+        // before the queue: the legacy vector registers only exist with MSI-X on
+        let msi_x = MsiX::enable(device).ok().and_then(|msi_x| match msi_x.set_vector(0, used_interrupt, disk) {
+            Ok(vector) => Some((msi_x, vector)),
+            Err(_) => {
+                msi_x.disable(); // polled, and the registers are the ones without MSI-X again
+                None
+            }
+        });
+        transport.disable_config_vector();
+
+        let vector = if msi_x.is_some() { 0 } else { NO_VECTOR };
+        let setup = Virtqueue::new(0, transport.queue_size(0)).and_then(|mut queue| {
+            let slots_size = slots_size(queue.size());
+            let slots = match memory::allocate_dma(slots_size) {
+                Some(slots) => slots,
+                None => {
+                    unsafe { queue.free() }; // not given to the device yet
+                    return Err(VirtioError::OutOfMemory);
+                }
+            };
+            if let Err(error) = transport.setup_queue(&mut queue, vector) {
+                transport.reset(); // the device may know the queue already
+                unsafe {
+                    queue.free();
+                    memory::free_dma(slots, slots_size);
+                }
+                return Err(error);
+            }
+            Ok((queue, slots))
+        });
+        let (queue, slots) = match setup {
+            Ok(setup) => setup,
+            Err(error) => {
+                transport.fail();
+                if let Some((msi_x, vector)) = msi_x {
+                    msi_x.clear_vector(0, vector);
+                    msi_x.disable();
+                }
+                transport.unmap();
+                return Err(error);
+            }
+        };
+        transport.driver_ok();
+        Ok(VirtioBlk {
+            name: format!("virtio{}", disk),
+            disk,
+            sectors: transport.read_config_u64(CONFIG_CAPACITY),
+            transport,
+            queue,
+            msi_x,
+            slots,
+            read_only: features & FEATURE_READ_ONLY != 0,
+            can_flush: features & FEATURE_FLUSH != 0,
+            failed: false,
+        })
+    }
+
+    pub fn is_modern(&self) -> bool {
+        self.transport.is_modern()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn uses_interrupts(&self) -> bool {
+        self.msi_x.is_some()
+    }
+
+    // Used ring interrupts so far, always 0 when the used ring is polled.
+    pub fn interrupts(&self) -> u64 {
+        INTERRUPTS[self.disk].load(Ordering::Relaxed)
+    }
+
+    // Do every request, as many at a time as the queue holds. The first error is returned once none
+    // of them is in flight anymore.
+    pub fn batch(&mut self, requests: &mut [Request]) -> Result<(), BlockError> {
+        if self.failed {
+            return Err(BlockError::Timeout);
+        }
+        let mut chains = Vec::with_capacity(requests.len()); // request index and its chain
+        for (index, request) in requests.iter().enumerate() {
+            if matches!(request, Request::Flush) && !self.can_flush {
+                continue;
+            }
+            chains.push((index, self.chain(request)?));
+        }
+
+        let mut result = Ok(());
+        let mut in_flight = vec![false; usize::from(self.queue.size())];
+        let (mut next, mut pending) = (0, 0);
+        while next < chains.len() || pending > 0 {
+            let first = next;
+            while next < chains.len() && usize::from(self.queue.free_descriptors()) >= chains[next].1.len() {
+                let (index, chain) = &mut chains[next];
+                let head = self.queue.next_head();
+                self.fill_slots(head, &requests[*index], chain);
+                self.queue.add(chain).expect("free descriptors were checked");
+                in_flight[usize::from(head)] = true;
+                next += 1;
+                pending += 1;
+            }
+            if next > first {
+                self.transport.notify(&self.queue); // once for all of them
+            }
+            let head = self.wait_used().map_err(|error| {
+                self.transport.reset();
+                self.failed = true;
+                error
+            })?;
+            if core::mem::replace(&mut in_flight[usize::from(head)], false) {
+                pending -= 1;
+                let status = unsafe { self.status_pointer(head).read_volatile() };
+                if status != STATUS_OK && result.is_ok() {
+                    result = Err(BlockError::Device { status, error: 0 });
+                }
+            }
+        }
+        result
+    }
+
+    // Header, data pieces and status byte of a request, the slots filled in later.
+    fn chain(&self, request: &Request) -> Result<Vec<Buffer>, BlockError> {
+        let mut chain = vec![Buffer { address: PhysAddr::zero(), length: HEADER_SIZE as u32, writable: false }];
+        if let (kind, sector, Some((start, length, writable))) = request.describe() {
+            if kind == TYPE_OUT && self.read_only {
+                return Err(BlockError::ReadOnly);
+            }
+            check_request(self, sector, length)?;
+            pieces(start, length, writable, &mut chain)?;
+        }
+        chain.push(Buffer { address: PhysAddr::zero(), length: 1, writable: true });
+        if chain.len() > usize::from(self.queue.size()) {
+            return Err(BlockError::BadBufferSize); // would never fit in the queue
+        }
+        Ok(chain)
+    }
+
+    // Write the header of the request about to be added at `head` and point its chain to the slots.
+    fn fill_slots(&self, head: u16, request: &Request, chain: &mut [Buffer]) {
+        let (kind, sector, _) = request.describe();
+        let header = self.slots + u64::from(head) * HEADER_SIZE;
+        unsafe {
+            let pointer = phys_to_virt(header).as_mut_ptr::<u32>();
+            pointer.write_volatile(kind);
+            pointer.add(1).write_volatile(0); // reserved
+            pointer.add(2).cast::<u64>().write_volatile(sector);
+            self.status_pointer(head).write_volatile(STATUS_PENDING);
+        }
+        chain[0].address = header;
+        chain[chain.len() - 1].address = self.slots + u64::from(self.queue.size()) * HEADER_SIZE + u64::from(head);
+    }
+
+    fn status_pointer(&self, head: u16) -> *mut u8 {
+        phys_to_virt(self.slots + u64::from(self.queue.size()) * HEADER_SIZE + u64::from(head)).as_mut_ptr()
+    }
+
+    // Head of the next completed chain.
+    fn wait_used(&mut self) -> Result<u16, BlockError> {
+        let sleep = self.msi_x.is_some() && interrupts::are_enabled();
+        let start = Instant::now();
+        loop {
+            if sleep {
+                interrupts::disable(); // no interrupt between the check and hlt
+            }
+            let used = self.queue.pop_used();
+            let timed_out = used.is_none() && start.elapsed() > REQUEST_TIMEOUT;
+            match (used, timed_out) {
+                (Some((head, _)), _) => {
+                    if sleep {
+                        interrupts::enable();
+                    }
+                    return Ok(head);
+                }
+                (None, true) => {
+                    if sleep {
+                        interrupts::enable();
+                    }
+                    return Err(BlockError::Timeout);
+                }
+                (None, false) if sleep => interrupts::enable_and_hlt(), // woken by the queue or the timer
+                (None, false) => core::hint::spin_loop(),
+            }
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buffer.len())?;
+        let mut requests: Vec<Request> = buffer.chunks_mut(MAX_REQUEST_BYTES).zip((sector..).step_by(REQUEST_SECTORS))
+            .map(|(buffer, sector)| Request::Read { sector, buffer })
+            .collect();
+        self.batch(&mut requests)
+    }
+
+    fn write_sectors(&mut self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buffer.len())?;
+        let mut requests: Vec<Request> = buffer.chunks(MAX_REQUEST_BYTES).zip((sector..).step_by(REQUEST_SECTORS))
+            .map(|(buffer, sector)| Request::Write { sector, buffer })
+            .collect();
+        if self.can_flush {
+            requests.push(Request::Flush); // the device may keep the data in its cache
+        }
+        self.batch(&mut requests)
+    }
+}
+
+// A header then a status byte for each descriptor of the queue.
+fn slots_size(queue_size: u16) -> u64 {
+    u64::from(queue_size) * (HEADER_SIZE + 1)
+}
+
+// Called in the interrupt handler: the waiting request notices the used ring on its own once woken.
+fn used_interrupt(disk: usize) {
+    INTERRUPTS[disk].fetch_add(1, Ordering::Relaxed);
+}
+
+// Split [start, start + length) in physically contiguous pieces, appended to `chain`.
+fn pieces(start: VirtAddr, length: usize, writable: bool, chain: &mut Vec<Buffer>) -> Result<(), BlockError> {
+    let end = start + length;
+    let mut address = start;
+    let first = chain.len();
+    while address < end {
+        let piece = (address.align_down(Size4KiB::SIZE) + Size4KiB::SIZE).min(end) - address;
+        let physical = memory::virt_to_phys(address).ok_or(BlockError::BadBufferSize)?;
+        let contiguous = chain.len() > first && chain.last().map_or(false, |last| last.address + u64::from(last.length) == physical);
+        match chain.last_mut() {
+            Some(last) if contiguous => last.length += piece as u32,
+            _ => chain.push(Buffer { address: physical, length: piece as u32, writable }),
+        }
+        address += piece;
+    }
+    Ok(())
+}
+
+static VIRTIO_BLK_DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    matches: &[
+        DeviceMatch::Id { vendor_id: VENDOR_ID, device_id: TRANSITIONAL_DEVICE_ID },
+        DeviceMatch::Id { vendor_id: VENDOR_ID, device_id: MODERN_DEVICE_ID },
+    ],
+    probe,
+};
+
+// Register the driver, the disks are found when their PCI function is probed. Needs `pci::init` for that,
+// and `apic::init` before it for the used ring interrupts.
+pub fn init() {
+    pci::register_driver(&VIRTIO_BLK_DRIVER);
+}
+
+// Every disk found, to use `batch`: the block device registry has them too.
+pub fn disks() -> Vec<Arc<Mutex<VirtioBlk>>> {
+    DISKS.lock().clone()
+}
+
+fn probe(device: &PciDevice) -> Result<(), ProbeError> {
+    let disk = NEXT_DISK.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| (next < MAX_DISKS).then(|| next + 1))
+        .map_err(|_| ProbeError::Failed("too many virtio disks"))?;
+    let disk = match VirtioBlk::new(device, disk) {
+        Ok(disk) => disk,
+        Err(_) => {
+            // give the index back, the next disk has not taken one yet: probes run one after the other
+            INTERRUPTS[disk].store(0, Ordering::Relaxed);
+            let _ = NEXT_DISK.compare_exchange(disk + 1, disk, Ordering::Relaxed, Ordering::Relaxed);
+            return Err(ProbeError::Failed("virtio initialization failed"));
+        }
+    };
+    let disk = Arc::new(Mutex::new(disk));
+    DISKS.lock().push(disk.clone());
+    super::register(disk);
+    Ok(())
+}
@@ -0,0 +1,232 @@
+/*
+
+ELF64 executables
+-----------------
+
+Only what is needed to run static x86_64 executables: the file header and the program headers.
+Fields are read byte by byte, the image can be at any alignment.
+Every check returns an `ElfError`, a malformed image must never panic the kernel.
+*/
+
+pub mod loader;
+
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+use crate::thread::SpawnError;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1; // little endian
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+
+const FILE_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_PHDR: u32 = 6;
+
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+#[derive(Debug)]
+pub enum ElfError {
+    TooShort, // image smaller than a header it claims to contain
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    UnsupportedVersion,
+    NotExecutable, // only ET_EXEC, no shared objects or relocatable files
+    WrongMachine,
+    BadProgramHeaderSize,
+    ProgramHeadersOutOfBounds,
+    NoLoadableSegment,
+    SegmentOutOfBounds, // file bytes of a segment are outside the image
+    SegmentFileSizeTooLarge, // p_filesz > p_memsz
+    SegmentOutsideUserSpace,
+    MisalignedSegment,
+    EntryPointNotExecutable,
+    ArgumentsTooLarge, // argv, envp and auxv do not fit on the user stack
+    AddressesInUse, // another program is loaded at the segments or the stack
+    Mapping(MapToError<Size4KiB>),
+    Spawn(SpawnError), // the program was loaded but its thread could not be created
+}
+
+impl From<MapToError<Size4KiB>> for ElfError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        ElfError::Mapping(error)
+    }
+}
+
+impl From<SpawnError> for ElfError {
+    fn from(error: SpawnError) -> Self {
+        ElfError::Spawn(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FileHeader {
+    pub elf_type: u16,
+    pub machine: u16,
+    pub entry: u64,
+    pub phoff: u64, // offset of the program header table
+    pub phentsize: u16,
+    pub phnum: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    pub fn is_load(&self) -> bool {
+        self.p_type == PT_LOAD
+    }
+}
+
+// A validated image, borrowed from the caller.
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    header: FileHeader,
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < FILE_HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 {
+            return Err(ElfError::NotElf64);
+        }
+        if data[5] != ELFDATA2LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if data[6] != EV_CURRENT || read_u32(data, 20)? != u32::from(EV_CURRENT) {
+            return Err(ElfError::UnsupportedVersion);
+        }
+
+        let header = FileHeader {
+            elf_type: read_u16(data, 16)?,
+            machine: read_u16(data, 18)?,
+            entry: read_u64(data, 24)?,
+            phoff: read_u64(data, 32)?,
+            phentsize: read_u16(data, 54)?,
+            phnum: read_u16(data, 56)?,
+        };
+        if header.elf_type != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if header.machine != EM_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+        if usize::from(header.phentsize) != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeaderSize);
+        }
+        let table_size = u64::from(header.phnum) * PROGRAM_HEADER_SIZE as u64;
+        match header.phoff.checked_add(table_size) {
+            Some(end) if end <= data.len() as u64 => {}
+            _ => return Err(ElfError::ProgramHeadersOutOfBounds),
+        }
+
+        let elf = ElfFile { data, header };
+        elf.validate_segments()?;
+        Ok(elf)
+    }
+
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..usize::from(self.header.phnum)).map(move |index| {
+            let offset = self.header.phoff as usize + index * PROGRAM_HEADER_SIZE;
+            self.program_header_at(offset).expect("program header table checked by parse")
+        })
+    }
+
+    // Bytes of the segment stored in the file (p_filesz of them).
+    pub fn segment_data(&self, segment: &ProgramHeader) -> &'a [u8] {
+        let start = segment.offset as usize;
+        &self.data[start..start + segment.filesz as usize] // checked by validate_segments
+    }
+
+    fn program_header_at(&self, offset: usize) -> Result<ProgramHeader, ElfError> {
+        let data = self.data;
+        Ok(ProgramHeader {
+            p_type: read_u32(data, offset)?,
+            flags: read_u32(data, offset + 4)?,
+            offset: read_u64(data, offset + 8)?,
+            vaddr: read_u64(data, offset + 16)?,
+            filesz: read_u64(data, offset + 32)?,
+            memsz: read_u64(data, offset + 40)?,
+            align: read_u64(data, offset + 48)?,
+        })
+    }
+
+    fn validate_segments(&self) -> Result<(), ElfError> {
+        use crate::usermode::is_user_range;
+
+        let mut loadable = false;
+        let mut entry_executable = false;
+        for segment in self.program_headers().filter(ProgramHeader::is_load) {
+            loadable = true;
+            if segment.filesz > segment.memsz {
+                return Err(ElfError::SegmentFileSizeTooLarge);
+            }
+            match segment.offset.checked_add(segment.filesz) {
+                Some(end) if end <= self.data.len() as u64 => {}
+                _ => return Err(ElfError::SegmentOutOfBounds),
+            }
+            if !is_user_range(segment.vaddr, segment.memsz) {
+                return Err(ElfError::SegmentOutsideUserSpace);
+            }
+            if segment.align > 1
+                && (!segment.align.is_power_of_two() || segment.vaddr % segment.align != segment.offset % segment.align)
+            {
+                return Err(ElfError::MisalignedSegment);
+            }
+            let entry = self.header.entry;
+            if segment.flags & PF_X != 0 && entry >= segment.vaddr && entry - segment.vaddr < segment.memsz {
+                entry_executable = true;
+            }
+        }
+
+        if !loadable {
+            return Err(ElfError::NoLoadableSegment);
+        }
+        if !entry_executable {
+            return Err(ElfError::EntryPointNotExecutable);
+        }
+        Ok(())
+    }
+}
+
+fn read_bytes<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], ElfError> {
+    let bytes = offset.checked_add(N).and_then(|end| data.get(offset..end)).ok_or(ElfError::TooShort)?;
+    let mut array = [0; N];
+    array.copy_from_slice(bytes);
+    Ok(array)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    read_bytes(data, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    read_bytes(data, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    read_bytes(data, offset).map(u64::from_le_bytes)
+}
@@ -0,0 +1,259 @@
+use super::{ElfError, ElfFile, ProgramHeader, PF_W, PF_X, PROGRAM_HEADER_SIZE};
+use crate::{memory, thread, usermode};
+use crate::thread::ThreadId;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+pub const USER_STACK_SIZE: u64 = 4096 * 16; // 64 KiB
+pub const USER_STACK_BOTTOM: u64 = usermode::USER_STACK_TOP - USER_STACK_SIZE;
+
+// Auxiliary vector entries (System V ABI), read by the C runtime of the program.
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+// A program mapped in user space, ready to be entered.
+#[derive(Debug, Clone, Copy)]
+pub struct Program {
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr, // points to argc
+    image_start: u64, // pages from the first to the last segment, owned by the program
+    image_end: u64,
+}
+
+// Map every PT_LOAD segment of `elf` and a stack holding argv, envp and auxv.
+// All programs share one address space: loading fails with AddressesInUse while another program uses the
+// same pages. On error nothing stays mapped.
+pub fn load(
+    elf: &ElfFile,
+    args: &[&str],
+    env: &[&str],
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<Program, ElfError> {
+    let (image_start, image_end) = image_range(elf);
+    let mut program = Program {
+        entry: VirtAddr::new(elf.header().entry),
+        stack_pointer: VirtAddr::new(usermode::USER_STACK_TOP),
+        image_start,
+        image_end,
+    };
+    // checked first, so the rollback below only unmaps pages of this load
+    if is_any_mapped(image_start, image_end, mapper) || is_any_mapped(USER_STACK_BOTTOM, usermode::USER_STACK_TOP, mapper) {
+        return Err(ElfError::AddressesInUse);
+    }
+
+    let mut map = || -> Result<VirtAddr, ElfError> {
+        for segment in elf.program_headers().filter(ProgramHeader::is_load) {
+            map_segment(elf, &segment, mapper, frame_allocator)?;
+        }
+        let stack_top = usermode::map_user_stack(USER_STACK_SIZE, mapper, frame_allocator)?;
+        init_stack(elf, stack_top, args, env)
+    };
+    match map() {
+        Ok(stack_pointer) => {
+            program.stack_pointer = stack_pointer;
+            Ok(program)
+        }
+        Err(error) => {
+            unsafe { unload(&program, mapper, frame_allocator) };
+            Err(error)
+        }
+    }
+}
+
+// Unmap the segments and the stack of `program` and free their frames.
+// Unsafe because no thread may still run the program.
+pub unsafe fn unload(
+    program: &Program,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    if program.image_end > program.image_start {
+        let size = program.image_end - program.image_start;
+        usermode::unmap_user_region(VirtAddr::new(program.image_start), size, mapper, frame_deallocator);
+    }
+    usermode::unmap_user_region(VirtAddr::new(USER_STACK_BOTTOM), USER_STACK_SIZE, mapper, frame_deallocator);
+}
+
+// Parse and load `image` in the kernel memory, then run it in ring 3 in a new thread.
+// The program is unloaded when the thread exits, through SYS_EXIT or a fault.
+pub fn spawn(image: &[u8], args: &[&str], env: &[&str]) -> Result<ThreadId, ElfError> {
+    let elf = ElfFile::parse(image)?;
+    let program = memory::with_kernel_memory(|memory| {
+        load(&elf, args, env, &mut memory.mapper, &mut memory.frame_allocator)
+    })?;
+    // the thread stack is allocated after the kernel memory is unlocked, the heap may need to grow
+    let spawned = thread::spawn(move || {
+        thread::at_exit(move || memory::with_kernel_memory(|memory| unsafe {
+            unload(&program, &mut memory.mapper, &mut memory.frame_allocator)
+        }));
+        unsafe { usermode::enter_user_mode(program.entry, program.stack_pointer) }
+    });
+    spawned.map_err(|error| {
+        memory::with_kernel_memory(|memory| unsafe { // the thread never ran
+            unload(&program, &mut memory.mapper, &mut memory.frame_allocator)
+        });
+        ElfError::from(error)
+    })
+}
+
+// Page aligned [start, end) covering every PT_LOAD segment, empty when they are all empty.
+fn image_range(elf: &ElfFile) -> (u64, u64) {
+    let segments = elf.program_headers().filter(|segment| segment.is_load() && segment.memsz > 0);
+    let (start, end) = segments.fold((u64::MAX, 0), |(start, end), segment| {
+        (start.min(segment.vaddr), end.max(segment.vaddr + segment.memsz))
+    });
+    if start >= end {
+        return (0, 0);
+    }
+    (start & !0xfff, (end + 0xfff) & !0xfff)
+}
+
+fn is_any_mapped(start: u64, end: u64, mapper: &impl Mapper<Size4KiB>) -> bool {
+    (start..end).step_by(4096)
+        .any(|address| mapper.translate_page(Page::containing_address(VirtAddr::new(address))).is_ok())
+}
+
+fn segment_flags(segment: &ProgramHeader) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT;
+    if segment.flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if segment.flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+// Copy the file bytes of the segment into fresh frames, the rest (.bss) is zeroed.
+// Frames are filled through the physical memory mapping: the user pages may be read-only.
+fn map_segment(
+    elf: &ElfFile,
+    segment: &ProgramHeader,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), ElfError> {
+    if segment.memsz == 0 {
+        return Ok(());
+    }
+    let data = elf.segment_data(segment);
+    let file_end = segment.vaddr + segment.filesz;
+    let page_range = {
+        let start_page = Page::containing_address(VirtAddr::new(segment.vaddr));
+        let end_page = Page::containing_address(VirtAddr::new(segment.vaddr + segment.memsz - 1)); // inclusive
+        Page::range_inclusive(start_page, end_page)
+    };
+
+    for page in page_range {
+        let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        let frame_ptr: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+        let page_start = page.start_address().as_u64();
+        let page_end = page_start + page.size();
+
+        unsafe {
+            core::ptr::write_bytes(frame_ptr, 0, page.size() as usize);
+            let copy_start = page_start.max(segment.vaddr);
+            let copy_end = page_end.min(file_end);
+            if copy_start < copy_end {
+                let source = &data[(copy_start - segment.vaddr) as usize..(copy_end - segment.vaddr) as usize];
+                let destination = frame_ptr.add((copy_start - page_start) as usize);
+                core::ptr::copy_nonoverlapping(source.as_ptr(), destination, source.len());
+            }
+            if let Err(error) = usermode::map_user_page(page, frame, segment_flags(segment), mapper, frame_allocator) {
+                frame_allocator.deallocate_frame(frame);
+                return Err(error.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Address of the program headers once loaded, if a PT_LOAD segment contains them.
+fn program_headers_address(elf: &ElfFile) -> Option<u64> {
+    let phoff = elf.header().phoff;
+    elf.program_headers()
+        .filter(ProgramHeader::is_load)
+        .find(|segment| phoff >= segment.offset && phoff - segment.offset < segment.filesz)
+        .map(|segment| segment.vaddr + (phoff - segment.offset))
+}
+
+/*
+Initial user stack (System V ABI), from the top:
+
+    strings of argv and envp
+    padding to 16 bytes
+    auxv pairs, ended by AT_NULL
+    envp pointers, ended by NULL
+    argv pointers, ended by NULL
+    argc                           <- stack pointer, 16 bytes aligned
+*/
+fn init_stack(elf: &ElfFile, stack_top: VirtAddr, args: &[&str], env: &[&str]) -> Result<VirtAddr, ElfError> {
+    // No heap allocation here: the loader may run while the kernel memory is locked
+    let mut auxv = [(AT_NULL, 0); 6];
+    let mut auxc = 0;
+    let mut push_aux = |key, value| {
+        auxv[auxc] = (key, value);
+        auxc += 1;
+    };
+    if let Some(address) = program_headers_address(elf) {
+        push_aux(AT_PHDR, address);
+    }
+    push_aux(AT_PHENT, PROGRAM_HEADER_SIZE as u64);
+    push_aux(AT_PHNUM, u64::from(elf.header().phnum));
+    push_aux(AT_PAGESZ, 4096);
+    push_aux(AT_ENTRY, elf.header().entry);
+    push_aux(AT_NULL, 0);
+
+    let strings_size: usize = args.iter().chain(env.iter()).map(|s| s.len() + 1).sum();
+    let words = 1 + (args.len() + 1) + (env.len() + 1) + 2 * auxc;
+    if strings_size + 16 + (words + 1) * 8 > USER_STACK_SIZE as usize {
+        return Err(ElfError::ArgumentsTooLarge);
+    }
+
+    let mut table_start = (stack_top.as_u64() - strings_size as u64) & !0xf;
+    if words % 2 == 1 {
+        table_start -= 8; // argc must end up 16 bytes aligned
+    }
+    table_start -= (words * 8) as u64;
+
+    let mut string_start = stack_top.as_u64();
+    let mut push_string = |s: &str| {
+        string_start -= s.len() as u64 + 1;
+        unsafe {
+            core::ptr::copy_nonoverlapping(s.as_ptr(), string_start as *mut u8, s.len());
+            (string_start as *mut u8).add(s.len()).write(0); // NUL terminated
+        }
+        string_start
+    };
+
+    unsafe {
+        let argv = table_start as *mut u64;
+        argv.write(args.len() as u64); // argc
+        let argv = argv.add(1);
+        for (index, arg) in args.iter().enumerate() {
+            argv.add(index).write(push_string(arg));
+        }
+        argv.add(args.len()).write(0);
+
+        let envp = argv.add(args.len() + 1);
+        for (index, variable) in env.iter().enumerate() {
+            envp.add(index).write(push_string(variable));
+        }
+        envp.add(env.len()).write(0);
+
+        let auxv_start = envp.add(env.len() + 1);
+        for (index, &(key, value)) in auxv[..auxc].iter().enumerate() {
+            auxv_start.add(2 * index).write(key);
+            auxv_start.add(2 * index + 1).write(value);
+        }
+    }
+    Ok(VirtAddr::new(table_start))
+}
@@ -2,47 +2,75 @@ use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use lazy_static::lazy_static;
+use core::ptr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0; // double fault stack
+pub const KERNEL_PRIVILEGE_STACK_INDEX: usize = 0; // stack loaded when an interrupt comes from ring 3
 
-lazy_static! { // No init at compile time.
-    static ref TSS: TaskStateSegment =  { // Task State Segment is a data structure that describes the state of a task.
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5; // 5 pages
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE]; // Arch64. Grow downwards.
+// Task State Segment: stacks the CPU switches to. Written by `init_tss` before the GDT points to it, then
+// only by `set_kernel_stack`, so it is a `static mut` accessed through raw pointers: the CPU reads it
+// behind our back and a shared reference to it must never be written through.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK }); // Act as memory for now. Unsafe race condition
-            let stack_end = stack_start + STACK_SIZE;
+fn init_tss() {
+    const STACK_SIZE: usize = 4096 * 5; // 5 pages
+    static mut DOUBLE_FAULT_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE]; // Arch64. Grow downwards.
+    static mut PRIVILEGE_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE]; // replaced by the scheduler with the stack of the running thread
 
-            stack_end
-        };
-        tss
-    };
+    unsafe {
+        let tss = ptr::addr_of_mut!(TSS);
+        (*tss).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            VirtAddr::from_ptr(ptr::addr_of!(DOUBLE_FAULT_STACK)) + STACK_SIZE;
+        (*tss).privilege_stack_table[KERNEL_PRIVILEGE_STACK_INDEX] =
+            VirtAddr::from_ptr(ptr::addr_of!(PRIVILEGE_STACK)) + STACK_SIZE;
+    }
 }
 
 lazy_static!  {
     static ref GDT: (GlobalDescriptorTable, Selectors) =  { // GlobalDescriptorTable is a data structure that describes the state of a GDT.
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment()); // Reload code segment to point to new kernel code segment.
-        let tss_selector  = gdt.add_entry(Descriptor::tss_segment(&TSS)); // Load TSS segment to point to new TSS segment.
-        (gdt, Selectors { code_selector, tss_selector }) // Return valide GDT and Selectors.
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        // User data right before user code: the order `sysret` expects, if we switch to it one day.
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        // the reference only gives the address to the descriptor, it does not outlive this line
+        let tss_selector  = gdt.add_entry(Descriptor::tss_segment(unsafe { &*ptr::addr_of!(TSS) }));
+        (gdt, Selectors { code_selector, data_selector, user_code_selector, user_data_selector, tss_selector }) // Return valide GDT and Selectors.
     };
 }
 
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector, // RPL 3 is set by the descriptor privilege level
+    user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
 pub fn init() {
     use x86_64::instructions::tables::load_tss;
-    use x86_64::instructions::segmentation::{CS, Segment};
+    use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment};
 
+    init_tss(); // before the GDT is built
     GDT.0.load();
 
-    unsafe { // We can brake memory safety by loading invalid selectors 
+    unsafe { // We can brake memory safety by loading invalid selectors
         CS::set_reg(GDT.1.code_selector); // Reload code segment to point to new kernel code segment.
+        SS::set_reg(GDT.1.data_selector);
+        DS::set_reg(GDT.1.data_selector);
+        ES::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector); // Load TSS segment to point to new TSS segment.
     }
-}
\ No newline at end of file
+}
+
+// Selectors to load when entering ring 3: (code, data).
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
+// Set the stack the CPU switches to when an interrupt or syscall comes from ring 3.
+// Unsafe because the stack must stay valid until it is replaced, and interrupts must be disabled.
+pub unsafe fn set_kernel_stack(stack_end: VirtAddr) {
+    (*ptr::addr_of_mut!(TSS)).privilege_stack_table[KERNEL_PRIVILEGE_STACK_INDEX] = stack_end;
+}
@@ -1,8 +1,15 @@
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+pub mod apic;
+pub mod exceptions;
+pub mod irq;
+pub mod stats;
+pub mod vectors;
+
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use pic8259::ChainedPics; // Represent Secondary and Primary PICs
 use spin::Mutex; // Spinlock
 use lazy_static::lazy_static;
-use crate::{println, print, gdt, hlt_loop};
+use x86_64::{PrivilegeLevel, VirtAddr};
+use crate::syscall;
 
 
 // Pics:(Programmable interupt controller) are used to handle interrupts. Range from 32 to 47.
@@ -13,6 +20,7 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 // Enable safe mutable access to the PICs thanks to Mutex
 pub static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+// Vector of each ISA IRQ: PIC_1_OFFSET + IRQ line, with the PICs as well as with the APICs.
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
@@ -21,12 +29,22 @@ pub enum InterruptIndex {
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+    pub fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+}
+
+// Mask every line of both PICs, they stay remapped so a spurious IRQ can't look like an exception.
+fn mask_pics() {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff); // primary PIC data port
+        Port::<u8>::new(0xa1).write(0xff); // secondary PIC data port
     }
 }
 
@@ -34,13 +52,14 @@ impl InterruptIndex {
 lazy_static! { // Use unsafe behind the scene.
     static ref IDT: InterruptDescriptorTable =  {
         let mut idt = InterruptDescriptorTable::new(); // mute for modify breakpoints entry
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler); // Because of IndexMut can access with indexing syntax.
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        exceptions::install(&mut idt); // every CPU exception
+        irq::install(&mut idt); // ISA IRQs, the handlers are added with irq::register_irq
+        vectors::install(&mut idt); // allocated at run time, e.g. for MSI
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
         unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt[usize::from(syscall::SYSCALL_INTERRUPT)]
+                .set_handler_addr(VirtAddr::new(syscall::entry_address()))
+                .set_privilege_level(PrivilegeLevel::Ring3); // `int 0x80` is allowed from user code
         }
         idt
     };
@@ -50,62 +69,24 @@ pub fn init_idt()  {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(_stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", _stack_frame);
-}
-
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8()); // Send End of Interrupt signal to PICs
-    }
+// Handlers of the core devices, drivers register theirs the same way.
+pub fn register_core_handlers() {
+    irq::register_irq(InterruptIndex::Timer.irq(), timer_interrupt).expect("timer IRQ registration failed");
+    irq::register_irq(InterruptIndex::Keyboard.irq(), crate::task::keyboard::keyboard_interrupt)
+        .expect("keyboard IRQ registration failed");
 }
 
-extern "x86-interrupt" fn page_fault_handler(_stack_frame: InterruptStackFrame, _error_code: PageFaultErrorCode)  {
-    use x86_64::registers::control::Cr2;
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read()); // Read the address that caused the page fault
-    println!("Error Code: {:?}", _error_code);
-    println!("{:#?}", _stack_frame);
-    hlt_loop(); // Halt the CPU because of the page fault
-}
-
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame)  {
-    
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use x86_64::instructions::port::Port;
-
-    lazy_static! { // Create a static keyboard US instance protected by Mutex
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1,
-                HandleControl::Ignore)
-            );
-    }
-
-    let mut keyboard = KEYBOARD.lock(); // Lock the keyboard
-    let mut port = Port::new(0x60); // I/O port for keyboard
-
-    let scancode: u8 = unsafe { port.read() }; // Read the scancode from the keyboard
-
-    // Decode the scancode
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) { // If the scancode is valid
-        if let Some(key) = keyboard.process_keyevent(key_event) { // check press event and decode the key
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
-    }
-
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8()); // Send End of Interrupt signal to PICs
-    }   
+// Handler of the timer IRQ, registered by `init`.
+fn timer_interrupt() -> irq::IrqResult {
+    crate::time::tick();
+    crate::thread::timer_tick(); // the thread switch is done by irq::dispatch, after the EOI
+    irq::IrqResult::Handled
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    _stack_frame: InterruptStackFrame, _error_code: u64) -> ! // diverging cant return from double fault
-{
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", _stack_frame);
+// Raised by the local APIC when an interrupt goes away before it is delivered, no EOI for it.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _entry = stats::enter(apic::SPURIOUS_VECTOR);
+    irq::count_apic_spurious();
 }
 
 
@@ -0,0 +1,214 @@
+/*
+
+Local APIC and I/O APIC
+-----------------------
+
+Found with the ACPI MADT. Once `init` succeeded the 8259 PICs are masked: ISA IRQs are routed by the
+I/O APIC to the local APIC of the boot processor, and the end of interrupt goes to the local APIC.
+Vectors stay the same as with the PICs (`PIC_1_OFFSET + irq`), so `InterruptIndex` works with both controllers.
+Every line with a handler in the IRQ registry is routed by `init`, lines registered later by `irq::register_irq`.
+ISA IRQs may be wired to another global system interrupt (GSI) than their number, e.g. the PIT (IRQ 0)
+is GSI 2 on QEMU: the interrupt source overrides of the MADT are followed.
+*/
+
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::instructions::interrupts;
+use super::irq::{self, IRQ_LINES};
+use crate::acpi::madt::{self, Madt, MadtEntry};
+use crate::memory;
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// Local APIC registers, offsets from its base
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_LVT_LINT0: usize = 0x350; // wired to the PICs (ExtINT)
+const LAPIC_ENABLE: u32 = 1 << 8; // in the spurious interrupt vector register
+const LVT_MASKED: u32 = 1 << 16;
+
+// I/O APIC registers, accessed through a select register and a window
+const IOAPIC_SELECT: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10; // two registers per entry
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+const MAX_IO_APICS: usize = 4;
+
+#[derive(Debug)]
+pub enum ApicError {
+    NoMadt, // acpi::init was not called or failed
+    NoIoApic,
+    NoRoute(u32), // no I/O APIC handles this GSI
+    Mapping(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for ApicError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        ApicError::Mapping(error)
+    }
+}
+
+// Virtual address of the local APIC registers, 0 while the PICs are used.
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    redirections: u32, // number of inputs
+}
+
+// Taken with interrupts disabled: a register access is a select then a read or write.
+static IO_APICS: Mutex<[Option<IoApic>; MAX_IO_APICS]> = Mutex::new([None, None, None, None]);
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        ptr::write_volatile((self.base.as_u64() as usize + IOAPIC_SELECT) as *mut u32, register);
+        ptr::read_volatile((self.base.as_u64() as usize + IOAPIC_WINDOW) as *const u32)
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        ptr::write_volatile((self.base.as_u64() as usize + IOAPIC_SELECT) as *mut u32, register);
+        ptr::write_volatile((self.base.as_u64() as usize + IOAPIC_WINDOW) as *mut u32, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.redirections
+    }
+
+    unsafe fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        self.write(register, REDIRECTION_MASKED as u32); // never half updated while unmasked
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+
+    unsafe fn mask_all(&self) {
+        for gsi in self.gsi_base..self.gsi_base + self.redirections {
+            self.set_redirection(gsi, REDIRECTION_MASKED);
+        }
+    }
+}
+
+unsafe fn local_apic_read(register: usize) -> u32 {
+    ptr::read_volatile((LOCAL_APIC.load(Ordering::Relaxed) as usize + register) as *const u32)
+}
+
+unsafe fn local_apic_write(register: usize, value: u32) {
+    ptr::write_volatile((LOCAL_APIC.load(Ordering::Relaxed) as usize + register) as *mut u32, value);
+}
+
+// Switch from the PICs to the APICs. Needs `acpi::init` and `memory::init_kernel_memory`.
+// On error the PICs are still used.
+pub fn init() -> Result<(), ApicError> {
+    let madt = Madt::get().ok_or(ApicError::NoMadt)?;
+    let local_apic = memory::map_mmio(madt.local_apic_address(), 4096)?;
+
+    let mut io_apics: [Option<IoApic>; MAX_IO_APICS] = [None, None, None, None];
+    let found = madt.entries().filter_map(|entry| match entry {
+        MadtEntry::IoApic { address, gsi_base, .. } => Some((address, gsi_base)),
+        _ => None,
+    });
+    for (slot, (address, gsi_base)) in io_apics.iter_mut().zip(found) {
+        let base = memory::map_mmio(PhysAddr::new(u64::from(address)), 0x20)?;
+        let mut io_apic = IoApic { base, gsi_base, redirections: 0 };
+        unsafe {
+            io_apic.redirections = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+            io_apic.mask_all();
+        }
+        *slot = Some(io_apic);
+    }
+    if io_apics.iter().all(Option::is_none) {
+        return Err(ApicError::NoIoApic);
+    }
+
+    interrupts::without_interrupts(|| {
+        *IO_APICS.lock() = io_apics;
+        LOCAL_APIC.store(local_apic.as_u64(), Ordering::Relaxed);
+        for line in (0..IRQ_LINES as u8).filter(|&line| irq::is_registered(line)) {
+            if let Err(error) = route_isa_irq(line, irq::vector(line)) {
+                // back to the PICs: the lines routed so far must not also come through the I/O APICs
+                for io_apic in IO_APICS.lock().iter().flatten() {
+                    unsafe { io_apic.mask_all() };
+                }
+                LOCAL_APIC.store(0, Ordering::Relaxed);
+                return Err(error);
+            }
+        }
+        super::mask_pics();
+        unsafe {
+            local_apic_write(LAPIC_SPURIOUS, LAPIC_ENABLE | u32::from(SPURIOUS_VECTOR));
+            local_apic_write(LAPIC_TASK_PRIORITY, 0); // accept every vector
+            local_apic_write(LAPIC_LVT_TIMER, LVT_MASKED); // the PIT is the timer
+            local_apic_write(LAPIC_LVT_LINT0, LVT_MASKED);
+        }
+        Ok(())
+    })
+}
+
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::Relaxed) != 0
+}
+
+pub fn end_of_interrupt() {
+    unsafe { local_apic_write(LAPIC_EOI, 0) };
+}
+
+pub fn local_apic_id() -> u8 {
+    (unsafe { local_apic_read(LAPIC_ID) } >> 24) as u8
+}
+
+// GSI and override flags of ISA IRQ `irq`: ISA interrupts are active high and edge triggered unless
+// an override says otherwise.
+fn isa_irq_source(madt: &Madt, irq: u8) -> (u32, u16) {
+    madt.entries()
+        .find_map(|entry| match entry {
+            MadtEntry::InterruptSourceOverride { bus: 0, source, gsi, flags } if source == irq => Some((gsi, flags)),
+            _ => None,
+        })
+        .unwrap_or((u32::from(irq), 0))
+}
+
+// I/O APIC input ISA IRQ `irq` is wired to, the IRQ itself without a MADT.
+pub fn isa_irq_gsi(irq: u8) -> u32 {
+    Madt::get().map_or(u32::from(irq), |madt| isa_irq_source(&madt, irq).0)
+}
+
+// Deliver ISA IRQ `irq` as `vector` to this processor.
+pub fn route_isa_irq(irq: u8, vector: u8) -> Result<(), ApicError> {
+    let (gsi, flags) = isa_irq_source(&Madt::get().ok_or(ApicError::NoMadt)?, irq);
+
+    let mut entry = u64::from(vector) | u64::from(local_apic_id()) << 56; // fixed delivery, physical destination
+    if flags & madt::POLARITY_MASK == madt::POLARITY_ACTIVE_LOW {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if flags & madt::TRIGGER_MASK == madt::TRIGGER_LEVEL {
+        entry |= REDIRECTION_LEVEL;
+    }
+    interrupts::without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+        let io_apic = io_apics.iter().flatten().find(|io_apic| io_apic.handles(gsi)).ok_or(ApicError::NoRoute(gsi))?;
+        unsafe { io_apic.set_redirection(gsi, entry) };
+        Ok(())
+    })
+}
+
+// Stop delivering ISA IRQ `irq`, until it is routed again.
+pub fn mask_isa_irq(irq: u8) -> Result<(), ApicError> {
+    let gsi = isa_irq_gsi(irq);
+    interrupts::without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+        let io_apic = io_apics.iter().flatten().find(|io_apic| io_apic.handles(gsi)).ok_or(ApicError::NoRoute(gsi))?;
+        unsafe { io_apic.set_redirection(gsi, REDIRECTION_MASKED) };
+        Ok(())
+    })
+}
@@ -0,0 +1,280 @@
+/*
+
+CPU exceptions
+--------------
+
+Every exception goes through an assembly stub that pushes a dummy error code (when the CPU does not push one),
+the vector and all general registers, then calls `rusty_exception_dispatch` with a pointer to that `ExceptionContext`.
+Having the registers saved by us is what lets the report show their values at the time of the fault.
+
+Every exception prints a report on VGA and serial. Then a fault in user code kills the thread and a fault
+in the kernel panics. The one kernel fault that kills the thread instead is a page fault on user memory in
+`usermode::copy_from_user`: the syscall was given a bad pointer. Debug and NMI only print the report and
+return, so does a breakpoint in the kernel.
+Vectors 21 (control protection), 28 and 29 are not installed, x86_64 0.14.2 has no IDT fields for them.
+*/
+
+use core::arch::global_asm;
+use core::fmt;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrameValue, PageFaultErrorCode};
+use x86_64::VirtAddr;
+use crate::{gdt, println, serial_println, usermode};
+use crate::backtrace::Backtrace;
+use super::stats;
+
+pub const DIVIDE_ERROR: u64 = 0;
+pub const DEBUG: u64 = 1;
+pub const NON_MASKABLE_INTERRUPT: u64 = 2;
+pub const BREAKPOINT: u64 = 3;
+pub const INVALID_TSS: u64 = 10;
+pub const SEGMENT_NOT_PRESENT: u64 = 11;
+pub const STACK_SEGMENT_FAULT: u64 = 12;
+pub const GENERAL_PROTECTION_FAULT: u64 = 13;
+pub const PAGE_FAULT: u64 = 14;
+
+const EXCEPTION_NAMES: [&str; 32] = [
+    "DIVIDE ERROR", "DEBUG", "NON-MASKABLE INTERRUPT", "BREAKPOINT",
+    "OVERFLOW", "BOUND RANGE EXCEEDED", "INVALID OPCODE", "DEVICE NOT AVAILABLE",
+    "DOUBLE FAULT", "COPROCESSOR SEGMENT OVERRUN", "INVALID TSS", "SEGMENT NOT PRESENT",
+    "STACK-SEGMENT FAULT", "GENERAL PROTECTION FAULT", "PAGE FAULT", "RESERVED",
+    "X87 FLOATING-POINT", "ALIGNMENT CHECK", "MACHINE CHECK", "SIMD FLOATING-POINT",
+    "VIRTUALIZATION", "CONTROL PROTECTION", "RESERVED", "RESERVED",
+    "RESERVED", "RESERVED", "RESERVED", "RESERVED",
+    "HYPERVISOR INJECTION", "VMM COMMUNICATION", "SECURITY", "RESERVED",
+];
+
+pub fn exception_name(vector: u64) -> &'static str {
+    EXCEPTION_NAMES.get(vector as usize).copied().unwrap_or("UNKNOWN")
+}
+
+// Everything pushed on the stack by the CPU and the entry stubs, from the lowest address.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64, // 0 for exceptions without one
+    pub frame: InterruptStackFrameValue,
+}
+
+global_asm!(
+    ".macro exception_entry vector, error_code",
+    ".global rusty_exception_\\vector",
+    "rusty_exception_\\vector:",
+    ".if \\error_code == 0",
+    "push 0", // same layout with or without an error code
+    ".endif",
+    "push \\vector",
+    "jmp rusty_exception_common",
+    ".endm",
+    "",
+    "exception_entry 0, 0",
+    "exception_entry 1, 0",
+    "exception_entry 2, 0",
+    "exception_entry 3, 0",
+    "exception_entry 4, 0",
+    "exception_entry 5, 0",
+    "exception_entry 6, 0",
+    "exception_entry 7, 0",
+    "exception_entry 8, 1",
+    "exception_entry 10, 1",
+    "exception_entry 11, 1",
+    "exception_entry 12, 1",
+    "exception_entry 13, 1",
+    "exception_entry 14, 1",
+    "exception_entry 16, 0",
+    "exception_entry 17, 1",
+    "exception_entry 18, 0",
+    "exception_entry 19, 0",
+    "exception_entry 20, 0",
+    "exception_entry 30, 1",
+    "",
+    "rusty_exception_common:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp", // &mut ExceptionContext, the stack is 16 bytes aligned here
+    "cld",
+    "call rusty_exception_dispatch",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "add rsp, 16", // vector and error code
+    "iretq",
+);
+
+extern "C" {
+    fn rusty_exception_0();
+    fn rusty_exception_1();
+    fn rusty_exception_2();
+    fn rusty_exception_3();
+    fn rusty_exception_4();
+    fn rusty_exception_5();
+    fn rusty_exception_6();
+    fn rusty_exception_7();
+    fn rusty_exception_8();
+    fn rusty_exception_10();
+    fn rusty_exception_11();
+    fn rusty_exception_12();
+    fn rusty_exception_13();
+    fn rusty_exception_14();
+    fn rusty_exception_16();
+    fn rusty_exception_17();
+    fn rusty_exception_18();
+    fn rusty_exception_19();
+    fn rusty_exception_20();
+    fn rusty_exception_30();
+}
+
+fn stub(entry: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(entry as *const () as u64)
+}
+
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_addr(stub(rusty_exception_0));
+        idt.debug.set_handler_addr(stub(rusty_exception_1));
+        idt.non_maskable_interrupt.set_handler_addr(stub(rusty_exception_2));
+        idt.breakpoint.set_handler_addr(stub(rusty_exception_3));
+        idt.overflow.set_handler_addr(stub(rusty_exception_4));
+        idt.bound_range_exceeded.set_handler_addr(stub(rusty_exception_5));
+        idt.invalid_opcode.set_handler_addr(stub(rusty_exception_6));
+        idt.device_not_available.set_handler_addr(stub(rusty_exception_7));
+        idt.double_fault.set_handler_addr(stub(rusty_exception_8))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX); // the kernel stack may be what overflowed
+        idt.invalid_tss.set_handler_addr(stub(rusty_exception_10));
+        idt.segment_not_present.set_handler_addr(stub(rusty_exception_11));
+        idt.stack_segment_fault.set_handler_addr(stub(rusty_exception_12));
+        idt.general_protection_fault.set_handler_addr(stub(rusty_exception_13));
+        idt.page_fault.set_handler_addr(stub(rusty_exception_14));
+        idt.x87_floating_point.set_handler_addr(stub(rusty_exception_16));
+        idt.alignment_check.set_handler_addr(stub(rusty_exception_17));
+        idt.machine_check.set_handler_addr(stub(rusty_exception_18));
+        idt.simd_floating_point.set_handler_addr(stub(rusty_exception_19));
+        idt.virtualization.set_handler_addr(stub(rusty_exception_20));
+        idt.security_exception.set_handler_addr(stub(rusty_exception_30));
+    }
+}
+
+#[no_mangle]
+extern "C" fn rusty_exception_dispatch(context: &mut ExceptionContext) {
+    use x86_64::registers::control::Cr2;
+
+    let _entry = stats::enter(context.vector as u8);
+    let name = exception_name(context.vector);
+    let from_user = usermode::from_user_mode(&context.frame);
+    match context.vector {
+        DEBUG | NON_MASKABLE_INTERRUPT => { // not caused by the interrupted code, whatever its privilege level
+            report(context);
+            return;
+        }
+        BREAKPOINT if !from_user => {
+            report(context);
+            return;
+        }
+        PAGE_FAULT if usermode::is_user_copy(context.frame.instruction_pointer.as_u64())
+            && usermode::is_user_range(Cr2::read().as_u64(), 1) => { // bad pointer given to a syscall
+            usermode::kill_faulting_thread(context);
+        }
+        _ if from_user => usermode::kill_faulting_thread(context),
+        _ => {}
+    }
+    report(context);
+    panic!("EXCEPTION: {} in kernel at {:?}", name, context.frame.instruction_pointer);
+}
+
+pub fn report(context: &ExceptionContext) {
+    let report = FaultReport(context);
+    if usermode::from_user_mode(&context.frame) { // no kernel frames to walk, rbp belongs to the program
+        println!("{}", report);
+        serial_println!("{}", report);
+        return;
+    }
+    let backtrace = Backtrace::from_fault(context.frame.instruction_pointer.as_u64(), context.rbp);
+    println!("{}\n{}", report, backtrace);
+    serial_println!("{}\n{}", report, backtrace);
+}
+
+// The structured report printed for every exception.
+pub struct FaultReport<'a>(pub &'a ExceptionContext);
+
+impl fmt::Display for FaultReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use x86_64::registers::control::{Cr2, Cr3};
+
+        let context = self.0;
+        let frame = &context.frame;
+        let (page_table, _) = Cr3::read();
+        writeln!(f, "EXCEPTION: {} (vector {})", exception_name(context.vector), context.vector)?;
+        writeln!(f, "error code: {:#x} {}", context.error_code, ErrorCode(context.vector, context.error_code))?;
+        writeln!(f, "rip: {:#018x}  cs: {:#x}  rflags: {:#x}",
+            frame.instruction_pointer.as_u64(), frame.code_segment, frame.cpu_flags)?;
+        writeln!(f, "rsp: {:#018x}  ss: {:#x}", frame.stack_pointer.as_u64(), frame.stack_segment)?;
+        writeln!(f, "cr2: {:#018x}  cr3: {:#018x}", Cr2::read().as_u64(), page_table.start_address().as_u64())?;
+        writeln!(f, "rax: {:#018x}  rbx: {:#018x}  rcx: {:#018x}", context.rax, context.rbx, context.rcx)?;
+        writeln!(f, "rdx: {:#018x}  rsi: {:#018x}  rdi: {:#018x}", context.rdx, context.rsi, context.rdi)?;
+        writeln!(f, "rbp: {:#018x}  r8:  {:#018x}  r9:  {:#018x}", context.rbp, context.r8, context.r9)?;
+        writeln!(f, "r10: {:#018x}  r11: {:#018x}  r12: {:#018x}", context.r10, context.r11, context.r12)?;
+        write!(f, "r13: {:#018x}  r14: {:#018x}  r15: {:#018x}", context.r13, context.r14, context.r15)
+    }
+}
+
+// Decoded error code of an exception.
+struct ErrorCode(u64, u64); // (vector, code)
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ErrorCode(vector, code) = *self;
+        match vector {
+            PAGE_FAULT => write!(f, "{:?}", PageFaultErrorCode::from_bits_truncate(code)),
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT if code != 0 => {
+                // Selector error code: which descriptor caused the fault
+                let table = match (code >> 1) & 0b11 {
+                    0 => "GDT",
+                    2 => "LDT",
+                    _ => "IDT",
+                };
+                write!(f, "({} index {}{})", table, (code >> 3) & 0x1fff, if code & 1 != 0 { ", external" } else { "" })
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,222 @@
+/*
+
+IRQ registry
+------------
+
+The 16 ISA IRQ lines all enter `dispatch` (vectors PIC_1_OFFSET..PIC_1_OFFSET + 16). Drivers add their handler
+at run time with `register_irq(line, handler)` and remove it with `unregister_irq`, up to MAX_HANDLERS_PER_LINE
+handlers can share a line: every handler runs and tells if its device raised the interrupt.
+
+`dispatch` sends the end of interrupt after the handlers ran, to the PICs or the local APIC, whichever is in use:
+a level triggered line is only acknowledged once its devices were serviced. Handlers never switch threads,
+the timer asks for it and `dispatch` switches last, after the EOI.
+
+A spurious IRQ 7 or 15 from the PICs (the line went away before the CPU acknowledged it) is detected
+with the in-service register: it is counted and gets no EOI, or only the primary PIC gets it for IRQ 15.
+*/
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use super::{apic, stats, PICS, PIC_1_OFFSET};
+
+pub const IRQ_LINES: usize = 16;
+pub const MAX_HANDLERS_PER_LINE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqResult {
+    Handled,
+    NotMine, // the device of this handler did not raise the interrupt
+}
+
+// Called in the interrupt handler with interrupts disabled: must not block or allocate.
+pub type IrqHandler = fn() -> IrqResult;
+
+#[derive(Debug)]
+pub enum IrqError {
+    InvalidLine(u8),
+    LineFull(u8), // MAX_HANDLERS_PER_LINE handlers already
+    NotRegistered(u8), // the handler is not on this line
+    Routing(apic::ApicError),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IrqStats {
+    pub count: u64, // interrupts delivered to the handlers
+    pub unhandled: u64, // no handler claimed them
+    pub spurious: u64,
+}
+
+// Taken with interrupts disabled.
+static HANDLERS: Mutex<[[Option<IrqHandler>; MAX_HANDLERS_PER_LINE]; IRQ_LINES]> =
+    Mutex::new([[None; MAX_HANDLERS_PER_LINE]; IRQ_LINES]);
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; IRQ_LINES] = [ZERO; IRQ_LINES];
+static UNHANDLED: [AtomicU64; IRQ_LINES] = [ZERO; IRQ_LINES];
+static SPURIOUS: [AtomicU64; IRQ_LINES] = [ZERO; IRQ_LINES];
+
+// Add `handler` to the handlers of ISA IRQ `line` and make sure the line reaches the CPU.
+pub fn register_irq(line: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if usize::from(line) >= IRQ_LINES {
+        return Err(IrqError::InvalidLine(line));
+    }
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let slot = handlers[usize::from(line)].iter_mut().find(|slot| slot.is_none()).ok_or(IrqError::LineFull(line))?;
+        *slot = Some(handler);
+        drop(handlers);
+        enable_line(line)
+    })
+}
+
+// Remove `handler` from the handlers of ISA IRQ `line`, the line is masked once it has none.
+// An interrupt already being dispatched may still call it once.
+pub fn unregister_irq(line: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if usize::from(line) >= IRQ_LINES {
+        return Err(IrqError::InvalidLine(line));
+    }
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let handlers = &mut handlers[usize::from(line)];
+        let slot = handlers.iter_mut()
+            .find(|slot| slot.map_or(false, |registered| registered as usize == handler as usize))
+            .ok_or(IrqError::NotRegistered(line))?;
+        *slot = None;
+        if handlers.iter().all(Option::is_none) {
+            disable_line(line)?;
+        }
+        Ok(())
+    })
+}
+
+pub fn is_registered(line: u8) -> bool {
+    interrupts::without_interrupts(|| {
+        HANDLERS.lock().get(usize::from(line)).map_or(false, |handlers| handlers.iter().any(Option::is_some))
+    })
+}
+
+pub fn stats(line: u8) -> IrqStats {
+    let line = usize::from(line);
+    IrqStats {
+        count: COUNTS[line].load(Ordering::Relaxed),
+        unhandled: UNHANDLED[line].load(Ordering::Relaxed),
+        spurious: SPURIOUS[line].load(Ordering::Relaxed),
+    }
+}
+
+pub fn vector(line: u8) -> u8 {
+    PIC_1_OFFSET + line
+}
+
+fn enable_line(line: u8) -> Result<(), IrqError> {
+    if apic::is_enabled() {
+        apic::route_isa_irq(line, vector(line)).map_err(IrqError::Routing)
+    } else {
+        unmask_pic_line(line);
+        Ok(())
+    }
+}
+
+fn disable_line(line: u8) -> Result<(), IrqError> {
+    if apic::is_enabled() {
+        apic::mask_isa_irq(line).map_err(IrqError::Routing)
+    } else {
+        mask_pic_line(line);
+        Ok(())
+    }
+}
+
+// The chained line 2 stays unmasked, other lines of the secondary PIC may use it.
+fn mask_pic_line(line: u8) {
+    let (mut data, bit) = if line < 8 { (Port::<u8>::new(0x21), line) } else { (Port::<u8>::new(0xa1), line - 8) };
+    unsafe {
+        let mask = data.read();
+        data.write(mask | 1 << bit);
+    }
+}
+
+fn unmask_pic_line(line: u8) {
+    let (mut data, bit) = if line < 8 { (Port::<u8>::new(0x21), line) } else { (Port::<u8>::new(0xa1), line - 8) };
+    unsafe {
+        let mask = data.read();
+        data.write(mask & !(1 << bit));
+        if line >= 8 { // the secondary PIC is chained on IRQ 2
+            let mut primary = Port::<u8>::new(0x21);
+            let mask = primary.read();
+            primary.write(mask & !(1 << 2));
+        }
+    }
+}
+
+// In-service register of the PICs: bit n is set while IRQ n is being handled.
+fn pic_in_service() -> u16 {
+    const READ_ISR: u8 = 0x0b; // OCW3
+    let (mut primary, mut secondary) = (Port::<u8>::new(0x20), Port::<u8>::new(0xa0));
+    unsafe {
+        primary.write(READ_ISR);
+        secondary.write(READ_ISR);
+        u16::from(secondary.read()) << 8 | u16::from(primary.read())
+    }
+}
+
+fn dispatch(line: u8) {
+    let _entry = stats::enter(vector(line)); // spurious interrupts included
+    if !apic::is_enabled() && (line == 7 || line == 15) && pic_in_service() & (1 << line) == 0 {
+        SPURIOUS[usize::from(line)].fetch_add(1, Ordering::Relaxed);
+        if line == 15 { // the primary PIC did see an interrupt on the chained line
+            unsafe { PICS.lock().notify_end_of_interrupt(vector(2)) };
+        }
+        return;
+    }
+    COUNTS[usize::from(line)].fetch_add(1, Ordering::Relaxed);
+
+    let handlers = HANDLERS.lock()[usize::from(line)]; // copied, the lock is not held while they run
+    let mut handled = false;
+    for handler in handlers.iter().flatten() {
+        handled |= handler() == IrqResult::Handled;
+    }
+    if !handled {
+        UNHANDLED[usize::from(line)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector(line)) };
+    }
+    crate::thread::preempt_if_requested();
+}
+
+static APIC_SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+// Interrupts at the spurious vector of the local APIC, they belong to no line.
+pub fn apic_spurious() -> u64 {
+    APIC_SPURIOUS.load(Ordering::Relaxed)
+}
+
+pub(super) fn count_apic_spurious() {
+    APIC_SPURIOUS.fetch_add(1, Ordering::Relaxed);
+}
+
+macro_rules! irq_entries {
+    ($($line:literal),*) => {
+        [$({
+            extern "x86-interrupt" fn entry(_stack_frame: InterruptStackFrame) {
+                dispatch($line);
+            }
+            entry
+        }),*]
+    };
+}
+
+static ENTRIES: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_LINES] =
+    irq_entries!(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    for (line, entry) in ENTRIES.iter().enumerate() {
+        idt[usize::from(vector(line as u8))].set_handler_fn(*entry);
+    }
+}
@@ -0,0 +1,99 @@
+/*
+
+Interrupt statistics
+--------------------
+
+One counter per vector, incremented when an exception or IRQ handler starts, and a count of nested
+interrupts: those arriving while another handler of the same thread is still running (e.g. a page fault
+in an IRQ handler, or an NMI). The nesting depth belongs to the running thread: the timer handler
+switches threads, so the scheduler saves and restores it with `depth` and `set_depth`.
+Spurious interrupts are counted by the IRQ registry, the table shows them with their line.
+*/
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use super::{apic, exceptions, irq, vectors};
+use crate::{println, serial_println};
+
+const VECTORS: usize = 256;
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; VECTORS] = [ZERO; VECTORS];
+static NESTED: AtomicU64 = AtomicU64::new(0);
+static DEPTH: AtomicU64 = AtomicU64::new(0); // handlers running in the current thread
+
+// Handler of `vector` running, until dropped.
+pub struct InterruptEntry(());
+
+impl Drop for InterruptEntry {
+    fn drop(&mut self) {
+        DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Called first by every handler, keep the returned value until it returns.
+pub fn enter(vector: u8) -> InterruptEntry {
+    COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+    if DEPTH.fetch_add(1, Ordering::Relaxed) > 0 {
+        NESTED.fetch_add(1, Ordering::Relaxed);
+    }
+    InterruptEntry(())
+}
+
+pub fn count(vector: u8) -> u64 {
+    COUNTS[usize::from(vector)].load(Ordering::Relaxed)
+}
+
+pub fn nested() -> u64 {
+    NESTED.load(Ordering::Relaxed)
+}
+
+pub fn spurious() -> u64 {
+    (0..irq::IRQ_LINES as u8).map(|line| irq::stats(line).spurious).sum::<u64>() + irq::apic_spurious()
+}
+
+// Saved and restored by the scheduler with the thread.
+pub(crate) fn depth() -> u64 {
+    DEPTH.load(Ordering::Relaxed)
+}
+
+pub(crate) fn set_depth(depth: u64) {
+    DEPTH.store(depth, Ordering::Relaxed);
+}
+
+pub fn dump_vga() {
+    println!("{}", InterruptTable);
+}
+
+pub fn dump_serial() {
+    serial_println!("{}", InterruptTable);
+}
+
+// Every vector that fired at least once, then the totals.
+pub struct InterruptTable;
+
+impl fmt::Display for InterruptTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:>6}  {:<26} {:>10} {:>9} {:>8}", "vector", "source", "count", "unhandled", "spurious")?;
+        for vector in 0..VECTORS {
+            let vector = vector as u8;
+            let line = vector.wrapping_sub(irq::vector(0));
+            let count = count(vector);
+            if usize::from(line) < irq::IRQ_LINES {
+                let stats = irq::stats(line);
+                if count > 0 {
+                    writeln!(f, "{:>6}  IRQ {:<22} {:>10} {:>9} {:>8}", vector, line, count, stats.unhandled, stats.spurious)?;
+                }
+            } else if count > 0 {
+                let source = match vector {
+                    0..=31 => exceptions::exception_name(u64::from(vector)),
+                    apic::SPURIOUS_VECTOR => "APIC SPURIOUS",
+                    _ if vector >= vectors::FIRST_VECTOR && usize::from(vector - vectors::FIRST_VECTOR) < vectors::VECTOR_COUNT => "DYNAMIC",
+                    _ => "OTHER",
+                };
+                writeln!(f, "{:>6}  {:<26} {:>10}", vector, source, count)?;
+            }
+        }
+        write!(f, "nested: {}, spurious: {}", nested(), spurious())
+    }
+}
@@ -0,0 +1,88 @@
+/*
+
+Dynamic vectors
+---------------
+
+The IDT is built once, so the vectors FIRST_VECTOR..FIRST_VECTOR + VECTOR_COUNT all get an entry up front;
+drivers allocate one of them at run time with a handler, e.g. for an MSI or MSI-X interrupt.
+These interrupts come through the local APIC only: the end of interrupt goes to it, and allocation fails
+while the PICs are in use. Like `irq::dispatch`, the end of interrupt is sent once the handler returned and
+a thread switch asked for by the timer waits until then.
+*/
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use super::{apic, stats};
+
+pub const FIRST_VECTOR: u8 = 0x40; // after the PIC and ISA IRQ vectors
+pub const VECTOR_COUNT: usize = 32;
+
+// Called in the interrupt handler with the argument given at allocation: must not block or allocate.
+pub type VectorHandler = fn(usize);
+
+#[derive(Debug)]
+pub enum VectorError {
+    ApicDisabled, // apic::init was not called or failed
+    NoFreeVector,
+}
+
+// Taken with interrupts disabled.
+static HANDLERS: Mutex<[Option<(VectorHandler, usize)>; VECTOR_COUNT]> = Mutex::new([None; VECTOR_COUNT]);
+
+// Reserve a vector whose interrupts call `handler(argument)`.
+pub fn allocate_vector(handler: VectorHandler, argument: usize) -> Result<u8, VectorError> {
+    if !apic::is_enabled() {
+        return Err(VectorError::ApicDisabled);
+    }
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let index = handlers.iter().position(Option::is_none).ok_or(VectorError::NoFreeVector)?;
+        handlers[index] = Some((handler, argument));
+        Ok(FIRST_VECTOR + index as u8)
+    })
+}
+
+// The device must not raise the vector anymore.
+pub fn free_vector(vector: u8) {
+    if let Some(index) = index(vector) {
+        interrupts::without_interrupts(|| HANDLERS.lock()[index] = None);
+    }
+}
+
+fn index(vector: u8) -> Option<usize> {
+    let index = usize::from(vector.checked_sub(FIRST_VECTOR)?);
+    (index < VECTOR_COUNT).then(|| index)
+}
+
+fn dispatch(index: usize) {
+    let _entry = stats::enter(FIRST_VECTOR + index as u8);
+    let handler = HANDLERS.lock()[index]; // copied, the lock is not held while it runs
+    if let Some((handler, argument)) = handler {
+        handler(argument);
+    }
+    apic::end_of_interrupt(); // after the handler, as for IRQs
+    crate::thread::preempt_if_requested(); // last: the next thread may run before this interrupt returns
+}
+
+macro_rules! vector_entries {
+    ($($index:literal),*) => {
+        [$({
+            extern "x86-interrupt" fn entry(_stack_frame: InterruptStackFrame) {
+                dispatch($index);
+            }
+            entry
+        }),*]
+    };
+}
+
+static ENTRIES: [extern "x86-interrupt" fn(InterruptStackFrame); VECTOR_COUNT] = vector_entries!(
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+    16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+);
+
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    for (index, entry) in ENTRIES.iter().enumerate() {
+        idt[usize::from(FIRST_VECTOR) + index].set_handler_fn(*entry);
+    }
+}
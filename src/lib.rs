@@ -11,11 +11,14 @@ pub fn init() {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() }; // Unsafe because we are not sure if the PICS is initialized.
+    time::init(time::DEFAULT_FREQUENCY);
+    interrupts::register_core_handlers();
     x86_64::instructions::interrupts::enable();
 }
 
 
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 pub trait Testable {
     fn run(&self) -> ();
@@ -27,11 +30,26 @@ where
 {
     fn run(&self) {
         serial_print!("{}...\t", core::any::type_name::<T>());
+        LEAKS_ALLOWED.store(false, Ordering::Relaxed);
+        let live_before = allocator::stats().live_allocations();
         self();
+        let live_after = allocator::stats().live_allocations();
+        if !LEAKS_ALLOWED.load(Ordering::Relaxed) && live_after > live_before {
+            panic!("test leaked {} allocations\n{}", live_after - live_before, allocator::stats());
+        }
         serial_println!("[ok]");
     }
 }
 
+static LEAKS_ALLOWED: AtomicBool = AtomicBool::new(false);
+
+// A test fails if it returns with more live heap allocations than it started with.
+// Called by a test that keeps memory on purpose, e.g. the scheduler state of the threads it spawned:
+// only the running test is exempt, the next one is checked again.
+pub fn allow_leaks() {
+    LEAKS_ALLOWED.store(true, Ordering::Relaxed);
+}
+
 pub fn test_runner(tests: &[&dyn Testable]) {
     serial_println!("Running {} tests", tests.len());
     for test in tests {
@@ -43,6 +61,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    serial_println!("{}\n", backtrace::Backtrace::capture());
     shutdown(QemuExitCode::Failed);
     hlt_loop();
 }
@@ -103,5 +122,17 @@ pub mod interrupts;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
+pub mod task;
+pub mod thread;
+pub mod syscall;
+pub mod usermode;
+pub mod elf;
+pub mod backtrace;
+pub mod acpi;
+pub mod time;
+pub mod power;
+pub mod pci;
+pub mod block;
+pub mod virtio;
 
 extern crate alloc;
\ No newline at end of file
@@ -7,6 +7,7 @@
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use rusty_os::println;
+use rusty_os::task::{Task, executor::Executor, keyboard};
 extern crate alloc;
 use alloc::{boxed::Box, vec, vec::Vec, rc::Rc};
 
@@ -15,7 +16,7 @@ entry_point!(kernel_main); // macro to define the entry point of the program to
 
 pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use rusty_os::memory::{self, BootInfoFrameAllocator};
-    use rusty_os::allocator;
+    use rusty_os::{acpi, allocator, block, interrupts, pci, thread, time};
     use x86_64::{VirtAddr};
     
     println!(" > Booting rusty, welcome MR. GOFFI");
@@ -34,6 +35,38 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator); // the heap grows with them from now on
+
+    let acpi = acpi::init().map_err(|error| println!(" > ACPI not available: {:?}", error));
+    for table in acpi::list() {
+        println!(" > ACPI table {}", table);
+    }
+    if let Err(error) = time::sync_wall_clock() { // with the century register of the FADT now
+        println!(" > RTC date not available: {:?}", error);
+    }
+    // Replace the 8259 PICs with the local APIC and I/O APIC when ACPI describes them
+    let apic = acpi
+        .and_then(|()| interrupts::apic::init().map_err(|error| println!(" > APIC not available: {:?}", error)));
+    if apic.is_ok() {
+        println!(" > Interrupts routed through the APIC");
+    }
+    if let Err(error) = time::init_hpet() {
+        println!(" > HPET not available: {:?}", error);
+    } else if let Err(error) = time::hpet::init_oneshot() {
+        println!(" > HPET one-shot interrupts not available: {:?}", error);
+    }
+    println!(" > Clock source: {:?}", time::clock_source());
+    pci::init();
+    for device in pci::devices() {
+        println!(" > PCI {}", device);
+    }
+    block::ata::init();
+    block::virtio_blk::init();
+    for disk in block::devices() {
+        let disk = disk.lock();
+        println!(" > Disk {}: {} sectors of {} bytes", disk.name(), disk.sector_count(), disk.sector_size());
+    }
+    thread::init(); // kernel_main becomes the boot thread, the timer starts switching threads
 
     // allocate a number on the heap
     let heap_value = Box::new(41);
@@ -53,18 +86,39 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     core::mem::drop(reference_counted);
     println!("reference count is {} now", Rc::strong_count(&cloned_reference));
 
+    // run in parallel with the executor below, preempted by the timer
+    let spawned = thread::spawn(|| {
+        println!("kernel thread {} started", thread::current().as_u64());
+    });
+    if let Err(error) = spawned {
+        println!("kernel thread not started: {:?}", error);
+    }
+
 
     
     #[cfg(test)]
     test_main();
 
-    rusty_os::hlt_loop();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(example_task()));
+    executor.spawn(Task::new(keyboard::print_keypresses())); // keyboard input is handled by a task now
+    executor.run();
+}
+
+async fn async_number() -> u32 {
+    42
+}
+
+async fn example_task() {
+    let number = async_number().await;
+    println!("async number: {}", number);
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    println!("{}", rusty_os::backtrace::Backtrace::capture());
     rusty_os::hlt_loop();
 }
 
@@ -1,14 +1,168 @@
-use x86_64::{structures::paging::{PageTable, OffsetPageTable, PhysFrame, Size4KiB, FrameAllocator}, VirtAddr, PhysAddr};
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{structures::paging::{PageTable, OffsetPageTable, PhysFrame, Size4KiB, FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, mapper::{MapToError, UnmapError}}, VirtAddr, PhysAddr};
+use bootloader::bootinfo::{MemoryMap, MemoryRegion, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub mod buddy;
 
 //Init offsetPageTable
 // Unsafe because caller must guarantee that the comlete physical memory is mapped.
 // return instance with static lifetime: valid for complete runtimr of the kernel.
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>  { 
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+// Where the bootloader mapped the complete physical memory, set by `init`.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// Virtual address through which the kernel can access the given physical address.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    assert!(offset != 0, "memory::init was not called");
+    VirtAddr::new(offset + addr.as_u64())
+}
+
+// Is `addr` mapped in the active page table. Only reads the tables: safe to call from fault and panic handlers.
+// False before `init`.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::PageTableFlags;
+
+    if PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+    let (level_4_frame, _) = Cr3::read();
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut table_address = level_4_frame.start_address();
+    for (level, &index) in indexes.iter().enumerate() {
+        let table = unsafe { &*phys_to_virt(table_address).as_ptr::<PageTable>() };
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        if level > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE) { // 1 GiB or 2 MiB page
+            return true;
+        }
+        table_address = entry.addr();
+    }
+    true
+}
+
+// Page table and frame allocator of the kernel once the heap is set up.
+// The heap maps new pages with them when it grows, so they are shared instead of owned by `kernel_main`.
+static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
+
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BootInfoFrameAllocator,
+}
+
+// Hand the mapper and frame allocator over to the rest of the kernel.
+pub fn init_kernel_memory(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    interrupts::without_interrupts(|| {
+        *KERNEL_MEMORY.lock() = Some(KernelMemory { mapper, frame_allocator });
+    });
+}
+
+// Run `f` with the kernel page table and frame allocator.
+// `f` should not allocate on the heap: the heap can't grow while the lock is held.
+pub fn with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        f(KERNEL_MEMORY.lock().as_mut().expect("memory::init_kernel_memory was not called"))
+    })
+}
+
+// Same as `with_kernel_memory` but None instead of waiting, if the lock is held or nothing was registered yet.
+// Interrupts must be disabled.
+pub(crate) fn try_with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    KERNEL_MEMORY.try_lock()?.as_mut().map(f)
+}
+
+// Device registers are mapped from here on, one after the other. An unmapped range is only reused when it
+// was the last one mapped, e.g. by a driver giving up on its device.
+pub const MMIO_START: u64 = 0x_5555_0000_0000;
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+// Map the device memory [phys, phys + size) uncached and return its virtual address.
+// Needs `init_kernel_memory`.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    use x86_64::structures::paging::PageTableFlags;
+
+    let offset = phys.as_u64() % Size4KiB::SIZE;
+    let pages = mmio_pages(phys.as_u64(), size);
+    let start = NEXT_MMIO.fetch_add(pages * Size4KiB::SIZE, Ordering::Relaxed);
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    let mapped = with_kernel_memory(|memory| {
+        for index in 0..pages {
+            let page = Page::containing_address(VirtAddr::new(start + index * Size4KiB::SIZE));
+            match unsafe { memory.mapper.map_to(page, first_frame + index, flags, &mut memory.frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    });
+    if let Err(error) = mapped {
+        unsafe { unmap_mmio(VirtAddr::new(start), pages * Size4KiB::SIZE) }; // gives the range back too
+        return Err(error);
+    }
+    Ok(VirtAddr::new(start + offset))
+}
+
+// Unmap what `map_mmio` returned for `size` bytes. The frames are device memory, they are not freed.
+// Unsafe because nothing may still use the registers.
+pub unsafe fn unmap_mmio(addr: VirtAddr, size: u64) {
+    let start = addr.align_down(Size4KiB::SIZE).as_u64();
+    let pages = mmio_pages(addr.as_u64(), size);
+    with_kernel_memory(|memory| {
+        for index in 0..pages {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(start + index * Size4KiB::SIZE));
+            if let Ok((_, flush)) = memory.mapper.unmap(page) { // a failed `map_mmio` stopped halfway
+                flush.flush();
+            }
+        }
+    });
+    let end = start + pages * Size4KiB::SIZE;
+    let _ = NEXT_MMIO.compare_exchange(end, start, Ordering::Relaxed, Ordering::Relaxed); // fails if mapped since
+}
+
+// Pages covering [addr, addr + size), addr may be physical or virtual: they have the same page offset.
+fn mmio_pages(addr: u64, size: u64) -> u64 {
+    (addr % Size4KiB::SIZE + size.max(1) + Size4KiB::SIZE - 1) / Size4KiB::SIZE
+}
+
+// Zeroed, physically contiguous memory for the DMA of a device, reached through `phys_to_virt`, given back
+// with `free_dma`. Needs `init_kernel_memory`.
+pub fn allocate_dma(size: u64) -> Option<PhysAddr> {
+    let frames = (size.max(1) + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+    let start = with_kernel_memory(|memory| memory.frame_allocator.allocate_contiguous(frames))?.start_address();
+    unsafe { core::ptr::write_bytes(phys_to_virt(start).as_mut_ptr::<u8>(), 0, (frames * Size4KiB::SIZE) as usize) };
+    Some(start)
+}
+
+// Give back what `allocate_dma(size)` returned.
+// Unsafe because the device and the kernel must not use the memory anymore.
+pub unsafe fn free_dma(start: PhysAddr, size: u64) {
+    let frames = (size.max(1) + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(start);
+    with_kernel_memory(|memory| {
+        for index in 0..frames {
+            memory.frame_allocator.deallocate_frame(first_frame + index);
+        }
+    });
+}
+
+// Physical address `addr` is mapped to in the kernel page table, e.g. to give a heap buffer to a device.
+// Needs `init_kernel_memory`.
+pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    use x86_64::structures::paging::Translate;
+
+    with_kernel_memory(|memory| memory.mapper.translate_addr(addr))
+}
 
 // Return a mutable reference to the active lvl4 table.
 // Fn can only be called once to avoid aliasing mut refs.
@@ -32,32 +186,162 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+/*
+
+Frame allocator
+---------------
+
+Frames that were never allocated are taken in order from the usable regions of the memory map,
+frames given back are kept in a free list linked through their first 8 bytes.
+Both are O(1): no walk over the memory map on each allocation like before.
+The free list is accessed through the physical memory mapping, `init` must have been called.
+*/
+
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap, // reference to the memory map
-    next: usize, // keeps track of the next free frame
+    region: usize, // index of the region we are taking new frames from
+    next: u64, // start address of the next never allocated frame in that region
+    free_list: Option<PhysFrame>, // head of the frames given back
+    total_frames: u64, // usable frames in the memory map
+    free_frames: u64,
+    reserved_frames: u64, // frames of every other region type (kernel, bootloader, ACPI...)
 }
 
 impl BootInfoFrameAllocator {
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self { // Caller must guarantee that memory map is valid
-        BootInfoFrameAllocator {
+        let frames = |r: &MemoryRegion| (r.range.end_addr() - r.range.start_addr()) / Size4KiB::SIZE;
+        let total_frames = memory_map.iter().filter(|r| is_usable(r)).map(frames).sum();
+        let reserved_frames = memory_map.iter().filter(|r| !is_usable(r)).map(frames).sum();
+        let mut allocator = BootInfoFrameAllocator {
             memory_map,
+            region: 0,
             next: 0,
+            free_list: None,
+            total_frames,
+            free_frames: total_frames,
+            reserved_frames,
+        };
+        allocator.seek_region(0);
+        allocator
+    }
+
+    pub fn total_frames(&self) -> u64 {
+        self.total_frames
+    }
+
+    pub fn free_frames(&self) -> u64 {
+        self.free_frames
+    }
+
+    pub fn used_frames(&self) -> u64 {
+        self.total_frames - self.free_frames
+    }
+
+    pub fn reserved_frames(&self) -> u64 {
+        self.reserved_frames
+    }
+
+    // Move to the first usable region starting at `index`.
+    fn seek_region(&mut self, index: usize) {
+        self.region = index;
+        while let Some(region) = self.memory_map.get(self.region) {
+            if is_usable(region) {
+                self.next = region.range.start_addr();
+                return;
+            }
+            self.region += 1;
         }
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> { // Iterator over usables frames in memory map
-        let regions = self.memory_map.iter(); // get usable regions from memory map as an iterator
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable); // skip any reserved or unavailable region
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr()); // transform as an iterator of address ranges
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096)); // get start address of each frame and using step of 4096
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr))) // transform to iterator of frames
+    fn allocate_new_frame(&mut self) -> Option<PhysFrame> {
+        loop {
+            let region = self.memory_map.get(self.region)?; // None when every region is used up
+            if self.next < region.range.end_addr() {
+                let frame = PhysFrame::containing_address(PhysAddr::new(self.next));
+                self.next += Size4KiB::SIZE;
+                return Some(frame);
+            }
+            self.seek_region(self.region + 1);
+        }
+    }
+
+    // `count` physically contiguous frames, taken from the never allocated ones. The frames left at the end
+    // of a region too small for them go to the free list.
+    pub fn allocate_contiguous(&mut self, count: u64) -> Option<PhysFrame> {
+        loop {
+            let end = self.memory_map.get(self.region)?.range.end_addr();
+            if end - self.next >= count * Size4KiB::SIZE {
+                let start = PhysFrame::containing_address(PhysAddr::new(self.next));
+                self.next += count * Size4KiB::SIZE;
+                self.free_frames -= count;
+                return Some(start);
+            }
+            while self.next < end { // still free, so not counted again
+                let frame = PhysFrame::containing_address(PhysAddr::new(self.next));
+                unsafe { Self::free_list_next(frame).write(self.free_list) };
+                self.free_list = Some(frame);
+                self.next += Size4KiB::SIZE;
+            }
+            self.seek_region(self.region + 1);
+        }
+    }
+
+    fn free_list_next(frame: PhysFrame) -> *mut Option<PhysFrame> {
+        phys_to_virt(frame.start_address()).as_mut_ptr()
+    }
+
+    fn contains(&self, frame: PhysFrame) -> bool {
+        let addr = frame.start_address().as_u64();
+        self.memory_map.iter()
+            .filter(|r| is_usable(r))
+            .any(|r| r.range.start_addr() <= addr && addr < r.range.end_addr())
     }
 }
 
+fn is_usable(region: &MemoryRegion) -> bool {
+    region.region_type == MemoryRegionType::Usable
+}
+
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next); // get next frame from iterator
-        self.next += 1; // increment next
-        frame // return next frame
+        let frame = match self.free_list {
+            Some(frame) => { // reuse a freed frame first
+                self.free_list = unsafe { Self::free_list_next(frame).read() };
+                Some(frame)
+            }
+            None => self.allocate_new_frame(),
+        };
+        if frame.is_some() {
+            self.free_frames -= 1;
+        }
+        frame
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    // Caller must guarantee that the frame is unused and was allocated by us.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        assert!(self.contains(frame), "freeing frame {:?} outside of usable memory", frame);
+        Self::free_list_next(frame).write(self.free_list);
+        self.free_list = Some(frame);
+        self.free_frames += 1;
+    }
+}
+
+// Unmap every page of [start, start + size) and give their frames back.
+// Unsafe because nothing may still use the pages.
+pub unsafe fn unmap_region(
+    start: VirtAddr,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> Result<(), UnmapError> {
+    let start_page = Page::<Size4KiB>::containing_address(start);
+    let end_page = Page::containing_address(start + size - 1u64); // inclusive
+    for page in Page::range_inclusive(start_page, end_page) {
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        frame_deallocator.deallocate_frame(frame);
     }
+    Ok(())
 }
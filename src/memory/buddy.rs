@@ -0,0 +1,238 @@
+/*
+
+Buddy frame allocator
+---------------------
+
+Hands out physically contiguous blocks of 2^order frames, aligned to their size, for DMA and huge pages.
+A block of order N is split into two buddies of order N - 1 when no smaller block is free,
+and freed buddies are merged back (the buddy of a block is at `address ^ block_size`).
+
+Free blocks are kept in one doubly linked list per order, linked through the blocks themselves.
+To know if the buddy of a freed block is free, one byte per frame stores `order + 1` when a free
+block starts at that frame. This table is carved from the first usable region big enough at `init`.
+Everything is accessed through the physical memory mapping, `memory::init` must have been called.
+*/
+
+use super::{phys_to_virt, is_usable};
+use bootloader::bootinfo::MemoryMap;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+pub const MAX_ORDER: usize = 10; // 4 MiB blocks
+pub const HUGE_PAGE_ORDER: usize = 9; // 2 MiB = 2^9 frames of 4 KiB
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+
+// Header written at the start of every free block.
+struct FreeBlock {
+    prev: Option<PhysAddr>,
+    next: Option<PhysAddr>,
+}
+
+pub struct BuddyFrameAllocator {
+    free_lists: [Option<PhysAddr>; MAX_ORDER + 1], // heads of the free blocks of each order
+    base: u64, // physical address of the first frame described by `block_orders`
+    frame_count: u64, // frames described by `block_orders`
+    block_orders: VirtAddr, // one byte per frame: order + 1 if a free block starts there, else 0
+    total_frames: u64,
+    free_frames: u64,
+}
+
+impl BuddyFrameAllocator {
+    // Caller must guarantee that the memory map is valid and no usable frame is in use
+    // (so it can't be used together with `BootInfoFrameAllocator`).
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        let usable = || memory_map.iter().filter(|r| is_usable(r));
+        let base = usable().map(|r| r.range.start_addr()).min().expect("no usable memory");
+        let end = usable().map(|r| r.range.end_addr()).max().unwrap();
+        let frame_count = (end - base) / FRAME_SIZE;
+        let table_size = align_up(frame_count, FRAME_SIZE);
+
+        // Steal the table from the start of a usable region
+        let table_region = usable()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= table_size)
+            .expect("no region big enough for the buddy allocator table");
+        let table_start = table_region.range.start_addr();
+        let block_orders = phys_to_virt(PhysAddr::new(table_start));
+        core::ptr::write_bytes(block_orders.as_mut_ptr::<u8>(), 0, frame_count as usize);
+
+        let mut allocator = BuddyFrameAllocator {
+            free_lists: [None; MAX_ORDER + 1],
+            base,
+            frame_count,
+            block_orders,
+            total_frames: 0,
+            free_frames: 0,
+        };
+        for region in usable() {
+            let mut start = region.range.start_addr();
+            if start == table_start {
+                start += table_size;
+            }
+            allocator.add_range(start, region.range.end_addr());
+        }
+        allocator
+    }
+
+    // Add [start, end) as the largest aligned blocks that fit.
+    unsafe fn add_range(&mut self, mut start: u64, end: u64) {
+        while start + FRAME_SIZE <= end {
+            let mut order = MAX_ORDER;
+            while start % block_size(order) != 0 || start + block_size(order) > end {
+                order -= 1;
+            }
+            self.push(PhysAddr::new(start), order);
+            self.total_frames += 1 << order;
+            self.free_frames += 1 << order;
+            start += block_size(order);
+        }
+    }
+
+    pub fn total_frames(&self) -> u64 {
+        self.total_frames
+    }
+
+    pub fn free_frames(&self) -> u64 {
+        self.free_frames
+    }
+
+    // Number of free blocks of `order`, walks the list.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        let mut count = 0;
+        let mut next = self.free_lists[order];
+        while let Some(block) = next {
+            count += 1;
+            next = unsafe { Self::header(block).read().next };
+        }
+        count
+    }
+
+    // Smallest order whose blocks hold `size` bytes.
+    pub fn order_for_size(size: u64) -> Option<usize> {
+        let frames = align_up(size.max(1), FRAME_SIZE) / FRAME_SIZE;
+        let order = frames.next_power_of_two().trailing_zeros() as usize;
+        if order <= MAX_ORDER { Some(order) } else { None }
+    }
+
+    // Allocate 2^order contiguous frames aligned to their size.
+    pub fn allocate(&mut self, order: usize) -> Option<PhysAddr> {
+        assert!(order <= MAX_ORDER, "order {} above MAX_ORDER", order);
+        let found = (order..=MAX_ORDER).find(|&o| self.free_lists[o].is_some())?;
+        let block = self.free_lists[found].unwrap();
+        unsafe {
+            self.remove(block, found);
+            for split in (order..found).rev() { // give back the upper halves
+                self.push(block + block_size(split), split);
+            }
+        }
+        self.free_frames -= 1 << order;
+        Some(block)
+    }
+
+    // Allocate a contiguous block of at least `size` bytes.
+    pub fn allocate_contiguous(&mut self, size: u64) -> Option<PhysAddr> {
+        self.allocate(Self::order_for_size(size)?)
+    }
+
+    // Caller must guarantee that the block was returned by `allocate(order)` and is unused.
+    pub unsafe fn deallocate(&mut self, block: PhysAddr, order: usize) {
+        assert!(order <= MAX_ORDER, "order {} above MAX_ORDER", order);
+        assert!(block.is_aligned(block_size(order)), "block {:?} is not aligned to order {}", block, order);
+        assert_eq!(self.order_at(block), None, "double free of block {:?}", block);
+        self.free_frames += 1 << order;
+
+        let (mut block, mut order) = (block.as_u64(), order);
+        while order < MAX_ORDER {
+            let buddy = PhysAddr::new(block ^ block_size(order));
+            if self.order_at(buddy) != Some(order) {
+                break; // buddy in use, split further or outside of managed memory
+            }
+            self.remove(buddy, order);
+            block = block.min(buddy.as_u64());
+            order += 1;
+        }
+        self.push(PhysAddr::new(block), order);
+    }
+
+    fn header(block: PhysAddr) -> *mut FreeBlock {
+        phys_to_virt(block).as_mut_ptr()
+    }
+
+    fn order_slot(&self, block: PhysAddr) -> Option<*mut u8> {
+        let addr = block.as_u64();
+        if addr < self.base || (addr - self.base) / FRAME_SIZE >= self.frame_count {
+            return None;
+        }
+        let index = (addr - self.base) / FRAME_SIZE;
+        Some(unsafe { self.block_orders.as_mut_ptr::<u8>().add(index as usize) })
+    }
+
+    // Order of the free block starting at `block`, None if no free block starts there.
+    fn order_at(&self, block: PhysAddr) -> Option<usize> {
+        match self.order_slot(block).map(|slot| unsafe { slot.read() }) {
+            Some(0) | None => None,
+            Some(order) => Some(usize::from(order) - 1),
+        }
+    }
+
+    unsafe fn set_order(&mut self, block: PhysAddr, order: Option<usize>) {
+        let slot = self.order_slot(block).expect("block outside of managed memory");
+        slot.write(order.map_or(0, |order| order as u8 + 1));
+    }
+
+    unsafe fn push(&mut self, block: PhysAddr, order: usize) {
+        let next = self.free_lists[order];
+        Self::header(block).write(FreeBlock { prev: None, next });
+        if let Some(next) = next {
+            (*Self::header(next)).prev = Some(block);
+        }
+        self.free_lists[order] = Some(block);
+        self.set_order(block, Some(order));
+    }
+
+    unsafe fn remove(&mut self, block: PhysAddr, order: usize) {
+        let FreeBlock { prev, next } = Self::header(block).read();
+        match prev {
+            Some(prev) => (*Self::header(prev)).next = next,
+            None => self.free_lists[order] = next,
+        }
+        if let Some(next) = next {
+            (*Self::header(next)).prev = prev;
+        }
+        self.set_order(block, None);
+    }
+}
+
+const fn block_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+const fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate(0).map(PhysFrame::containing_address)
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate(HUGE_PAGE_ORDER).map(PhysFrame::containing_address)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.deallocate(frame.start_address(), 0);
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate(frame.start_address(), HUGE_PAGE_ORDER);
+    }
+}
@@ -0,0 +1,471 @@
+/*
+
+PCI
+---
+
+`init` scans the configuration space of every bus reachable from the host bridges: each device of a bus,
+each function of a multi-function device, and the secondary bus behind every PCI-to-PCI bridge.
+Functions are decoded (ids, class, BARs with their sizes, interrupt line) and kept in a list.
+
+Configuration space is memory mapped (ECAM) when ACPI has an MCFG table, see `config`.
+Capabilities are walked with `PciDevice::capabilities`, MSI and MSI-X are set up by `msi`.
+
+Drivers register with `register_driver` and a list of vendor/device ids or class codes. A driver is probed
+against every matching function not bound yet, found before or after it registered; the first driver
+whose probe succeeds owns the function.
+*/
+
+pub mod config;
+pub mod ecam;
+pub mod msi;
+
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use crate::serial_println;
+
+// Configuration space registers of every header type
+const VENDOR_ID: u16 = 0x00;
+const COMMAND: u16 = 0x04;
+const STATUS: u16 = 0x06;
+const CLASS_REVISION: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0e;
+const BAR0: u16 = 0x10;
+const BRIDGE_BUSES: u16 = 0x18; // primary, secondary and subordinate bus numbers of a PCI-to-PCI bridge
+const CAPABILITIES_POINTER: u16 = 0x34;
+const CARDBUS_CAPABILITIES_POINTER: u16 = 0x14;
+const INTERRUPT: u16 = 0x3c; // line and pin
+const EXTENDED_CAPABILITIES: u16 = 0x100; // PCI Express, past the legacy configuration space
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+const MAX_CAPABILITIES: usize = 48; // (256 - 64) / 4, stops a looping list
+
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const MULTI_FUNCTION: u8 = 1 << 7;
+const NO_DEVICE: u16 = 0xffff;
+
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR: u8 = 0x09;
+pub const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_MSI_X: u8 = 0x11;
+
+pub const CLASS_BRIDGE: u8 = 0x06;
+pub const SUBCLASS_HOST_BRIDGE: u8 = 0x00;
+pub const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8, // 0..32
+    pub function: u8, // 0..8
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory { address: u64, size: u64, prefetchable: bool, is_64bit: bool },
+    Io { port: u16, size: u16 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderKind {
+    Endpoint,
+    PciBridge { secondary_bus: u8, subordinate_bus: u8 },
+    CardBusBridge,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub kind: HeaderKind,
+    pub bars: [Option<Bar>; 6], // a 64-bit BAR takes two slots, the second one is None
+    pub interrupt_line: u8, // ISA IRQ set up by the firmware, 0xff if none
+    pub interrupt_pin: u8, // 1..=4 for INTA#..INTD#, 0 if none
+}
+
+impl PciDevice {
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        config::read_u32(self.address, offset)
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        config::write_u32(self.address, offset, value)
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        (self.read_u32(offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        (self.read_u32(offset & !3) >> ((offset & 3) * 8)) as u8
+    }
+
+    // Read-modify-write of the dword holding the register.
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        let dword = self.read_u32(offset & !3) & !(0xffff << shift);
+        self.write_u32(offset & !3, dword | u32::from(value) << shift);
+    }
+
+    pub fn write_u8(&self, offset: u16, value: u8) {
+        let shift = (offset & 3) * 8;
+        let dword = self.read_u32(offset & !3) & !(0xff << shift);
+        self.write_u32(offset & !3, dword | u32::from(value) << shift);
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read_u16(COMMAND)
+    }
+
+    // Set bits of the command register, e.g. COMMAND_MEMORY | COMMAND_BUS_MASTER before using DMA.
+    pub fn enable(&self, bits: u16) {
+        self.set_command(self.command() | bits);
+    }
+
+    pub fn disable(&self, bits: u16) {
+        self.set_command(self.command() & !bits);
+    }
+
+    // The status register shares the dword, its bits are cleared by writing 1: write 0 to them.
+    fn set_command(&self, command: u16) {
+        self.write_u32(COMMAND, u32::from(command));
+    }
+}
+
+// Entry of the capability list: its id and the offset of its registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    pub offset: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    pub offset: u16,
+}
+
+impl PciDevice {
+    pub fn capabilities(&self) -> impl Iterator<Item = Capability> + '_ {
+        let mut next = if self.read_u16(STATUS) & STATUS_CAPABILITIES == 0 {
+            0
+        } else if self.kind == HeaderKind::CardBusBridge {
+            self.read_u8(CARDBUS_CAPABILITIES_POINTER)
+        } else {
+            self.read_u8(CAPABILITIES_POINTER)
+        };
+        core::iter::from_fn(move || {
+            let offset = u16::from(next & !0b11); // the low bits are reserved
+            if offset < 0x40 {
+                return None; // 0 ends the list, lower offsets belong to the header
+            }
+            let header = self.read_u16(offset);
+            next = (header >> 8) as u8;
+            Some(Capability { id: header as u8, offset })
+        })
+        .take(MAX_CAPABILITIES)
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities().find(|capability| capability.id == id)
+    }
+
+    // Only reachable through ECAM.
+    pub fn extended_capabilities(&self) -> impl Iterator<Item = ExtendedCapability> + '_ {
+        let mut next = match config::config_size(self.address) > EXTENDED_CAPABILITIES {
+            true => EXTENDED_CAPABILITIES,
+            false => 0,
+        };
+        core::iter::from_fn(move || {
+            if next < EXTENDED_CAPABILITIES {
+                return None;
+            }
+            let header = self.read_u32(next);
+            if header == 0 || header == u32::MAX {
+                return None;
+            }
+            let capability = ExtendedCapability { id: header as u16, version: (header >> 16) as u8 & 0xf, offset: next };
+            next = (header >> 20) as u16 & !0b11;
+            Some(capability)
+        })
+        .take((4096 - 256) / 4)
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:04x}:{:04x} {} ({:02x}.{:02x}.{:02x})", self.address, self.vendor_id, self.device_id,
+            class_name(self.class, self.subclass), self.class, self.subclass, self.prog_if)
+    }
+}
+
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVM controller",
+        (0x01, _) => "storage controller",
+        (0x02, _) => "network controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia controller",
+        (0x05, _) => "memory controller",
+        (CLASS_BRIDGE, SUBCLASS_HOST_BRIDGE) => "host bridge",
+        (CLASS_BRIDGE, 0x01) => "ISA bridge",
+        (CLASS_BRIDGE, SUBCLASS_PCI_BRIDGE) => "PCI bridge",
+        (CLASS_BRIDGE, _) => "bridge",
+        (0x07, _) => "communication controller",
+        (0x08, _) => "system peripheral",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus controller",
+        (0x0c, _) => "serial bus controller",
+        _ => "unknown device",
+    }
+}
+
+// Devices a driver handles.
+#[derive(Debug, Clone, Copy)]
+pub enum DeviceMatch {
+    Id { vendor_id: u16, device_id: u16 },
+    Class { class: u8, subclass: u8, prog_if: Option<u8> },
+}
+
+impl DeviceMatch {
+    fn matches(&self, device: &PciDevice) -> bool {
+        match *self {
+            DeviceMatch::Id { vendor_id, device_id } => device.vendor_id == vendor_id && device.device_id == device_id,
+            DeviceMatch::Class { class, subclass, prog_if } => {
+                device.class == class && device.subclass == subclass && prog_if.map_or(true, |prog_if| device.prog_if == prog_if)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProbeError {
+    NotSupported, // another driver may take the device
+    Failed(&'static str),
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [DeviceMatch],
+    pub probe: fn(&PciDevice) -> Result<(), ProbeError>,
+}
+
+struct Function {
+    device: PciDevice,
+    driver: Option<&'static str>,
+}
+
+struct Registry {
+    functions: Vec<Function>,
+    drivers: Vec<&'static PciDriver>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry { functions: Vec::new(), drivers: Vec::new() });
+
+// Enumerate every function and probe the drivers registered so far. Called once, needs the heap,
+// and `acpi::init` before it for ECAM.
+pub fn init() {
+    ecam::init(); // when ACPI has an MCFG table
+    let mut functions = Vec::new();
+    let host_bridge = PciAddress { bus: 0, device: 0, function: 0 };
+    if config::read_u32(host_bridge, HEADER_TYPE & !3) >> 16 & u32::from(MULTI_FUNCTION) == 0 {
+        scan_bus(0, &mut functions);
+    } else {
+        // several host bridges, function n handles bus n
+        for function in 0..8 {
+            let address = PciAddress { function, ..host_bridge };
+            if vendor_id(address) != NO_DEVICE {
+                scan_bus(function, &mut functions);
+            }
+        }
+    }
+    let drivers = {
+        let mut registry = REGISTRY.lock();
+        registry.functions = functions.into_iter().map(|device| Function { device, driver: None }).collect();
+        registry.drivers.clone()
+    };
+    probe(&drivers);
+}
+
+// Every function found by `init`.
+pub fn devices() -> Vec<PciDevice> {
+    REGISTRY.lock().functions.iter().map(|function| function.device).collect()
+}
+
+pub fn find(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    let registry = REGISTRY.lock();
+    let function = registry.functions.iter().find(|function| {
+        function.device.vendor_id == vendor_id && function.device.device_id == device_id
+    });
+    function.map(|function| function.device)
+}
+
+// Name of the driver bound to the function.
+pub fn driver_of(address: PciAddress) -> Option<&'static str> {
+    REGISTRY.lock().functions.iter().find(|function| function.device.address == address)?.driver
+}
+
+// Add a driver and probe it against the unbound matching functions found so far. The other drivers are
+// not probed again: they already declined or failed on these functions.
+pub fn register_driver(driver: &'static PciDriver) {
+    REGISTRY.lock().drivers.push(driver);
+    probe(&[driver]);
+}
+
+// Probe `drivers` in order against every unbound function, until one binds it.
+// The registry is not locked while a probe runs: it may use this module.
+fn probe(drivers: &[&'static PciDriver]) {
+    let mut index = 0;
+    loop {
+        let (device, drivers) = {
+            let registry = REGISTRY.lock();
+            let function = match registry.functions.get(index) {
+                Some(function) => function,
+                None => return,
+            };
+            index += 1;
+            if function.driver.is_some() {
+                continue;
+            }
+            let drivers: Vec<&'static PciDriver> = drivers.iter().copied()
+                .filter(|driver| driver.matches.iter().any(|pattern| pattern.matches(&function.device)))
+                .collect();
+            (function.device, drivers)
+        };
+        for driver in drivers {
+            match (driver.probe)(&device) {
+                Ok(()) => {
+                    let mut registry = REGISTRY.lock();
+                    if let Some(function) = registry.functions.iter_mut().find(|function| function.device.address == device.address) {
+                        function.driver = Some(driver.name);
+                    }
+                    break;
+                }
+                Err(ProbeError::NotSupported) => {}
+                Err(ProbeError::Failed(reason)) => serial_println!("pci: {} failed on {}: {}", driver.name, device, reason),
+            }
+        }
+    }
+}
+
+fn vendor_id(address: PciAddress) -> u16 {
+    config::read_u32(address, VENDOR_ID) as u16
+}
+
+fn scan_bus(bus: u8, functions: &mut Vec<PciDevice>) {
+    for device in 0..32 {
+        let address = PciAddress { bus, device, function: 0 };
+        if vendor_id(address) == NO_DEVICE {
+            continue;
+        }
+        let header_type = (config::read_u32(address, HEADER_TYPE & !3) >> 16) as u8;
+        let function_count = if header_type & MULTI_FUNCTION != 0 { 8 } else { 1 };
+        for function in 0..function_count {
+            let address = PciAddress { function, ..address };
+            if vendor_id(address) != NO_DEVICE {
+                scan_function(address, functions);
+            }
+        }
+    }
+}
+
+fn scan_function(address: PciAddress, functions: &mut Vec<PciDevice>) {
+    let ids = config::read_u32(address, VENDOR_ID);
+    let class = config::read_u32(address, CLASS_REVISION);
+    let header_type = (config::read_u32(address, HEADER_TYPE & !3) >> 16) as u8 & !MULTI_FUNCTION;
+    let interrupt = config::read_u32(address, INTERRUPT);
+    let buses = config::read_u32(address, BRIDGE_BUSES);
+    let kind = match header_type {
+        0x01 => HeaderKind::PciBridge { secondary_bus: (buses >> 8) as u8, subordinate_bus: (buses >> 16) as u8 },
+        0x02 => HeaderKind::CardBusBridge,
+        _ => HeaderKind::Endpoint,
+    };
+    let bar_count = match kind {
+        HeaderKind::Endpoint => 6,
+        HeaderKind::PciBridge { .. } => 2,
+        HeaderKind::CardBusBridge => 0, // its registers are not BARs
+    };
+    let mut device = PciDevice {
+        address,
+        vendor_id: ids as u16,
+        device_id: (ids >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        revision: class as u8,
+        kind,
+        bars: [None; 6],
+        interrupt_line: interrupt as u8,
+        interrupt_pin: (interrupt >> 8) as u8,
+    };
+    read_bars(&mut device, bar_count);
+    functions.push(device);
+
+    if let HeaderKind::PciBridge { secondary_bus, .. } = kind {
+        if secondary_bus > address.bus { // a bridge the firmware did not configure has bus 0
+            scan_bus(secondary_bus, functions);
+        }
+    }
+}
+
+// The size of a BAR is found by writing all ones to it: the address bits that stay 0 are the size.
+// Decoding is disabled meanwhile so the device never answers at the bogus address.
+fn read_bars(device: &mut PciDevice, count: usize) {
+    let command = device.command();
+    device.disable(COMMAND_IO | COMMAND_MEMORY);
+    let mut index = 0;
+    while index < count {
+        let offset = BAR0 + 4 * index as u16;
+        let original = device.read_u32(offset);
+        device.write_u32(offset, u32::MAX);
+        let mask = device.read_u32(offset);
+        device.write_u32(offset, original);
+
+        if original & 1 == 1 {
+            let mask = (mask & !0b11) as u16; // ports are 16 bits, the upper bits may read as 0
+            if mask != 0 {
+                device.bars[index] = Some(Bar::Io { port: (original & !0b11) as u16, size: (!mask).wrapping_add(1) });
+            }
+            index += 1;
+            continue;
+        }
+        let is_64bit = (original >> 1) & 0b11 == 0b10;
+        let mut address = u64::from(original & !0xf);
+        let mut mask = u64::from(mask & !0xf);
+        if is_64bit && index + 1 < count {
+            let high_offset = offset + 4;
+            let high = device.read_u32(high_offset);
+            device.write_u32(high_offset, u32::MAX);
+            let high_mask = device.read_u32(high_offset);
+            device.write_u32(high_offset, high);
+            address |= u64::from(high) << 32;
+            mask |= u64::from(high_mask) << 32;
+        } else if mask != 0 {
+            mask |= 0xffff_ffff_0000_0000; // the size of a 32-bit BAR fits in 32 bits
+        }
+        if mask != 0 { // 0 when the BAR is not implemented
+            let size = (!mask).wrapping_add(1);
+            device.bars[index] = Some(Bar::Memory { address, size, prefetchable: original & 0b1000 != 0, is_64bit });
+        }
+        index += if is_64bit { 2 } else { 1 };
+    }
+    device.set_command(command);
+}
@@ -0,0 +1,63 @@
+// Configuration space access: memory mapped (ECAM) when the ACPI MCFG table describes the bus, otherwise through
+// the address port 0xCF8 and the data port 0xCFC (configuration mechanism #1), which only reaches the first
+// 256 bytes of a function: reads past them return all ones.
+
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+use super::{ecam, PciAddress};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+const ENABLE: u32 = 1 << 31;
+
+pub const LEGACY_CONFIG_SIZE: u16 = 256;
+
+// Taken with interrupts disabled: an access is a write to the address port then one to the data port.
+static PORTS: Mutex<()> = Mutex::new(());
+
+fn select(address: PciAddress, offset: u16) {
+    let value = ENABLE | u32::from(address.bus) << 16 | u32::from(address.device) << 11
+        | u32::from(address.function) << 8 | u32::from(offset & 0xfc);
+    unsafe { Port::<u32>::new(CONFIG_ADDRESS).write(value) };
+}
+
+// Bytes of configuration space reachable for the function.
+pub fn config_size(address: PciAddress) -> u16 {
+    match ecam::register(address, 0) {
+        Some(_) => ecam::CONFIG_SIZE,
+        None => LEGACY_CONFIG_SIZE,
+    }
+}
+
+pub fn read_u32(address: PciAddress, offset: u16) -> u32 {
+    if offset < ecam::CONFIG_SIZE {
+        if let Some(register) = ecam::register(address, offset) {
+            return unsafe { register.as_ptr::<u32>().read_volatile() };
+        }
+    }
+    if offset >= LEGACY_CONFIG_SIZE {
+        return u32::MAX;
+    }
+    interrupts::without_interrupts(|| {
+        let _ports = PORTS.lock();
+        select(address, offset);
+        unsafe { Port::<u32>::new(CONFIG_DATA).read() }
+    })
+}
+
+pub fn write_u32(address: PciAddress, offset: u16, value: u32) {
+    if offset < ecam::CONFIG_SIZE {
+        if let Some(register) = ecam::register(address, offset) {
+            unsafe { register.as_mut_ptr::<u32>().write_volatile(value) };
+            return;
+        }
+    }
+    if offset >= LEGACY_CONFIG_SIZE {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let _ports = PORTS.lock();
+        select(address, offset);
+        unsafe { Port::<u32>::new(CONFIG_DATA).write(value) };
+    })
+}
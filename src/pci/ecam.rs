@@ -0,0 +1,54 @@
+// PCI Express enhanced configuration access: the 4 KiB configuration space of every function is memory
+// mapped, at base + (bus << 20 | device << 15 | function << 12). The region of segment 0 comes from the
+// ACPI MCFG table and is mapped whole by `init`, so a configuration access never maps memory.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+use super::PciAddress;
+use crate::acpi::mcfg;
+use crate::memory;
+
+pub const CONFIG_SIZE: u16 = 4096;
+const BUS_SIZE: u64 = 1 << 20;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static START_BUS: AtomicU64 = AtomicU64::new(0);
+static END_BUS: AtomicU64 = AtomicU64::new(0);
+static START_BUS_BASE: AtomicU64 = AtomicU64::new(0); // virtual address of the first bus of the MCFG
+
+// Use ECAM for the buses the MCFG describes. Needs `acpi::init` and `memory::init_kernel_memory`.
+// False without MCFG or when the region can't be mapped: the ports are used.
+pub fn init() -> bool {
+    let entry = match mcfg::entries().find(|entry| entry.segment == 0 && entry.start_bus <= entry.end_bus) {
+        Some(entry) => entry,
+        None => return false,
+    };
+    let (start_bus, end_bus) = (u64::from(entry.start_bus), u64::from(entry.end_bus));
+    // the base address of the entry is the one of bus 0, even when it starts later
+    let physical = PhysAddr::new(entry.base_address.as_u64() + start_bus * BUS_SIZE);
+    let base = match memory::map_mmio(physical, (end_bus - start_bus + 1) * BUS_SIZE) {
+        Ok(base) => base,
+        Err(_) => return false,
+    };
+    START_BUS_BASE.store(base.as_u64(), Ordering::Relaxed);
+    START_BUS.store(start_bus, Ordering::Relaxed);
+    END_BUS.store(end_bus, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Release);
+    true
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+// Virtual address of the configuration register, None if ECAM does not cover the bus.
+pub(super) fn register(address: PciAddress, offset: u16) -> Option<VirtAddr> {
+    let bus = u64::from(address.bus);
+    let start_bus = START_BUS.load(Ordering::Relaxed);
+    if !is_enabled() || bus < start_bus || bus > END_BUS.load(Ordering::Relaxed) {
+        return None;
+    }
+    let function = u64::from(address.device) << 15 | u64::from(address.function) << 12;
+    let base = START_BUS_BASE.load(Ordering::Relaxed) + (bus - start_bus) * BUS_SIZE;
+    Some(VirtAddr::new(base + function + u64::from(offset & !3)))
+}
@@ -0,0 +1,176 @@
+/*
+
+MSI and MSI-X
+-------------
+
+Message signaled interrupts are memory writes of the device to the local APIC: the address selects the
+processor and the data the vector, one of those allocated with `interrupts::vectors::allocate_vector`.
+A device with MSI sends one message (multiple messages are not used), a device with MSI-X has a table in
+one of its BARs with an address, a data and a mask for each of its messages.
+Enabling either disables the legacy INTx interrupt of the function.
+*/
+
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use super::{Bar, PciDevice, CAPABILITY_MSI, CAPABILITY_MSI_X, COMMAND_INTX_DISABLE, COMMAND_MEMORY};
+use crate::interrupts::{apic, vectors::{self, VectorError, VectorHandler}};
+use crate::memory;
+
+const MESSAGE_ADDRESS: u64 = 0xfee0_0000; // local APIC, destination id in bits 12..20
+
+// MSI registers, offsets from the capability
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS: u16 = 0x04;
+const MSI_ADDRESS_HIGH: u16 = 0x08; // 64-bit capable only
+const MSI_DATA_32: u16 = 0x08;
+const MSI_DATA_64: u16 = 0x0c;
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_MULTIPLE_ENABLE: u16 = 0b111 << 4;
+const MSI_64BIT: u16 = 1 << 7;
+
+// MSI-X registers
+const MSI_X_CONTROL: u16 = 0x02;
+const MSI_X_TABLE: u16 = 0x04; // offset in the BAR, BAR index in the low 3 bits
+const MSI_X_TABLE_SIZE: u16 = 0x7ff; // minus one
+const MSI_X_FUNCTION_MASK: u16 = 1 << 14;
+const MSI_X_ENABLE: u16 = 1 << 15;
+const MSI_X_ENTRY_SIZE: u64 = 16;
+const MSI_X_ENTRY_MASKED: u32 = 1 << 0; // vector control
+
+#[derive(Debug)]
+pub enum MsiError {
+    NoCapability,
+    Vector(VectorError),
+    BadTableBar, // the MSI-X table is not in a memory BAR
+    Mapping(MapToError<Size4KiB>),
+    InvalidEntry(u16),
+}
+
+impl From<VectorError> for MsiError {
+    fn from(error: VectorError) -> Self {
+        MsiError::Vector(error)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for MsiError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        MsiError::Mapping(error)
+    }
+}
+
+// Fixed delivery of `vector` to this processor, edge triggered.
+fn message(vector: u8) -> (u64, u32) {
+    (MESSAGE_ADDRESS | u64::from(apic::local_apic_id()) << 12, u32::from(vector))
+}
+
+// Deliver the MSI of `device` to `handler(argument)` on a newly allocated vector, returned.
+pub fn enable_msi(device: &PciDevice, handler: VectorHandler, argument: usize) -> Result<u8, MsiError> {
+    let capability = device.find_capability(CAPABILITY_MSI).ok_or(MsiError::NoCapability)?.offset;
+    let vector = vectors::allocate_vector(handler, argument)?;
+    let (address, data) = message(vector);
+    let control = device.read_u16(capability + MSI_CONTROL);
+    device.write_u32(capability + MSI_ADDRESS, address as u32);
+    if control & MSI_64BIT != 0 {
+        device.write_u32(capability + MSI_ADDRESS_HIGH, (address >> 32) as u32);
+        device.write_u16(capability + MSI_DATA_64, data as u16);
+    } else {
+        device.write_u16(capability + MSI_DATA_32, data as u16);
+    }
+    device.enable(COMMAND_INTX_DISABLE);
+    device.write_u16(capability + MSI_CONTROL, (control & !MSI_MULTIPLE_ENABLE) | MSI_ENABLE); // one message
+    Ok(vector)
+}
+
+// The vector given by `enable_msi` is freed.
+pub fn disable_msi(device: &PciDevice, vector: u8) {
+    if let Some(capability) = device.find_capability(CAPABILITY_MSI) {
+        let control = device.read_u16(capability.offset + MSI_CONTROL);
+        device.write_u16(capability.offset + MSI_CONTROL, control & !MSI_ENABLE);
+    }
+    vectors::free_vector(vector);
+}
+
+// Whether MSI-X is on for the function, e.g. some registers of virtio legacy devices move with it.
+pub fn is_msi_x_enabled(device: &PciDevice) -> bool {
+    device.find_capability(CAPABILITY_MSI_X)
+        .map_or(false, |capability| device.read_u16(capability.offset + MSI_X_CONTROL) & MSI_X_ENABLE != 0)
+}
+
+// MSI-X table of a function, every entry masked until `set_vector`.
+pub struct MsiX {
+    device: PciDevice,
+    capability: u16,
+    table: VirtAddr,
+    size: u16,
+}
+
+impl MsiX {
+    // Map the table and enable MSI-X.
+    pub fn enable(device: &PciDevice) -> Result<MsiX, MsiError> {
+        let capability = device.find_capability(CAPABILITY_MSI_X).ok_or(MsiError::NoCapability)?.offset;
+        let control = device.read_u16(capability + MSI_X_CONTROL);
+        let size = (control & MSI_X_TABLE_SIZE) + 1;
+        let table = device.read_u32(capability + MSI_X_TABLE);
+        let bar_address = match device.bars.get((table & 0b111) as usize).copied().flatten() {
+            Some(Bar::Memory { address, .. }) => address,
+            _ => return Err(MsiError::BadTableBar),
+        };
+        let physical = PhysAddr::new(bar_address + u64::from(table & !0b111));
+        let msi_x = MsiX {
+            device: *device,
+            capability,
+            table: memory::map_mmio(physical, u64::from(size) * MSI_X_ENTRY_SIZE)?,
+            size,
+        };
+        device.enable(COMMAND_MEMORY | COMMAND_INTX_DISABLE);
+        // entries are masked while the table is set up, with the whole function masked meanwhile
+        device.write_u16(capability + MSI_X_CONTROL, control | MSI_X_ENABLE | MSI_X_FUNCTION_MASK);
+        for entry in 0..size {
+            unsafe { msi_x.write(entry, 12, MSI_X_ENTRY_MASKED) };
+        }
+        device.write_u16(capability + MSI_X_CONTROL, (control | MSI_X_ENABLE) & !MSI_X_FUNCTION_MASK);
+        Ok(msi_x)
+    }
+
+    // Number of entries of the table.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    // Deliver the message of `entry` to `handler(argument)` on a newly allocated vector, returned.
+    pub fn set_vector(&self, entry: u16, handler: VectorHandler, argument: usize) -> Result<u8, MsiError> {
+        if entry >= self.size {
+            return Err(MsiError::InvalidEntry(entry));
+        }
+        let vector = vectors::allocate_vector(handler, argument)?;
+        let (address, data) = message(vector);
+        unsafe {
+            self.write(entry, 12, MSI_X_ENTRY_MASKED);
+            self.write(entry, 0, address as u32);
+            self.write(entry, 4, (address >> 32) as u32);
+            self.write(entry, 8, data);
+            self.write(entry, 12, 0); // unmasked
+        }
+        Ok(vector)
+    }
+
+    // Mask `entry` and free its vector.
+    pub fn clear_vector(&self, entry: u16, vector: u8) {
+        if entry < self.size {
+            unsafe { self.write(entry, 12, MSI_X_ENTRY_MASKED) };
+        }
+        vectors::free_vector(vector);
+    }
+
+    // Turn MSI-X off and INTx back on, the vectors must be cleared first.
+    pub fn disable(self) {
+        let control = self.device.read_u16(self.capability + MSI_X_CONTROL);
+        self.device.write_u16(self.capability + MSI_X_CONTROL, control & !MSI_X_ENABLE);
+        self.device.disable(COMMAND_INTX_DISABLE);
+    }
+
+    unsafe fn write(&self, entry: u16, register: u64, value: u32) {
+        let address = self.table + u64::from(entry) * MSI_X_ENTRY_SIZE + register;
+        address.as_mut_ptr::<u32>().write_volatile(value);
+    }
+}
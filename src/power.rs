@@ -0,0 +1,111 @@
+/*
+
+Power off and reboot
+--------------------
+
+`power_off` puts the machine in the S5 (soft-off) sleep state: the sleep type comes from the `\_S5` object
+of the DSDT and is written with SLP_EN to the PM1 control blocks of the FADT, after switching the chipset
+to ACPI mode if the firmware left it in legacy mode.
+`reboot` tries the ACPI reset register, then the reset line of the 8042 keyboard controller, then a triple
+fault, which resets every x86 CPU.
+Both work on real hardware and on QEMU, unlike `crate::shutdown` which needs the isa-debug-exit device.
+*/
+
+use core::convert::Infallible;
+use x86_64::instructions::{interrupts, port::Port};
+use crate::acpi::{dsdt, fadt::Fadt};
+use crate::time;
+
+// PM1 control register
+const SCI_EN: u64 = 1 << 0; // the chipset is in ACPI mode
+const SLP_TYP_SHIFT: u64 = 10;
+const SLP_EN: u64 = 1 << 13;
+
+const ACPI_MODE_TIMEOUT_MS: u64 = 3000;
+const RESET_WAIT_US: u64 = 50_000; // for a reset method to take effect before the next one
+
+const KEYBOARD_CONTROLLER_STATUS: u16 = 0x64; // also the command port
+const KEYBOARD_CONTROLLER_INPUT_FULL: u8 = 1 << 1;
+const KEYBOARD_CONTROLLER_RESET: u8 = 0xfe; // pulse the CPU reset line
+
+#[derive(Debug)]
+pub enum PowerError {
+    NoFadt, // acpi::init was not called or failed
+    NoSleepType, // no `\_S5` object in the DSDT
+    NoControlBlock,
+    AcpiModeTimeout,
+    UnsupportedRegister, // a register outside of I/O and memory space
+    StillRunning,
+}
+
+// Switch to ACPI mode: the firmware stops handling power events through SMIs.
+pub fn enable_acpi_mode() -> Result<(), PowerError> {
+    let fadt = Fadt::get().ok_or(PowerError::NoFadt)?;
+    let control = fadt.pm1a_control.ok_or(PowerError::NoControlBlock)?;
+    let enabled = || control.read().map(|value| value & SCI_EN != 0).ok_or(PowerError::UnsupportedRegister);
+    if enabled()? || fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return Ok(()); // no legacy mode to leave
+    }
+    unsafe { Port::<u8>::new(fadt.smi_command).write(fadt.acpi_enable) };
+    for _ in 0..ACPI_MODE_TIMEOUT_MS {
+        if enabled()? {
+            return Ok(());
+        }
+        time::delay_us(1000);
+    }
+    Err(PowerError::AcpiModeTimeout)
+}
+
+// Returns only if the machine could not be turned off.
+pub fn power_off() -> Result<Infallible, PowerError> {
+    let fadt = Fadt::get().ok_or(PowerError::NoFadt)?;
+    let (sleep_type_a, sleep_type_b) = dsdt::sleep_type_s5().ok_or(PowerError::NoSleepType)?;
+    let control_a = fadt.pm1a_control.ok_or(PowerError::NoControlBlock)?;
+    enable_acpi_mode()?;
+
+    interrupts::without_interrupts(|| unsafe {
+        // the B block, when there is one, must hold its sleep type before SLP_EN of A turns the power off
+        if let Some(control_b) = fadt.pm1b_control {
+            let value = control_b.read().ok_or(PowerError::UnsupportedRegister)?;
+            control_b.write(value & SCI_EN | u64::from(sleep_type_b) << SLP_TYP_SHIFT | SLP_EN);
+        }
+        let value = control_a.read().ok_or(PowerError::UnsupportedRegister)?;
+        control_a.write(value & SCI_EN | u64::from(sleep_type_a) << SLP_TYP_SHIFT | SLP_EN);
+        time::delay_us(RESET_WAIT_US);
+        Err(PowerError::StillRunning)
+    })
+}
+
+pub fn reboot() -> ! {
+    interrupts::disable();
+    if let Some(fadt) = Fadt::get().filter(Fadt::supports_reset_register) {
+        let register = fadt.reset_register.expect("reset register supported but missing");
+        unsafe { register.write(u64::from(fadt.reset_value)) };
+        time::delay_us(RESET_WAIT_US);
+    }
+    if Fadt::get().map_or(true, |fadt| fadt.has_8042()) {
+        let mut controller = Port::<u8>::new(KEYBOARD_CONTROLLER_STATUS);
+        unsafe {
+            for _ in 0..1000 {
+                if controller.read() & KEYBOARD_CONTROLLER_INPUT_FULL == 0 {
+                    break;
+                }
+                time::delay_us(10);
+            }
+            controller.write(KEYBOARD_CONTROLLER_RESET);
+        }
+        time::delay_us(RESET_WAIT_US);
+    }
+    triple_fault()
+}
+
+// With an empty IDT the breakpoint raises a general protection fault, then a double fault, then a reset.
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+    use x86_64::VirtAddr;
+
+    let empty = DescriptorTablePointer { limit: 0, base: VirtAddr::zero() };
+    unsafe { lidt(&empty) };
+    x86_64::instructions::interrupts::int3();
+    crate::hlt_loop()
+}
@@ -0,0 +1,155 @@
+/*
+
+System calls
+------------
+
+User code calls `int 0x80` with the syscall number in rax and the arguments in
+rdi, rsi, rdx, r10, r8, r9 (same registers as Linux). The result is returned in rax.
+The gate is an interrupt gate: the handler runs with interrupts disabled, on the kernel
+stack of the thread taken from the TSS.
+*/
+
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use crate::{print, thread, usermode};
+
+pub const SYSCALL_INTERRUPT: u8 = 0x80;
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_YIELD: u64 = 2;
+pub const SYS_GETTID: u64 = 3;
+pub const SYS_SLEEP: u64 = 4;
+
+pub const ENOSYS: u64 = u64::MAX; // unknown syscall number
+pub const EFAULT: u64 = u64::MAX - 1; // pointer outside of user space
+pub const EINVAL: u64 = u64::MAX - 2; // bad argument
+pub const ENOMEM: u64 = u64::MAX - 3; // no kernel memory for the request
+
+// Caller-saved registers, pushed by `rusty_syscall_entry` in reverse order.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallRegisters {
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rax: u64,
+}
+
+impl SyscallRegisters {
+    pub fn number(&self) -> u64 {
+        self.rax
+    }
+
+    pub fn arg(&self, index: usize) -> u64 {
+        match index {
+            0 => self.rdi,
+            1 => self.rsi,
+            2 => self.rdx,
+            3 => self.r10,
+            4 => self.r8,
+            5 => self.r9,
+            _ => panic!("syscalls take at most 6 arguments"),
+        }
+    }
+}
+
+type SyscallHandler = fn(&SyscallRegisters) -> u64;
+
+// Indexed by syscall number.
+static SYSCALL_TABLE: [SyscallHandler; 5] = [
+    sys_exit,
+    sys_write,
+    sys_yield,
+    sys_gettid,
+    sys_sleep,
+];
+
+global_asm!(
+    ".global rusty_syscall_entry",
+    "rusty_syscall_entry:",
+    "push rax",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "mov rdi, rsp", // &mut SyscallRegisters, the stack is 16 bytes aligned here
+    "call rusty_syscall_dispatch",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rax",
+    "iretq",
+);
+
+extern "C" {
+    fn rusty_syscall_entry();
+}
+
+// Address of the assembly entry point, installed in the IDT with privilege level 3.
+pub fn entry_address() -> u64 {
+    rusty_syscall_entry as *const () as u64
+}
+
+#[no_mangle]
+extern "C" fn rusty_syscall_dispatch(registers: &mut SyscallRegisters) {
+    let result = match SYSCALL_TABLE.get(registers.number() as usize) {
+        Some(handler) => handler(registers),
+        None => ENOSYS,
+    };
+    registers.rax = result;
+}
+
+fn sys_exit(_registers: &SyscallRegisters) -> u64 {
+    thread::exit();
+}
+
+// write(buffer, length): print an UTF-8 string on the screen, return the number of bytes written.
+fn sys_write(registers: &SyscallRegisters) -> u64 {
+    let (ptr, len) = (registers.arg(0), registers.arg(1));
+    if !usermode::is_user_range(ptr, len) {
+        return EFAULT;
+    }
+    let mut bytes = Vec::new();
+    if bytes.try_reserve_exact(len as usize).is_err() {
+        return ENOMEM;
+    }
+    bytes.resize(len as usize, 0);
+    usermode::copy_from_user(ptr, &mut bytes); // unmapped pages kill the thread
+    match core::str::from_utf8(&bytes) {
+        Ok(s) => {
+            print!("{}", s);
+            len
+        }
+        Err(_) => EINVAL,
+    }
+}
+
+fn sys_yield(_registers: &SyscallRegisters) -> u64 {
+    thread::yield_now();
+    0
+}
+
+fn sys_gettid(_registers: &SyscallRegisters) -> u64 {
+    thread::current().as_u64()
+}
+
+// sleep(ticks)
+fn sys_sleep(registers: &SyscallRegisters) -> u64 {
+    thread::sleep(registers.arg(0));
+    0
+}
@@ -0,0 +1,111 @@
+use conquer_once::spin::OnceCell;
+use core::{pin::Pin, task::{Context, Poll}};
+use core::sync::atomic::{AtomicU64, Ordering};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use crate::{print, println};
+use crate::interrupts::irq::IrqResult;
+
+const SCANCODE_QUEUE_SIZE: usize = 100;
+
+// Lock free so the interrupt handler never has to wait on a lock held by a task.
+// OnceCell instead of lazy_static so the queue is never allocated inside the interrupt handler.
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+// Scancodes lost because the queue was full or not there yet. Counted instead of printed: printing
+// from the interrupt handler would wait on the writer lock, maybe held by the interrupted code.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+// Called by the keyboard interrupt handler.
+// Must not block or allocate.
+fn add_scancode(scancode: u8) {
+    match SCANCODE_QUEUE.try_get() {
+        Ok(queue) if queue.push(scancode).is_ok() => WAKER.wake(), // wake the task waiting on the stream
+        _ => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub fn dropped_scancodes() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+// Handler of the keyboard IRQ: decoding is done by a task, keep it short.
+pub(crate) fn keyboard_interrupt() -> IrqResult {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(0x60); // I/O port for keyboard
+    let scancode: u8 = unsafe { port.read() }; // Read the scancode from the keyboard
+    add_scancode(scancode);
+    IrqResult::Handled
+}
+
+pub struct ScancodeStream {
+    _private: (), // prevent construction from outside of the module
+}
+
+impl ScancodeStream {
+    // Only one stream may exist, it initializes the queue.
+    pub fn new() -> Self {
+        SCANCODE_QUEUE
+            .try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE))
+            .expect("ScancodeStream::new should only be called once");
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = SCANCODE_QUEUE
+            .try_get()
+            .expect("scancode queue not initialized");
+
+        // fast path: avoid registering the waker if a scancode is already there
+        if let Some(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        WAKER.register(cx.waker());
+        // check again, the interrupt handler may have pushed before the waker was registered
+        match queue.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+// Decode scancodes and print the keys, runs as a task outside of interrupt context.
+pub async fn print_keypresses() {
+    let mut scancodes = ScancodeStream::new();
+    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    let mut dropped = dropped_scancodes();
+
+    while let Some(scancode) = scancodes.next().await {
+        if dropped_scancodes() != dropped {
+            dropped = dropped_scancodes();
+            println!("WARNING: {} scancodes dropped; keyboard input lost", dropped);
+        }
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            if let Some(key) = keyboard.process_keyevent(key_event) {
+                match key {
+                    DecodedKey::Unicode(character) => print!("{}", character),
+                    DecodedKey::RawKey(key) => print!("{:?}", key),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use core::{future::Future, pin::Pin};
+use core::task::{Context, Poll};
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::boxed::Box;
+
+pub mod executor;
+pub mod keyboard;
+
+// A task is a pinned, heap allocated future with no output.
+// Pinned because async blocks can be self-referential and must not move once polled.
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future), // move the future to the heap and pin it
+        }
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed)) // every id is returned exactly once
+    }
+}
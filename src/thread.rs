@@ -0,0 +1,202 @@
+/*
+
+Preemptive kernel threads
+-------------------------
+
+Every thread has its own stack (see `stack`) and a saved stack pointer.
+The timer interrupt calls the scheduler, which saves the registers of the running thread
+on its stack and resumes the next one of the round-robin run queue.
+
+Thread stacks have a guard page: an overflow ends in a double fault instead of corrupting memory.
+*/
+
+mod context;
+mod scheduler;
+pub mod stack;
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+use x86_64::{instructions::interrupts, VirtAddr};
+use scheduler::{Scheduler, SCHEDULER};
+use stack::Stack;
+
+type ExitHook = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug)]
+pub enum SpawnError {
+    Stack(MapToError<Size4KiB>), // no memory to map the thread stack
+}
+
+impl From<MapToError<Size4KiB>> for SpawnError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        SpawnError::Stack(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    Ready,
+    Sleeping(u64), // tick at which the thread becomes ready again
+    Exited,
+}
+
+pub struct Thread {
+    id: ThreadId,
+    state: ThreadState,
+    rsp: u64, // saved stack pointer while the thread is not running
+    stack: Option<Stack>, // None for the boot thread, which keeps the bootloader stack
+    interrupt_depth: u64, // interrupt handlers the thread is in, saved while it is not running
+    exit_hook: Option<ExitHook>, // run by `exit`, see `at_exit`
+}
+
+impl Thread {
+    fn new(entry: Box<dyn FnOnce() + Send + 'static>) -> Result<Box<Self>, SpawnError> {
+        let mut stack = Stack::new()?;
+        let entry = Box::into_raw(Box::new(entry)); // double box: a thin pointer fits in a register
+        let rsp = context::init_stack(stack.as_mut_slice(), entry as u64);
+        Ok(Box::new(Thread {
+            id: ThreadId::new(),
+            state: ThreadState::Ready,
+            rsp,
+            stack: Some(stack),
+            interrupt_depth: 0,
+            exit_hook: None,
+        }))
+    }
+
+    fn boot() -> Box<Self> {
+        Box::new(Thread {
+            id: ThreadId::new(),
+            state: ThreadState::Running,
+            rsp: 0, // filled on the first switch
+            stack: None,
+            interrupt_depth: 0,
+            exit_hook: None,
+        })
+    }
+
+    // End of the thread's own stack, used as kernel stack when it comes back from ring 3.
+    fn stack_end(&self) -> Option<VirtAddr> {
+        self.stack.as_ref().map(Stack::end)
+    }
+}
+
+// Turn the running code into the boot thread and start preemption.
+// Needs `memory::init_kernel_memory`.
+pub fn init() {
+    let boot_thread = Thread::boot();
+    let idle_thread = Thread::new(Box::new(|| crate::hlt_loop())).expect("no memory for the idle thread stack");
+    interrupts::without_interrupts(|| {
+        *SCHEDULER.lock() = Some(Scheduler::new(boot_thread, idle_thread));
+    });
+}
+
+// Fails when there is no memory left for the stack, `f` is dropped then.
+pub fn spawn<F>(f: F) -> Result<ThreadId, SpawnError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let thread = Thread::new(Box::new(f))?;
+    let id = thread.id;
+    with_scheduler(|scheduler| scheduler.add(thread));
+    Ok(id)
+}
+
+pub fn current() -> ThreadId {
+    with_scheduler(|scheduler| scheduler.current())
+}
+
+// Wait until the thread `id` has exited.
+pub fn join(id: ThreadId) {
+    while with_scheduler(|scheduler| scheduler.is_alive(id)) {
+        yield_now();
+    }
+}
+
+// Give the CPU to the next ready thread.
+pub fn yield_now() {
+    interrupts::without_interrupts(scheduler::schedule);
+}
+
+// Block the current thread for at least `ticks` timer interrupts.
+pub fn sleep(ticks: u64) {
+    interrupts::without_interrupts(|| {
+        with_scheduler(|scheduler| {
+            let until = scheduler.ticks() + ticks;
+            scheduler.set_current_state(ThreadState::Sleeping(until));
+        });
+        scheduler::schedule();
+    });
+}
+
+// Run `f` when the current thread calls `exit`, e.g. to free what it mapped in user space.
+// A thread has one hook, a second call replaces the first one.
+pub fn at_exit<F>(f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let hook: ExitHook = Box::new(f);
+    let replaced = with_scheduler(|scheduler| scheduler.set_exit_hook(hook));
+    drop(replaced); // outside of the scheduler lock
+}
+
+// Terminate the current thread, its stack is freed by the scheduler later.
+pub fn exit() -> ! {
+    if let Some(hook) = with_scheduler(Scheduler::take_exit_hook) {
+        hook(); // the thread is still alive: the hook may lock, allocate and be preempted
+    }
+    interrupts::disable();
+    with_scheduler(|scheduler| scheduler.set_current_state(ThreadState::Exited));
+    scheduler::schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+// Set by the timer interrupt handler, the switch waits for the end of the IRQ.
+static PREEMPT: AtomicBool = AtomicBool::new(false);
+
+// Called by the timer interrupt handler, with the other handlers of the line still to run.
+pub(crate) fn timer_tick() {
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.tick();
+        PREEMPT.store(true, Ordering::Relaxed);
+    }
+}
+
+// Called by `irq::dispatch` once every handler ran and the end of interrupt was sent:
+// the next thread may run for a while before this one returns from the interrupt.
+pub(crate) fn preempt_if_requested() {
+    if PREEMPT.swap(false, Ordering::Relaxed) {
+        scheduler::schedule();
+    }
+}
+
+fn with_scheduler<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        f(SCHEDULER.lock().as_mut().expect("thread::init was not called"))
+    })
+}
+
+// Entry point of every new thread, reached through `rusty_thread_start`.
+#[no_mangle]
+extern "C" fn rusty_thread_entry(entry: *mut Box<dyn FnOnce() + Send + 'static>) -> ! {
+    let entry = unsafe { Box::from_raw(entry) };
+    interrupts::enable(); // a new thread starts inside `schedule`, with interrupts disabled
+    entry();
+    exit();
+}
@@ -0,0 +1,67 @@
+use core::arch::global_asm;
+
+// Saved state of a thread that is not running: callee-saved registers and rflags
+// pushed on its own stack, the stack pointer is kept in `Thread::rsp`.
+// Caller-saved registers are already saved by the compiler around the call
+// (or by the x86-interrupt prologue when switching from the timer handler).
+global_asm!(
+    ".global rusty_switch_context",
+    "rusty_switch_context:", // rdi = where to save the old rsp, rsi = rsp to load
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "pushfq",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "popfq",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    "",
+    ".global rusty_thread_start",
+    "rusty_thread_start:", // first `ret` of a new thread lands here, r12 holds the entry closure
+    "mov rdi, r12",
+    "and rsp, -16", // System V ABI: stack aligned before the call
+    "call rusty_thread_entry",
+    "ud2", // rusty_thread_entry never returns
+);
+
+extern "C" {
+    fn rusty_switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn rusty_thread_start();
+}
+
+// Number of u64 popped by `rusty_switch_context` before its `ret`.
+const SAVED_REGISTERS: usize = 7;
+
+// Prepare a fresh stack so that switching to it starts `rusty_thread_start` with `entry` in r12.
+// Return the initial stack pointer.
+pub fn init_stack(stack: &mut [u8], entry: u64) -> u64 {
+    let stack_top = (stack.as_mut_ptr() as u64 + stack.len() as u64) & !0xf; // align down to 16 bytes
+    let frame: [u64; SAVED_REGISTERS + 1] = [
+        0x2,    // rflags: reserved bit set, interrupts disabled until the thread enables them
+        0,      // r15
+        0,      // r14
+        0,      // r13
+        entry,  // r12
+        0,      // rbx
+        0,      // rbp
+        rusty_thread_start as *const () as u64, // return address
+    ];
+    let rsp = stack_top - core::mem::size_of_val(&frame) as u64;
+    unsafe { (rsp as *mut [u64; SAVED_REGISTERS + 1]).write(frame) };
+    rsp
+}
+
+// Save the current context into `old_rsp` and resume the one saved at `new_rsp`.
+// Unsafe because `new_rsp` must come from `init_stack` or a previous switch, and interrupts must be disabled.
+pub unsafe fn switch(old_rsp: *mut u64, new_rsp: u64) {
+    rusty_switch_context(old_rsp, new_rsp);
+}
@@ -0,0 +1,147 @@
+use super::{ExitHook, Thread, ThreadId, ThreadState};
+use alloc::{boxed::Box, collections::{BTreeMap, VecDeque}};
+use spin::Mutex;
+use crate::gdt;
+use crate::interrupts::stats;
+
+// Always locked with interrupts disabled, otherwise the timer could preempt the holder and deadlock.
+pub(super) static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+pub(super) struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>, // boxed so `rsp` keeps its address while we switch
+    run_queue: VecDeque<ThreadId>, // round-robin order of ready threads
+    current: ThreadId,
+    idle: ThreadId, // runs only when nothing else is ready, never queued
+    ticks: u64, // timer interrupts since `init`
+}
+
+impl Scheduler {
+    pub fn new(boot_thread: Box<Thread>, idle_thread: Box<Thread>) -> Self {
+        let current = boot_thread.id;
+        let idle = idle_thread.id;
+        let mut threads = BTreeMap::new();
+        threads.insert(current, boot_thread);
+        threads.insert(idle, idle_thread);
+        Scheduler {
+            threads,
+            run_queue: VecDeque::new(),
+            current,
+            idle,
+            ticks: 0,
+        }
+    }
+
+    pub fn add(&mut self, thread: Box<Thread>) {
+        let id = thread.id;
+        self.threads.insert(id, thread);
+        self.run_queue.push_back(id);
+    }
+
+    pub fn current(&self) -> ThreadId {
+        self.current
+    }
+
+    pub fn is_alive(&self, id: ThreadId) -> bool {
+        match self.threads.get(&id) {
+            Some(thread) => thread.state != ThreadState::Exited,
+            None => false,
+        }
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+    }
+
+    pub fn set_current_state(&mut self, state: ThreadState) {
+        let current = self.current;
+        self.thread_mut(current).state = state;
+    }
+
+    // Hook of the current thread, the previous one returned.
+    pub fn set_exit_hook(&mut self, hook: ExitHook) -> Option<ExitHook> {
+        let current = self.current;
+        self.thread_mut(current).exit_hook.replace(hook)
+    }
+
+    pub fn take_exit_hook(&mut self) -> Option<ExitHook> {
+        let current = self.current;
+        self.thread_mut(current).exit_hook.take()
+    }
+
+    fn thread_mut(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads.get_mut(&id).expect("thread not in scheduler")
+    }
+
+    // Free exited threads, except the current one: we are still running on its stack.
+    fn reap(&mut self) {
+        let current = self.current;
+        self.threads.retain(|&id, thread| id == current || thread.state != ThreadState::Exited);
+    }
+
+    // Move sleeping threads whose deadline passed back to the run queue.
+    fn wake_sleepers(&mut self) {
+        let ticks = self.ticks;
+        for (&id, thread) in self.threads.iter_mut() {
+            if let ThreadState::Sleeping(until) = thread.state {
+                if until <= ticks {
+                    thread.state = ThreadState::Ready;
+                    self.run_queue.push_back(id);
+                }
+            }
+        }
+    }
+
+    // Pick the next thread to run. Return where to save the current stack pointer and the one to load,
+    // or None when the current thread keeps the CPU.
+    pub fn switch(&mut self) -> Option<(*mut u64, u64)> {
+        self.reap();
+        self.wake_sleepers();
+
+        let current = self.current;
+        let current_runnable = self.threads[&current].state == ThreadState::Running;
+        let next = match self.run_queue.pop_front() {
+            Some(next) => next,
+            None if current_runnable => return None, // nothing else to run
+            None => self.idle,
+        };
+        if next == current {
+            return None;
+        }
+
+        if current_runnable {
+            self.thread_mut(current).state = ThreadState::Ready;
+            if current != self.idle {
+                self.run_queue.push_back(current);
+            }
+        }
+        self.thread_mut(next).state = ThreadState::Running;
+        self.current = next;
+        if let Some(stack_end) = self.threads[&next].stack_end() {
+            unsafe { gdt::set_kernel_stack(stack_end) }; // interrupts from ring 3 land on the thread's own stack
+        }
+
+        // the nesting depth of the interrupt statistics follows the thread
+        self.thread_mut(current).interrupt_depth = stats::depth();
+        stats::set_depth(self.threads[&next].interrupt_depth);
+
+        let old_rsp = &mut self.thread_mut(current).rsp as *mut u64;
+        let new_rsp = self.threads[&next].rsp;
+        Some((old_rsp, new_rsp))
+    }
+}
+
+// Switch to the next ready thread, if any. Interrupts must be disabled.
+pub(super) fn schedule() {
+    let switch = match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => scheduler.switch(),
+        None => None, // not initialized yet
+    }; // the lock must be released before switching, the next thread will take it again
+
+    if let Some((old_rsp, new_rsp)) = switch {
+        unsafe { super::context::switch(old_rsp, new_rsp) };
+    }
+}
@@ -0,0 +1,82 @@
+/*
+
+Thread stacks
+-------------
+
+Every stack has its own slot in a region of the kernel address space, mapped with frames of the kernel
+frame allocator instead of taken from the heap. The page below each stack is left unmapped: an overflow
+faults there instead of silently overwriting the stack below. Slots of freed stacks are reused.
+*/
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use crate::memory::{self, with_kernel_memory};
+
+pub const STACKS_START: u64 = 0x_6666_0000_0000;
+pub const STACK_SIZE: u64 = 4096 * 16; // 64 KiB per thread
+pub const GUARD_SIZE: u64 = Size4KiB::SIZE;
+const SLOT_SIZE: u64 = GUARD_SIZE + STACK_SIZE;
+
+static NEXT_SLOT: AtomicU64 = AtomicU64::new(STACKS_START);
+// Taken with interrupts disabled: stacks are freed by the scheduler.
+static FREE_SLOTS: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+pub struct Stack {
+    bottom: VirtAddr, // lowest mapped address, the guard page is right below
+}
+
+impl Stack {
+    // Needs `memory::init_kernel_memory`.
+    pub fn new() -> Result<Stack, MapToError<Size4KiB>> {
+        let slot = interrupts::without_interrupts(|| FREE_SLOTS.lock().pop())
+            .unwrap_or_else(|| NEXT_SLOT.fetch_add(SLOT_SIZE, Ordering::Relaxed));
+        let bottom = VirtAddr::new(slot + GUARD_SIZE);
+        let mapped = with_kernel_memory(|memory| {
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            for index in 0..STACK_SIZE / Size4KiB::SIZE {
+                let page = Page::<Size4KiB>::containing_address(bottom + index * Size4KiB::SIZE);
+                let result = match memory.frame_allocator.allocate_frame() {
+                    Some(frame) => unsafe { memory.mapper.map_to(page, frame, flags, &mut memory.frame_allocator) }
+                        .map(|flush| flush.flush()),
+                    None => Err(MapToError::FrameAllocationFailed),
+                };
+                if let Err(error) = result { // give back what was mapped
+                    if index > 0 {
+                        unsafe { memory::unmap_region(bottom, index * Size4KiB::SIZE, &mut memory.mapper, &mut memory.frame_allocator) }
+                            .expect("unmapping a partial thread stack failed");
+                    }
+                    return Err(error);
+                }
+            }
+            Ok(())
+        });
+        if let Err(error) = mapped {
+            interrupts::without_interrupts(|| FREE_SLOTS.lock().push(slot));
+            return Err(error);
+        }
+        unsafe { core::ptr::write_bytes(bottom.as_mut_ptr::<u8>(), 0, STACK_SIZE as usize) };
+        Ok(Stack { bottom })
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        self.bottom + STACK_SIZE
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.bottom.as_mut_ptr(), STACK_SIZE as usize) }
+    }
+}
+
+// Nothing may run on the stack anymore.
+impl Drop for Stack {
+    fn drop(&mut self) {
+        with_kernel_memory(|memory| unsafe {
+            memory::unmap_region(self.bottom, STACK_SIZE, &mut memory.mapper, &mut memory.frame_allocator)
+        }).expect("unmapping a thread stack failed");
+        interrupts::without_interrupts(|| FREE_SLOTS.lock().push(self.bottom.as_u64() - GUARD_SIZE));
+    }
+}
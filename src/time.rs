@@ -0,0 +1,239 @@
+/*
+
+Kernel clock
+------------
+
+The PIT raises IRQ 0 at `frequency()` Hz (DEFAULT_FREQUENCY after `init`), every interrupt increments the
+monotonic tick counter, adds the tick period to the time elapsed since boot and advances the timer wheel.
+`Instant` reads the best clock source available: the HPET main counter once `init_hpet` found one, else
+the TSC when the CPU has one, its frequency measured at `init` against PIT channel 2, else the elapsed
+ticks of the PIT (a resolution of one period). Switching to the HPET continues from the current instant,
+so instants stay monotonic. Durations are `core::time::Duration`.
+The wall clock is the CMOS RTC date read at `init`, advanced by the monotonic clock: it doesn't jump when
+the RTC is changed later. `sync_wall_clock` reads the RTC again, once ACPI tells where its century is.
+*/
+
+pub mod hpet;
+pub mod pit;
+pub mod rtc;
+pub mod timer;
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use x86_64::instructions::interrupts;
+pub use core::time::Duration;
+pub use timer::sleep;
+pub use rtc::DateTime;
+
+pub const DEFAULT_FREQUENCY: u32 = 100; // Hz
+
+const CALIBRATION_COUNT: u16 = 11_932; // PIT cycles, 10 ms
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TICK_NANOS: AtomicU64 = AtomicU64::new(0); // period of the PIT
+static TICKS_ELAPSED_NANOS: AtomicU64 = AtomicU64::new(0); // sum of the periods of every tick, survives frequency changes
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0); // Hz, 0 without TSC
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+static HPET_START: AtomicU64 = AtomicU64::new(0); // main counter when the HPET became the clock source
+static HPET_START_NANOS: AtomicU64 = AtomicU64::new(0); // instant at that time
+static CLOCK_SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Pit as u8);
+static BOOT_UNIX_NANOS: AtomicU64 = AtomicU64::new(0); // wall clock when `Instant` was 0
+
+// Program the PIT, calibrate the TSC and read the RTC. Called by `crate::init` before interrupts are enabled.
+pub fn init(frequency: u32) {
+    if has_tsc() {
+        let cycles = pit::measure(CALIBRATION_COUNT, || unsafe { _rdtsc() });
+        let frequency = cycles * u64::from(pit::BASE_FREQUENCY) / u64::from(CALIBRATION_COUNT);
+        TSC_START.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+        TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+        CLOCK_SOURCE.store(ClockSource::Tsc as u8, Ordering::Relaxed);
+    }
+    set_frequency(frequency);
+    let _ = sync_wall_clock(); // the wall clock starts at 1970 when the RTC date is invalid
+}
+
+// Set the wall clock to the RTC date again, e.g. after `acpi::init`: before it the century register is
+// guessed. The wall clock is left as it is on error.
+pub fn sync_wall_clock() -> Result<(), rtc::RtcError> {
+    let rtc = Duration::from_secs(rtc::read()?.timestamp());
+    BOOT_UNIX_NANOS.store((rtc.saturating_sub(uptime())).as_nanos() as u64, Ordering::Relaxed);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    Pit,
+    Tsc,
+    Hpet,
+}
+
+pub fn clock_source() -> ClockSource {
+    match CLOCK_SOURCE.load(Ordering::Acquire) {
+        source if source == ClockSource::Hpet as u8 => ClockSource::Hpet,
+        source if source == ClockSource::Tsc as u8 => ClockSource::Tsc,
+        _ => ClockSource::Pit,
+    }
+}
+
+// Start the HPET and make it the clock source. Needs `acpi::init` and `memory::init_kernel_memory`,
+// the previous clock source stays on error.
+pub fn init_hpet() -> Result<(), hpet::HpetError> {
+    hpet::init()?;
+    interrupts::without_interrupts(|| {
+        let now = Instant::now();
+        HPET_START.store(hpet::counter(), Ordering::Relaxed);
+        HPET_START_NANOS.store(now.nanos, Ordering::Relaxed);
+        CLOCK_SOURCE.store(ClockSource::Hpet as u8, Ordering::Release);
+    });
+    Ok(())
+}
+
+// Change the rate of the timer interrupt, and so of the scheduler.
+pub fn set_frequency(frequency: u32) {
+    TICK_NANOS.store(pit::set_frequency(frequency), Ordering::Relaxed);
+}
+
+// Actual rate of the timer interrupt, close to the requested one.
+pub fn frequency() -> u32 {
+    match TICK_NANOS.load(Ordering::Relaxed) {
+        0 => 0,
+        nanos => (1_000_000_000 / nanos) as u32,
+    }
+}
+
+// Timer interrupts since `init`.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+pub fn uptime() -> Duration {
+    Instant::now().duration_since(Instant::BOOT)
+}
+
+// Time since 1970-01-01 00:00:00 UTC.
+pub fn unix_time() -> Duration {
+    Duration::from_nanos(BOOT_UNIX_NANOS.load(Ordering::Relaxed)) + uptime()
+}
+
+// Current UTC date, e.g. for log timestamps and file times.
+pub fn now() -> DateTime {
+    DateTime::from_timestamp(unix_time().as_secs())
+}
+
+// Busy-wait at least `micros` microseconds, also with interrupts disabled.
+pub fn delay_us(micros: u64) {
+    if clock_source() != ClockSource::Pit {
+        let deadline = Instant::now() + Duration::from_micros(micros);
+        while Instant::now() < deadline {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+    // the ticks may not advance: count on PIT channel 2 instead
+    let mut cycles = micros * u64::from(pit::BASE_FREQUENCY) / 1_000_000 + 1;
+    while cycles > 0 {
+        let count = cycles.min(u64::from(u16::MAX));
+        pit::wait(count as u16, || {});
+        cycles -= count;
+    }
+}
+
+// Called by the timer interrupt handler, runs the expired timers.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    TICKS_ELAPSED_NANOS.fetch_add(tick_nanos(), Ordering::Relaxed);
+    timer::tick();
+}
+
+fn tick_nanos() -> u64 {
+    TICK_NANOS.load(Ordering::Relaxed)
+}
+
+fn has_tsc() -> bool {
+    const CPUID_TSC: u32 = 1 << 4; // leaf 1, edx
+    #[allow(unused_unsafe)] // __cpuid is safe on recent toolchains
+    let edx = unsafe { __cpuid(1).edx };
+    edx & CPUID_TSC != 0
+}
+
+// Point in time since boot, it never goes backwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    const BOOT: Instant = Instant { nanos: 0 };
+
+    pub fn now() -> Instant {
+        let nanos = match clock_source() {
+            ClockSource::Hpet => {
+                let ticks = hpet::counter().saturating_sub(HPET_START.load(Ordering::Relaxed));
+                HPET_START_NANOS.load(Ordering::Relaxed) + hpet::ticks_to_nanos(ticks)
+            }
+            ClockSource::Tsc => {
+                let cycles = unsafe { _rdtsc() }.saturating_sub(TSC_START.load(Ordering::Relaxed));
+                let frequency = TSC_FREQUENCY.load(Ordering::Relaxed);
+                (u128::from(cycles) * 1_000_000_000 / u128::from(frequency)) as u64
+            }
+            ClockSource::Pit => TICKS_ELAPSED_NANOS.load(Ordering::Relaxed),
+        };
+        Instant { nanos }
+    }
+
+    // Zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.nanos.checked_sub(earlier.nanos).map(Duration::from_nanos)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_add(nanos).map(|nanos| Instant { nanos })
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_sub(nanos).map(|nanos| Instant { nanos })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding a duration to an instant")
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("overflow when subtracting a duration from an instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
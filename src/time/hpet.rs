@@ -0,0 +1,229 @@
+/*
+
+High Precision Event Timer
+--------------------------
+
+Found with the ACPI HPET table. Its main counter runs at a fixed frequency of at least 10 MHz, given
+by the hardware as a period in femtoseconds, and is a better clock than the PIT: `time::init_hpet`
+makes it the clock source. Only a 64-bit main counter is used, a 32-bit one wraps within minutes.
+
+One comparator gives one-shot interrupts, set up by `init_oneshot` apart from the counter: it is routed,
+not in legacy replacement mode, to an ISA IRQ line it supports that no other driver uses, and the registered
+handler of that line runs the callback. Comparators may support no such line (QEMU's i440FX only offers
+IRQ 2, the PIT's input), then `set_oneshot` reports one-shots as unsupported.
+*/
+
+use core::ptr;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+use x86_64::instructions::interrupts;
+use super::Duration;
+use crate::acpi::hpet::HpetTable;
+use crate::interrupts::{apic, irq::{self, IrqError, IrqResult, IRQ_LINES}};
+use crate::memory;
+
+// Registers, offsets from the base
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+const TIMER_CONFIGURATION: usize = 0x100; // + 0x20 * comparator
+const TIMER_COMPARATOR: usize = 0x108; // + 0x20 * comparator
+
+const ENABLE: u64 = 1 << 0; // configuration
+const COUNTER_64BIT: u64 = 1 << 13; // capabilities
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_32BIT: u64 = 1 << 8; // force 32-bit mode
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+const TIMER_FSB: u64 = 1 << 14;
+
+const NO_IRQ: usize = usize::MAX;
+
+#[derive(Debug)]
+pub enum HpetError {
+    NoTable, // acpi::init was not called or there is no HPET
+    Counter32Bit,
+    Mapping(MapToError<Size4KiB>),
+    NoFreeLine, // no comparator can be routed to an unused ISA IRQ line
+    Irq(IrqError),
+    AlreadyInitialized,
+}
+
+impl From<MapToError<Size4KiB>> for HpetError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        HpetError::Mapping(error)
+    }
+}
+
+static BASE: AtomicU64 = AtomicU64::new(0); // virtual address of the registers, 0 without HPET
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0); // of the main counter, in femtoseconds
+static ONESHOT_COMPARATOR: AtomicUsize = AtomicUsize::new(NO_IRQ);
+static ONESHOT_IRQ: AtomicUsize = AtomicUsize::new(NO_IRQ);
+static ONESHOT_DEADLINE: AtomicU64 = AtomicU64::new(0); // main counter value, 0 while disarmed
+static ONESHOT_CALLBACK: AtomicUsize = AtomicUsize::new(0); // fn(), 0 while disarmed
+
+unsafe fn read_register(register: usize) -> u64 {
+    ptr::read_volatile((BASE.load(Ordering::Relaxed) as usize + register) as *const u64)
+}
+
+unsafe fn write_register(register: usize, value: u64) {
+    ptr::write_volatile((BASE.load(Ordering::Relaxed) as usize + register) as *mut u64, value);
+}
+
+// Map the registers and start the main counter. Needs `acpi::init` and `memory::init_kernel_memory`.
+pub fn init() -> Result<(), HpetError> {
+    if is_present() {
+        return Err(HpetError::AlreadyInitialized);
+    }
+    let table = HpetTable::get().ok_or(HpetError::NoTable)?;
+    let base = memory::map_mmio(table.base_address, 1024)?;
+    let capabilities = unsafe { ptr::read_volatile((base.as_u64() as usize + CAPABILITIES) as *const u64) };
+    if capabilities & COUNTER_64BIT == 0 {
+        return Err(HpetError::Counter32Bit);
+    }
+    interrupts::without_interrupts(|| unsafe {
+        BASE.store(base.as_u64(), Ordering::Relaxed);
+        PERIOD_FS.store(capabilities >> 32, Ordering::Relaxed);
+        let configuration = read_register(CONFIGURATION);
+        write_register(CONFIGURATION, configuration & !0b11); // stopped, no legacy replacement
+        for comparator in 0..comparators() {
+            let register = TIMER_CONFIGURATION + 0x20 * comparator;
+            write_register(register, read_register(register) & !(TIMER_INTERRUPT_ENABLE | TIMER_FSB));
+        }
+        write_register(MAIN_COUNTER, 0);
+        write_register(CONFIGURATION, (configuration & !0b11) | ENABLE);
+    });
+    Ok(())
+}
+
+fn comparators() -> usize {
+    ((unsafe { read_register(CAPABILITIES) } >> 8) & 0x1f) as usize + 1
+}
+
+// Find a comparator and an unused ISA IRQ line it can interrupt, the PIT lines excluded, for `set_oneshot`.
+// Needs `init`.
+pub fn init_oneshot() -> Result<(), HpetError> {
+    if !is_present() {
+        return Err(HpetError::NoTable);
+    }
+    if oneshot_irq().is_some() {
+        return Err(HpetError::AlreadyInitialized);
+    }
+    let route = (0..comparators()).find_map(|comparator| {
+        let routes = unsafe { read_register(TIMER_CONFIGURATION + 0x20 * comparator) } >> 32;
+        (3..IRQ_LINES as u8)
+            .filter(|&line| !irq::is_registered(line))
+            .find(|&line| apic::isa_irq_gsi(line) == u32::from(line) && routes & (1 << line) != 0)
+            .map(|line| (comparator, line))
+    });
+    let (comparator, line) = route.ok_or(HpetError::NoFreeLine)?;
+    unsafe {
+        let register = TIMER_CONFIGURATION + 0x20 * comparator;
+        let configuration = read_register(register) & !(TIMER_PERIODIC | TIMER_32BIT | TIMER_ROUTE_MASK | 0b10);
+        write_register(register, configuration | u64::from(line) << TIMER_ROUTE_SHIFT); // edge triggered
+    }
+    irq::register_irq(line, oneshot_interrupt).map_err(HpetError::Irq)?;
+    ONESHOT_IRQ.store(usize::from(line), Ordering::Relaxed);
+    ONESHOT_COMPARATOR.store(comparator, Ordering::Relaxed); // set_oneshot works from here on
+    Ok(())
+}
+
+pub fn is_present() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+// Main counter, 0 without HPET.
+pub fn counter() -> u64 {
+    if !is_present() {
+        return 0;
+    }
+    unsafe { read_register(MAIN_COUNTER) }
+}
+
+// Hz, 0 without HPET.
+pub fn frequency() -> u64 {
+    match PERIOD_FS.load(Ordering::Relaxed) {
+        0 => 0,
+        period => 1_000_000_000_000_000 / period,
+    }
+}
+
+// Nanoseconds of `ticks` of the main counter.
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+    (u128::from(ticks) * u128::from(PERIOD_FS.load(Ordering::Relaxed)) / 1_000_000) as u64
+}
+
+fn nanos_to_ticks(nanos: u128) -> u64 {
+    (nanos * 1_000_000 / u128::from(PERIOD_FS.load(Ordering::Relaxed).max(1))).min(u128::from(u64::MAX)) as u64
+}
+
+// ISA IRQ line of the one-shot interrupts, None while they are unsupported.
+pub fn oneshot_irq() -> Option<u8> {
+    match ONESHOT_IRQ.load(Ordering::Relaxed) {
+        NO_IRQ => None,
+        line => Some(line as u8),
+    }
+}
+
+// Run `callback` in the interrupt handler once `delay` elapsed, replacing an armed one-shot.
+// Like IRQ handlers the callback must not block or allocate. Returns false, doing nothing, when one-shots are
+// unsupported: without HPET or a line for them, see `init_oneshot`.
+pub fn set_oneshot(delay: Duration, callback: fn()) -> bool {
+    let comparator = ONESHOT_COMPARATOR.load(Ordering::Relaxed);
+    if comparator == NO_IRQ {
+        return false;
+    }
+    let missed = interrupts::without_interrupts(|| unsafe {
+        let deadline = counter().saturating_add(nanos_to_ticks(delay.as_nanos()).max(1));
+        ONESHOT_CALLBACK.store(callback as usize, Ordering::Relaxed);
+        ONESHOT_DEADLINE.store(deadline, Ordering::Relaxed);
+        let register = TIMER_CONFIGURATION + 0x20 * comparator;
+        write_register(TIMER_COMPARATOR + 0x20 * comparator, deadline);
+        write_register(register, read_register(register) | TIMER_INTERRUPT_ENABLE);
+        counter() >= deadline // the comparator only fires when the counter reaches it
+    });
+    if missed {
+        fire_oneshot(); // for a tiny delay the interrupt is lost: run the callback right away
+    }
+    true
+}
+
+// Disarm the one-shot interrupt, false if none was armed.
+pub fn cancel_oneshot() -> bool {
+    let comparator = ONESHOT_COMPARATOR.load(Ordering::Relaxed);
+    if comparator == NO_IRQ {
+        return false;
+    }
+    interrupts::without_interrupts(|| unsafe {
+        let register = TIMER_CONFIGURATION + 0x20 * comparator;
+        write_register(register, read_register(register) & !TIMER_INTERRUPT_ENABLE);
+        ONESHOT_DEADLINE.store(0, Ordering::Relaxed);
+        ONESHOT_CALLBACK.swap(0, Ordering::Relaxed) != 0
+    })
+}
+
+fn fire_oneshot() -> bool {
+    let callback = interrupts::without_interrupts(|| {
+        ONESHOT_DEADLINE.store(0, Ordering::Relaxed);
+        ONESHOT_CALLBACK.swap(0, Ordering::Relaxed)
+    });
+    if callback == 0 {
+        return false;
+    }
+    let callback: fn() = unsafe { core::mem::transmute(callback) };
+    callback();
+    true
+}
+
+// Edge triggered, so the line may be shared: it was ours if the armed deadline passed.
+fn oneshot_interrupt() -> IrqResult {
+    let deadline = ONESHOT_DEADLINE.load(Ordering::Relaxed);
+    if deadline == 0 || counter() < deadline {
+        return IrqResult::NotMine;
+    }
+    match fire_oneshot() {
+        true => IrqResult::Handled,
+        false => IrqResult::NotMine,
+    }
+}
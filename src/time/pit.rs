@@ -0,0 +1,58 @@
+// 8253/8254 programmable interval timer: channel 0 drives IRQ 0, channel 2 is polled to calibrate the TSC.
+
+use x86_64::instructions::port::Port;
+
+pub const BASE_FREQUENCY: u32 = 1_193_182; // Hz, input clock of every channel
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const SPEAKER_CONTROL: u16 = 0x61; // bit 0: channel 2 gate, bit 1: speaker, bit 5: channel 2 output
+
+// Command bits: channel (7-6), access lobyte then hibyte (5-4), mode (3-1), binary (0)
+const CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100; // mode 2
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000; // mode 0: the output goes high at the end of the count
+
+// Divisor of the input clock giving the closest frequency to `hz`, 0 stands for 65536.
+fn divisor(hz: u32) -> u32 {
+    let divisor = (BASE_FREQUENCY + hz / 2) / hz.max(1);
+    divisor.clamp(1, 65536)
+}
+
+// Fire IRQ 0 at about `hz`, returns the period in nanoseconds (the divisor rounds the frequency).
+pub fn set_frequency(hz: u32) -> u64 {
+    let divisor = divisor(hz);
+    unsafe {
+        Port::<u8>::new(COMMAND).write(CHANNEL_0_RATE_GENERATOR);
+        let mut data = Port::<u8>::new(CHANNEL_0);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    }
+    u64::from(divisor) * 1_000_000_000 / u64::from(BASE_FREQUENCY)
+}
+
+// Count `timestamp` cycles during `count` PIT cycles on channel 2, with its speaker output disabled.
+// Interrupts don't matter: channel 2 is polled.
+pub fn measure(count: u16, timestamp: impl Fn() -> u64) -> u64 {
+    let mut start = 0;
+    wait(count, || start = timestamp());
+    timestamp() - start
+}
+
+// Busy-wait `count` PIT cycles (at most 55 ms), `started` runs once the countdown started.
+pub fn wait(count: u16, started: impl FnOnce()) {
+    let mut control = Port::<u8>::new(SPEAKER_CONTROL);
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL_2);
+    unsafe {
+        let gate = control.read() & !0b11; // gate low, speaker off
+        control.write(gate);
+        command.write(CHANNEL_2_ONE_SHOT);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+        control.write(gate | 1); // counting starts on the rising gate
+        started();
+        while control.read() & 0x20 == 0 {}
+        control.write(gate);
+    }
+}
@@ -0,0 +1,176 @@
+// CMOS real-time clock: the date and time kept by the battery-backed clock, read at boot.
+
+use core::fmt;
+use x86_64::instructions::{interrupts, port::Port};
+use crate::acpi::fadt::Fadt;
+
+const INDEX: u16 = 0x70; // bit 7 disables the NMI, kept clear
+const DATA: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const DEFAULT_CENTURY: u8 = 0x32; // used until the ACPI FADT tells where the century is
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7; // status A
+const HOURS_24: u8 = 1 << 1; // status B
+const BINARY: u8 = 1 << 2; // status B, BCD otherwise
+const HOUR_PM: u8 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    InvalidDate(DateTime), // a register is out of its range, e.g. day 0 or hour 25
+}
+
+// Broken-down UTC date, the RTC is assumed to keep UTC like QEMU does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8, // 1..=12
+    pub day: u8, // 1..=31
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    // Seconds since 1970-01-01 00:00:00 UTC, at least 1970.
+    pub fn from_timestamp(timestamp: u64) -> DateTime {
+        let days = timestamp / 86_400;
+        let seconds = timestamp % 86_400;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    // Every field is in its range, the day in its month. `timestamp` is only meaningful for valid dates.
+    pub fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && (1..=days_in_month(self.year, self.month)).contains(&self.day)
+            && self.hour < 24 && self.minute < 60 && self.second < 60
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        days_from_civil(self.year, self.month, self.day) * 86_400
+            + u64::from(self.hour) * 3600 + u64::from(self.minute) * 60 + u64::from(self.second)
+    }
+}
+
+// ISO 8601
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date (years start in March so February comes last).
+fn days_from_civil(year: u16, month: u8, day: u8) -> u64 {
+    let year = u64::from(year) - u64::from(month <= 2);
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month_from_march = (u64::from(month) + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + u64::from(day).saturating_sub(1);
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    (era * 146_097 + day_of_era).saturating_sub(719_468) // dates before 1970 become 1970-01-01
+}
+
+fn civil_from_days(days: u64) -> (u16, u8, u8) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u8;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 } as u8;
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    (year as u16, month, day)
+}
+
+unsafe fn read_register(register: u8) -> u8 {
+    Port::<u8>::new(INDEX).write(register & 0x7f);
+    Port::<u8>::new(DATA).read()
+}
+
+#[derive(PartialEq, Eq)]
+struct Registers([u8; 7]); // seconds, minutes, hours, day, month, year, century
+
+unsafe fn read_registers(century: Option<u8>) -> Registers {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {}
+    let [second, minute, hour, day, month, year] = [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(|register| read_register(register));
+    let century = century.map_or(0, |register| read_register(register));
+    Registers([second, minute, hour, day, month, year, century])
+}
+
+// None when the FADT says there is no century register.
+fn century_register() -> Option<u8> {
+    match Fadt::get() {
+        Some(fadt) if fadt.century == 0 => None,
+        Some(fadt) => Some(fadt.century),
+        None => Some(DEFAULT_CENTURY),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+// Current date of the RTC, with a resolution of one second. The RTC is not trusted: a date out of range
+// is an error. Reads the century register the FADT gives once `acpi::init` ran, DEFAULT_CENTURY before.
+pub fn read() -> Result<DateTime, RtcError> {
+    let century = century_register();
+    let (registers, status) = interrupts::without_interrupts(|| unsafe {
+        // an update may start right after the flag was checked: read until twice the same values
+        let mut registers = read_registers(century);
+        loop {
+            let again = read_registers(century);
+            if again == registers {
+                break;
+            }
+            registers = again;
+        }
+        (registers, read_register(STATUS_B))
+    });
+    let [second, minute, hour, day, month, year, century] = registers.0;
+
+    let pm = hour & HOUR_PM != 0;
+    let decode = |value: u8| if status & BINARY != 0 { value } else { from_bcd(value) };
+    let mut hour = decode(hour & !HOUR_PM);
+    if status & HOURS_24 == 0 {
+        hour %= 12; // 12 AM is midnight
+        if pm {
+            hour += 12;
+        }
+    }
+    let year = u16::from(decode(year));
+    let century = u16::from(decode(century));
+    let year = match century {
+        19..=99 => century * 100 + year,
+        _ => 2000 + year, // no century register
+    };
+    let date = DateTime { year, month: decode(month), day: decode(day), hour, minute: decode(minute), second: decode(second) };
+    if !date.is_valid() {
+        return Err(RtcError::InvalidDate(date));
+    }
+    Ok(date)
+}
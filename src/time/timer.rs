@@ -0,0 +1,296 @@
+/*
+
+Timer wheel
+-----------
+
+Timers expire on timer interrupts: a delay is rounded up to whole ticks of the PIT. The wheel has LEVELS levels
+of SLOTS slots, a timer goes to the level whose slots cover its remaining ticks (64, 64², ...) and moves
+down a level each time the slots of its level wrap (cascading), so adding, cancelling and expiring are O(1)
+apart from short slot lists. Timers due after more than SLOTS^LEVELS ticks wait in the last level and
+are cascaded again until due.
+
+Timers live in a fixed pool, so the interrupt handler never allocates. Callbacks run in the timer interrupt
+with interrupts disabled: like IRQ handlers they must not block or allocate, but they may start or cancel timers.
+The pending delays are not adjusted when `time::set_frequency` changes the length of a tick.
+*/
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use super::{Duration, Instant};
+
+pub const MAX_TIMERS: usize = 256;
+
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 4;
+const MAX_DELTA: u64 = (1 << (SLOT_BITS * LEVELS as u32)) - 1; // ticks covered by the wheel
+
+pub type TimerCallback = fn(usize); // called with the argument given when the timer was started
+
+#[derive(Debug)]
+pub enum TimerError {
+    PoolFull, // MAX_TIMERS timers already pending
+    ZeroPeriod,
+}
+
+// Handle of a started timer, stale once a one-shot timer expired or any timer was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    index: u16,
+    generation: u32,
+}
+
+enum Action {
+    Call(TimerCallback, usize),
+    Wake(Waker),
+}
+
+struct Timer {
+    generation: u32,
+    expiry: u64, // tick of the wheel
+    period: u64, // ticks, 0 for a one-shot timer
+    action: Action,
+    position: (usize, usize), // level and slot it is linked in
+    next: Option<u16>,
+}
+
+struct Wheel {
+    now: u64, // ticks seen by the wheel
+    timers: [Option<Timer>; MAX_TIMERS],
+    generations: [u32; MAX_TIMERS], // of the next timer in each entry of the pool
+    slots: [[Option<u16>; SLOTS]; LEVELS], // heads of the timer lists
+    due: Option<u16>, // expired timers whose actions are still to run
+}
+
+// Taken with interrupts disabled.
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new());
+
+impl Wheel {
+    const fn new() -> Self {
+        const NO_TIMER: Option<Timer> = None;
+        Wheel {
+            now: 0,
+            timers: [NO_TIMER; MAX_TIMERS],
+            generations: [0; MAX_TIMERS],
+            slots: [[None; SLOTS]; LEVELS],
+            due: None,
+        }
+    }
+
+    fn start(&mut self, ticks: u64, period: u64, action: Action) -> Result<TimerId, TimerError> {
+        let index = self.timers.iter().position(Option::is_none).ok_or(TimerError::PoolFull)?;
+        let generation = self.generations[index];
+        self.generations[index] = generation.wrapping_add(1);
+        self.timers[index] = Some(Timer {
+            generation,
+            expiry: self.now + ticks.max(1), // the slot of the current tick already ran
+            period,
+            action,
+            position: (0, 0),
+            next: None,
+        });
+        self.link(index as u16);
+        Ok(TimerId { index: index as u16, generation })
+    }
+
+    fn get_mut(&mut self, id: TimerId) -> Option<&mut Timer> {
+        self.timers[usize::from(id.index)].as_mut().filter(|timer| timer.generation == id.generation)
+    }
+
+    fn timer_mut(&mut self, index: u16) -> &mut Timer {
+        self.timers[usize::from(index)].as_mut().expect("free timer in the wheel")
+    }
+
+    // Put the timer in the slot of the lowest level covering its remaining ticks.
+    fn link(&mut self, index: u16) {
+        let now = self.now;
+        let timer = self.timer_mut(index);
+        let delta = timer.expiry.saturating_sub(now).min(MAX_DELTA);
+        let level = (0..LEVELS).find(|&level| delta < 1 << (SLOT_BITS * (level as u32 + 1))).unwrap_or(LEVELS - 1);
+        let slot = ((now + delta) >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+        timer.position = (level, slot);
+        let head = self.slots[level][slot].replace(index);
+        self.timer_mut(index).next = head;
+    }
+
+    fn cancel(&mut self, id: TimerId) -> Option<Timer> {
+        self.get_mut(id)?;
+        if !self.remove_from_due(id.index) {
+            let (level, slot) = self.timer_mut(id.index).position;
+            let mut head = self.slots[level][slot];
+            self.remove(&mut head, id.index);
+            self.slots[level][slot] = head;
+        }
+        self.timers[usize::from(id.index)].take()
+    }
+
+    // Expired but its action did not run yet.
+    fn remove_from_due(&mut self, index: u16) -> bool {
+        let mut head = self.due;
+        let found = self.remove(&mut head, index);
+        self.due = head;
+        found
+    }
+
+    fn remove(&mut self, head: &mut Option<u16>, index: u16) -> bool {
+        let mut previous: Option<u16> = None;
+        let mut next = *head;
+        while let Some(current) = next {
+            next = self.timer_mut(current).next;
+            if current == index {
+                match previous {
+                    Some(previous) => self.timer_mut(previous).next = next,
+                    None => *head = next,
+                }
+                self.timer_mut(index).next = None;
+                return true;
+            }
+            previous = Some(current);
+        }
+        false
+    }
+
+    // One tick: cascade the levels that wrapped, highest first, then move the current slot to `due`.
+    fn advance(&mut self) {
+        self.now += 1;
+        let now = self.now;
+        for level in (1..LEVELS).rev() {
+            if now & ((1 << (SLOT_BITS * level as u32)) - 1) != 0 {
+                continue;
+            }
+            let slot = (now >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+            let mut next = self.slots[level][slot].take();
+            while let Some(index) = next {
+                next = self.timer_mut(index).next.take();
+                self.link(index);
+            }
+        }
+        let mut next = self.slots[0][now as usize % SLOTS].take();
+        while let Some(index) = next {
+            next = self.timer_mut(index).next.take();
+            if self.timer_mut(index).expiry > now {
+                self.link(index); // due after the wheel wrapped
+            } else {
+                let due = self.due.replace(index);
+                self.timer_mut(index).next = due;
+            }
+        }
+    }
+
+    // Pop an expired timer: re-arm a periodic one, free a one-shot one.
+    fn pop_due(&mut self) -> Option<Action> {
+        let index = self.due?;
+        let now = self.now;
+        self.due = self.timer_mut(index).next.take();
+        let timer = self.timer_mut(index);
+        if timer.period == 0 {
+            return self.timers[usize::from(index)].take().map(|timer| timer.action);
+        }
+        timer.expiry = now + timer.period;
+        let action = match timer.action {
+            Action::Call(callback, argument) => Action::Call(callback, argument),
+            Action::Wake(ref waker) => Action::Wake(waker.clone()),
+        };
+        self.link(index);
+        Some(action)
+    }
+}
+
+// Run `callback(argument)` once, `delay` from now.
+pub fn start_oneshot(delay: Duration, callback: TimerCallback, argument: usize) -> Result<TimerId, TimerError> {
+    let ticks = to_ticks(delay);
+    with_wheel(|wheel| wheel.start(ticks, 0, Action::Call(callback, argument)))
+}
+
+// Run `callback(argument)` every `period` until the timer is cancelled.
+pub fn start_periodic(period: Duration, callback: TimerCallback, argument: usize) -> Result<TimerId, TimerError> {
+    if period.is_zero() {
+        return Err(TimerError::ZeroPeriod);
+    }
+    let ticks = to_ticks(period);
+    with_wheel(|wheel| wheel.start(ticks, ticks, Action::Call(callback, argument)))
+}
+
+// False if the timer already expired or was cancelled.
+pub fn cancel(id: TimerId) -> bool {
+    with_wheel(|wheel| wheel.cancel(id)).is_some() // a waker is dropped outside of the lock
+}
+
+// Timers waiting to expire.
+pub fn pending() -> usize {
+    with_wheel(|wheel| wheel.timers.iter().filter(|timer| timer.is_some()).count())
+}
+
+// Called by `time::tick` in the timer interrupt.
+pub(super) fn tick() {
+    WHEEL.lock().advance();
+    loop {
+        let action = WHEEL.lock().pop_due(); // not held while the action runs
+        match action {
+            Some(Action::Call(callback, argument)) => callback(argument),
+            Some(Action::Wake(waker)) => waker.wake(),
+            None => break,
+        }
+    }
+}
+
+// Ticks of the current frequency covering `duration`, rounded up.
+fn to_ticks(duration: Duration) -> u64 {
+    let tick = u128::from(super::tick_nanos().max(1));
+    ((duration.as_nanos() + tick - 1) / tick).min(u128::from(u64::MAX)) as u64
+}
+
+fn with_wheel<R>(f: impl FnOnce(&mut Wheel) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut WHEEL.lock()))
+}
+
+// Wait for `duration` without blocking the executor.
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep { deadline: Instant::now() + duration, timer: None }
+}
+
+pub struct Sleep {
+    deadline: Instant,
+    timer: Option<TimerId>, // wakes the task at the deadline
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            if let Some(timer) = self.timer.take() {
+                cancel(timer);
+            }
+            return Poll::Ready(());
+        }
+        let this = &mut *self;
+        let waker = cx.waker().clone();
+        let ticks = to_ticks(this.deadline.duration_since(now));
+        let mut previous = None; // dropped outside of the lock
+        let started = with_wheel(|wheel| {
+            if let Some(timer) = this.timer.and_then(|timer| wheel.get_mut(timer)) {
+                previous = Some(core::mem::replace(&mut timer.action, Action::Wake(waker))); // maybe polled by another task
+                return Ok(());
+            }
+            wheel.start(ticks, 0, Action::Wake(waker)).map(|timer| this.timer = Some(timer))
+        });
+        drop(previous);
+        if started.is_err() {
+            cx.waker().wake_by_ref(); // no timer left, poll again
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer {
+            cancel(timer);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use core::arch::{asm, global_asm};
+use x86_64::{
+    structures::{
+        idt::InterruptStackFrameValue,
+        paging::{
+            mapper::{MapToError, UnmapError}, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+            PhysFrame, Size4KiB,
+        },
+    },
+    VirtAddr,
+};
+use crate::interrupts::exceptions::{self, ExceptionContext};
+use crate::{gdt, println, serial_println, thread};
+
+// Virtual address range given to user code. Uses its own level 4 entry (32),
+// so every page table created for it can be made accessible from ring 3.
+pub const USER_SPACE_START: u64 = 0x0000_1000_0000_0000;
+pub const USER_SPACE_END: u64 = 0x0000_1080_0000_0000; // 512 GiB
+pub const USER_STACK_TOP: u64 = USER_SPACE_END;
+
+// True if [start, start + len) is inside user space.
+pub fn is_user_range(start: u64, len: u64) -> bool {
+    match start.checked_add(len) {
+        Some(end) => start >= USER_SPACE_START && end <= USER_SPACE_END,
+        None => false,
+    }
+}
+
+// Map fresh frames over [start, start + size) with USER_ACCESSIBLE added to `flags`.
+// On error the pages mapped so far stay mapped, see `unmap_user_region`.
+pub fn map_user_region(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), MapToError<Size4KiB>> {
+    assert!(size > 0 && is_user_range(start.as_u64(), size), "region outside of user space");
+    let page_range = {
+        let start_page = Page::containing_address(start);
+        let end_page = Page::containing_address(start + size - 1u64); // inclusive
+        Page::range_inclusive(start_page, end_page)
+    };
+
+    for page in page_range {
+        let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        if let Err(error) = unsafe { map_user_page(page, frame, flags, mapper, frame_allocator) } {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+// Map one page with USER_ACCESSIBLE added to `flags`.
+// Unsafe because the frame must not be in use elsewhere.
+pub unsafe fn map_user_page(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    // Parent tables are shared by every user page, the leaf entry restricts the access
+    let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    mapper.map_to_with_table_flags(page, frame, flags, parent_flags, frame_allocator)?.flush();
+    Ok(())
+}
+
+// Unmap the pages of [start, start + size) that are mapped and give their frames back, holes are skipped.
+// Unsafe because nothing may still use the pages.
+pub unsafe fn unmap_user_region(
+    start: VirtAddr,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    assert!(size > 0 && is_user_range(start.as_u64(), size), "region outside of user space");
+    let start_page = Page::<Size4KiB>::containing_address(start);
+    let end_page = Page::containing_address(start + size - 1u64); // inclusive
+    for page in Page::range_inclusive(start_page, end_page) {
+        match mapper.unmap(page) {
+            Ok((frame, flush)) => {
+                flush.flush();
+                frame_deallocator.deallocate_frame(frame);
+            }
+            Err(UnmapError::PageNotMapped) => {}
+            Err(error) => panic!("unmapping user page {:?} failed: {:?}", page, error),
+        }
+    }
+}
+
+// Map a zeroed stack of `size` bytes ending at USER_STACK_TOP and return its top.
+pub fn map_user_stack(
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let stack_bottom = VirtAddr::new(USER_STACK_TOP - size);
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    map_user_region(stack_bottom, size, flags, mapper, frame_allocator)?;
+    unsafe { core::ptr::write_bytes(stack_bottom.as_mut_ptr::<u8>(), 0, size as usize) };
+    Ok(VirtAddr::new(USER_STACK_TOP))
+}
+
+// Drop the current thread to ring 3 at `entry` with the given stack. Never returns:
+// the thread only comes back to the kernel through interrupts and syscalls.
+// Unsafe because `entry` and `stack_top` must be mapped user accessible.
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack_top: VirtAddr) -> ! {
+    let (code_selector, data_selector) = gdt::user_selectors();
+    let code_selector = u64::from(code_selector.0);
+    let data_selector = u64::from(data_selector.0);
+
+    asm!(
+        "mov ds, {data:x}",
+        "mov es, {data:x}",
+        "push {data}",   // ss
+        "push {stack}",  // rsp
+        "push {rflags}", // rflags
+        "push {code}",   // cs
+        "push {entry}",  // rip
+        "iretq",
+        data = in(reg) data_selector,
+        stack = in(reg) stack_top.as_u64(),
+        rflags = in(reg) 0x202u64, // interrupts enabled so the timer can preempt user code
+        code = in(reg) code_selector,
+        entry = in(reg) entry.as_u64(),
+        options(noreturn),
+    );
+}
+
+// The only kernel code allowed to fault on user pages: a page fault on `rusty_copy_from_user_access` kills
+// the thread like a fault in ring 3, see `is_user_copy`. No lock is held there.
+global_asm!(
+    ".global rusty_copy_from_user",
+    "rusty_copy_from_user:", // (destination: rdi, source: rsi, length: rdx)
+    "mov rcx, rdx",
+    ".global rusty_copy_from_user_access",
+    "rusty_copy_from_user_access:",
+    "rep movsb",
+    "ret",
+);
+
+extern "C" {
+    fn rusty_copy_from_user(destination: *mut u8, source: *const u8, length: usize);
+    fn rusty_copy_from_user_access();
+}
+
+// Copy user memory at `source` to `buffer`, for syscalls. An unmapped page kills the current thread.
+// Panics if the range is outside of user space, check it with `is_user_range` first.
+pub fn copy_from_user(source: u64, buffer: &mut [u8]) {
+    assert!(is_user_range(source, buffer.len() as u64), "copy from outside of user space");
+    unsafe { rusty_copy_from_user(buffer.as_mut_ptr(), source as *const u8, buffer.len()) };
+}
+
+// The kernel faulted at `rip` while reading user memory for `copy_from_user`.
+pub fn is_user_copy(rip: u64) -> bool {
+    rip == rusty_copy_from_user_access as *const () as u64
+}
+
+// Interrupted code was running in ring 3.
+pub fn from_user_mode(stack_frame: &InterruptStackFrameValue) -> bool {
+    stack_frame.code_segment & 0b11 == 3
+}
+
+// Report a fault caused by user code and terminate its thread instead of halting the kernel.
+pub fn kill_faulting_thread(context: &ExceptionContext) -> ! {
+    exceptions::report(context);
+    let id = thread::current().as_u64();
+    println!("USER FAULT: thread {} killed", id);
+    serial_println!("USER FAULT: thread {} killed", id);
+    thread::exit();
+}
@@ -0,0 +1,394 @@
+/*
+
+Virtio over PCI
+---------------
+
+Virtio devices (vendor 0x1af4) are driven through the same steps whatever their type: reset, acknowledge,
+negotiate the features, set up the virtqueues (see `queue`), then DRIVER_OK. Two PCI transports give access
+to the registers:
+- modern (virtio 1.0): vendor capabilities point to the common, notification and device configuration
+  structures in memory BARs, the device needs the VERSION_1 feature;
+- legacy (virtio 0.9.5): every register is in the I/O BAR 0, features are 32 bits, the queue is one
+  page aligned area given by its page number, and the device configuration moves by 4 bytes while MSI-X is on.
+QEMU's transitional devices offer both, `Transport::new` prefers the modern one.
+
+The used ring of a queue raises an MSI-X message when an MSI-X entry is assigned to the queue.
+The legacy INTx interrupt is not used: without MSI-X, drivers poll the used ring.
+*/
+
+pub mod queue;
+
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory;
+use crate::pci::{msi, Bar, PciDevice, CAPABILITY_VENDOR};
+use queue::Virtqueue;
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+// Device status bits
+pub const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+pub const STATUS_DRIVER: u8 = 1 << 1;
+pub const STATUS_DRIVER_OK: u8 = 1 << 2;
+pub const STATUS_FEATURES_OK: u8 = 1 << 3;
+pub const STATUS_FAILED: u8 = 1 << 7;
+
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+pub const NO_VECTOR: u16 = 0xffff; // no MSI-X entry for the queue or the configuration changes
+
+const MAX_QUEUE_SIZE: u16 = 256; // modern devices accept smaller queues than their maximum
+
+// Legacy registers, offsets in the I/O BAR
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08; // page number
+const LEGACY_QUEUE_SIZE: u16 = 0x0c;
+const LEGACY_QUEUE_SELECT: u16 = 0x0e;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_CONFIG_VECTOR: u16 = 0x14; // with MSI-X only
+const LEGACY_QUEUE_VECTOR: u16 = 0x16; // with MSI-X only
+const LEGACY_CONFIG: u16 = 0x14;
+const LEGACY_CONFIG_MSI_X: u16 = 0x18;
+
+// Vendor capability of a modern device
+const CAPABILITY_TYPE: u16 = 3;
+const CAPABILITY_BAR: u16 = 4;
+const CAPABILITY_OFFSET: u16 = 8;
+const CAPABILITY_LENGTH: u16 = 12;
+const CAPABILITY_NOTIFY_MULTIPLIER: u16 = 16;
+const TYPE_COMMON: u8 = 1;
+const TYPE_NOTIFY: u8 = 2;
+const TYPE_DEVICE: u8 = 4;
+
+// Common configuration structure
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0c;
+const COMMON_CONFIG_VECTOR: u64 = 0x10;
+const COMMON_STATUS: u64 = 0x14;
+const COMMON_CONFIG_GENERATION: u64 = 0x15;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_VECTOR: u64 = 0x1a;
+const COMMON_QUEUE_ENABLE: u64 = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFFSET: u64 = 0x1e;
+const COMMON_QUEUE_DESCRIPTORS: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+#[derive(Debug)]
+pub enum VirtioError {
+    NoTransport, // neither the capabilities nor the I/O BAR
+    Mapping(MapToError<Size4KiB>),
+    FeaturesRejected,
+    NoQueue(u16),
+    OutOfMemory,
+    NoVector(u16), // the device refused the MSI-X entry of this queue
+}
+
+impl From<MapToError<Size4KiB>> for VirtioError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        VirtioError::Mapping(error)
+    }
+}
+
+pub enum Transport {
+    Legacy { device: PciDevice, base: u16 },
+    Modern {
+        common: VirtAddr,
+        notify: VirtAddr,
+        notify_multiplier: u32,
+        config: Option<VirtAddr>,
+        mappings: [Option<(VirtAddr, u64)>; 3], // the three structures and their size, for `unmap`
+    },
+}
+
+impl Transport {
+    // Modern transport if the device has one, legacy otherwise.
+    pub fn new(device: &PciDevice) -> Result<Transport, VirtioError> {
+        match Transport::modern(device)? {
+            Some(transport) => Ok(transport),
+            None => Transport::legacy(device).ok_or(VirtioError::NoTransport),
+        }
+    }
+
+    pub fn legacy(device: &PciDevice) -> Option<Transport> {
+        match device.bars[0] {
+            Some(Bar::Io { port, .. }) => Some(Transport::Legacy { device: *device, base: port }),
+            _ => None,
+        }
+    }
+
+    // None without the common and notification capabilities. Maps the structures it uses.
+    pub fn modern(device: &PciDevice) -> Result<Option<Transport>, VirtioError> {
+        let (mut common, mut notify, mut config) = (None, None, None);
+        for capability in device.capabilities().filter(|capability| capability.id == CAPABILITY_VENDOR) {
+            let kind = device.read_u8(capability.offset + CAPABILITY_TYPE);
+            let slot = match kind {
+                TYPE_COMMON => &mut common,
+                TYPE_NOTIFY => &mut notify,
+                TYPE_DEVICE => &mut config,
+                _ => continue,
+            };
+            if slot.is_some() {
+                continue; // the first one of each type is the preferred one
+            }
+            let bar = match device.bars.get(usize::from(device.read_u8(capability.offset + CAPABILITY_BAR))) {
+                Some(&Some(Bar::Memory { address, .. })) => address,
+                _ => continue,
+            };
+            let offset = u64::from(device.read_u32(capability.offset + CAPABILITY_OFFSET));
+            let length = u64::from(device.read_u32(capability.offset + CAPABILITY_LENGTH));
+            let multiplier = match kind {
+                TYPE_NOTIFY => device.read_u32(capability.offset + CAPABILITY_NOTIFY_MULTIPLIER),
+                _ => 0,
+            };
+            *slot = Some((PhysAddr::new(bar + offset), length, multiplier));
+        }
+        let ((common, common_length, _), (notify, notify_length, notify_multiplier)) = match (common, notify) {
+            (Some(common), Some(notify)) => (common, notify),
+            _ => return Ok(None),
+        };
+        let regions = [Some((common, common_length)), Some((notify, notify_length)), config.map(|(address, length, _)| (address, length))];
+        let mut mappings = [None; 3];
+        for (index, region) in regions.iter().enumerate() {
+            if let Some((address, length)) = *region {
+                match memory::map_mmio(address, length) {
+                    Ok(mapped) => mappings[index] = Some((mapped, length)),
+                    Err(error) => {
+                        unsafe { unmap_all(&mappings) };
+                        return Err(error.into());
+                    }
+                }
+            }
+        }
+        let mapped = |index: usize| mappings[index].map(|(address, _)| address);
+        Ok(Some(Transport::Modern {
+            common: mapped(0).unwrap(),
+            notify: mapped(1).unwrap(),
+            notify_multiplier,
+            config: mapped(2),
+            mappings,
+        }))
+    }
+
+    // Unmap the structures of a modern transport, e.g. when the driver gives up on the device.
+    pub fn unmap(self) {
+        if let Transport::Modern { mappings, .. } = self {
+            unsafe { unmap_all(&mappings) };
+        }
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self, Transport::Modern { .. })
+    }
+
+    pub fn status(&self) -> u8 {
+        match self {
+            Transport::Legacy { base, .. } => io_read::<u8>(*base, LEGACY_STATUS),
+            Transport::Modern { common, .. } => mmio_read::<u8>(*common, COMMON_STATUS),
+        }
+    }
+
+    pub fn set_status(&self, status: u8) {
+        match self {
+            Transport::Legacy { base, .. } => io_write(*base, LEGACY_STATUS, status),
+            Transport::Modern { common, .. } => mmio_write(*common, COMMON_STATUS, status),
+        }
+    }
+
+    fn add_status(&self, bits: u8) {
+        self.set_status(self.status() | bits);
+    }
+
+    // The device forgets the features and queues and stops using the memory it was given.
+    pub fn reset(&self) {
+        self.set_status(0);
+        while self.status() != 0 { // a modern device may take time
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn device_features(&self) -> u64 {
+        match self {
+            Transport::Legacy { base, .. } => u64::from(io_read::<u32>(*base, LEGACY_DEVICE_FEATURES)),
+            Transport::Modern { common, .. } => {
+                let half = |select: u32| {
+                    mmio_write(*common, COMMON_DEVICE_FEATURE_SELECT, select);
+                    u64::from(mmio_read::<u32>(*common, COMMON_DEVICE_FEATURE))
+                };
+                half(0) | half(1) << 32
+            }
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match self {
+            Transport::Legacy { base, .. } => io_write(*base, LEGACY_DRIVER_FEATURES, features as u32),
+            Transport::Modern { common, .. } => {
+                for half in 0..2u64 {
+                    mmio_write(*common, COMMON_DRIVER_FEATURE_SELECT, half as u32);
+                    mmio_write(*common, COMMON_DRIVER_FEATURE, (features >> (32 * half)) as u32);
+                }
+            }
+        }
+    }
+
+    // Reset the device and agree on the `wanted` features it has, returned. The queues are set up next,
+    // then `driver_ok`. VERSION_1 is always wanted on the modern transport.
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, VirtioError> {
+        self.reset();
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+        let wanted = if self.is_modern() { wanted | FEATURE_VERSION_1 } else { wanted & 0xffff_ffff };
+        let features = self.device_features() & wanted;
+        self.set_driver_features(features);
+        if self.is_modern() {
+            self.add_status(STATUS_FEATURES_OK);
+            if features & FEATURE_VERSION_1 == 0 || self.status() & STATUS_FEATURES_OK == 0 {
+                self.set_status(STATUS_FAILED);
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+        Ok(features)
+    }
+
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    pub fn fail(&self) {
+        self.add_status(STATUS_FAILED);
+    }
+
+    // Size a queue must have: set by legacy devices, the maximum or MAX_QUEUE_SIZE on modern ones.
+    // 0 if the queue does not exist.
+    pub fn queue_size(&self, index: u16) -> u16 {
+        match self {
+            Transport::Legacy { base, .. } => {
+                io_write(*base, LEGACY_QUEUE_SELECT, index);
+                io_read::<u16>(*base, LEGACY_QUEUE_SIZE)
+            }
+            Transport::Modern { common, .. } => {
+                mmio_write(*common, COMMON_QUEUE_SELECT, index);
+                mmio_read::<u16>(*common, COMMON_QUEUE_SIZE).min(MAX_QUEUE_SIZE)
+            }
+        }
+    }
+
+    // Give the queue to the device, its used ring raising MSI-X entry `vector` (or NO_VECTOR).
+    // MSI-X must be enabled first on a legacy device.
+    pub fn setup_queue(&self, queue: &mut Virtqueue, vector: u16) -> Result<(), VirtioError> {
+        let index = queue.index();
+        match self {
+            Transport::Legacy { base, .. } => {
+                io_write(*base, LEGACY_QUEUE_SELECT, index);
+                if self.has_msi_x() {
+                    io_write(*base, LEGACY_QUEUE_VECTOR, vector);
+                    if io_read::<u16>(*base, LEGACY_QUEUE_VECTOR) != vector {
+                        return Err(VirtioError::NoVector(index));
+                    }
+                }
+                io_write(*base, LEGACY_QUEUE_ADDRESS, (queue.descriptor_area().as_u64() >> 12) as u32);
+            }
+            Transport::Modern { common, notify_multiplier, .. } => {
+                mmio_write(*common, COMMON_QUEUE_SELECT, index);
+                mmio_write(*common, COMMON_QUEUE_SIZE, queue.size());
+                mmio_write_u64(*common, COMMON_QUEUE_DESCRIPTORS, queue.descriptor_area().as_u64());
+                mmio_write_u64(*common, COMMON_QUEUE_DRIVER, queue.driver_area().as_u64());
+                mmio_write_u64(*common, COMMON_QUEUE_DEVICE, queue.device_area().as_u64());
+                mmio_write(*common, COMMON_QUEUE_VECTOR, vector);
+                if mmio_read::<u16>(*common, COMMON_QUEUE_VECTOR) != vector {
+                    return Err(VirtioError::NoVector(index));
+                }
+                let offset = mmio_read::<u16>(*common, COMMON_QUEUE_NOTIFY_OFFSET);
+                queue.set_notify_offset(u64::from(offset) * u64::from(*notify_multiplier));
+                mmio_write(*common, COMMON_QUEUE_ENABLE, 1u16);
+            }
+        }
+        Ok(())
+    }
+
+    // No MSI-X message on configuration changes.
+    pub fn disable_config_vector(&self) {
+        match self {
+            Transport::Legacy { base, .. } if self.has_msi_x() => io_write(*base, LEGACY_CONFIG_VECTOR, NO_VECTOR),
+            Transport::Legacy { .. } => {}
+            Transport::Modern { common, .. } => mmio_write(*common, COMMON_CONFIG_VECTOR, NO_VECTOR),
+        }
+    }
+
+    // Tell the device new buffers are in the available ring of `queue`.
+    pub fn notify(&self, queue: &Virtqueue) {
+        match self {
+            Transport::Legacy { base, .. } => io_write(*base, LEGACY_QUEUE_NOTIFY, queue.index()),
+            Transport::Modern { notify, .. } => mmio_write(*notify, queue.notify_offset(), queue.index()),
+        }
+    }
+
+    pub fn read_config_u32(&self, offset: u16) -> u32 {
+        match self {
+            Transport::Legacy { base, .. } => io_read::<u32>(*base, self.legacy_config() + offset),
+            Transport::Modern { config, .. } => config.map_or(0, |config| mmio_read::<u32>(config, u64::from(offset))),
+        }
+    }
+
+    // Both halves from the same configuration: a modern device counts its changes in a generation.
+    pub fn read_config_u64(&self, offset: u16) -> u64 {
+        let read = || u64::from(self.read_config_u32(offset)) | u64::from(self.read_config_u32(offset + 4)) << 32;
+        match self {
+            Transport::Legacy { .. } => read(),
+            Transport::Modern { common, .. } => loop {
+                let generation = mmio_read::<u8>(*common, COMMON_CONFIG_GENERATION);
+                let value = read();
+                if mmio_read::<u8>(*common, COMMON_CONFIG_GENERATION) == generation {
+                    break value;
+                }
+            },
+        }
+    }
+
+    fn has_msi_x(&self) -> bool {
+        match self {
+            Transport::Legacy { device, .. } => msi::is_msi_x_enabled(device),
+            Transport::Modern { .. } => true,
+        }
+    }
+
+    fn legacy_config(&self) -> u16 {
+        if self.has_msi_x() { LEGACY_CONFIG_MSI_X } else { LEGACY_CONFIG }
+    }
+}
+
+// Unsafe because nothing may still use the mapped structures.
+unsafe fn unmap_all(mappings: &[Option<(VirtAddr, u64)>]) {
+    for &(address, length) in mappings.iter().rev().flatten() { // last first: the address range is given back
+        memory::unmap_mmio(address, length);
+    }
+}
+
+fn io_read<T: PortRead>(base: u16, register: u16) -> T {
+    unsafe { Port::<T>::new(base + register).read() }
+}
+
+fn io_write<T: PortWrite>(base: u16, register: u16, value: T) {
+    unsafe { Port::<T>::new(base + register).write(value) };
+}
+
+fn mmio_read<T>(base: VirtAddr, register: u64) -> T {
+    unsafe { (base + register).as_ptr::<T>().read_volatile() }
+}
+
+fn mmio_write<T>(base: VirtAddr, register: u64, value: T) {
+    unsafe { (base + register).as_mut_ptr::<T>().write_volatile(value) };
+}
+
+// As two 32-bit writes, low half first: devices need not accept 64-bit accesses.
+fn mmio_write_u64(base: VirtAddr, register: u64, value: u64) {
+    mmio_write(base, register, value as u32);
+    mmio_write(base, register + 4, (value >> 32) as u32);
+}
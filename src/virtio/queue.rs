@@ -0,0 +1,214 @@
+/*
+
+Split virtqueues
+----------------
+
+A queue is three areas shared with the device, in one physically contiguous allocation laid out as the
+legacy transport wants it (the used ring on the next page boundary):
+- the descriptor table: buffers by physical address and length, chained with NEXT, WRITE when the
+  device writes them;
+- the available ring: heads of the chains given to the device, written by the driver;
+- the used ring: heads of the chains the device is done with and the bytes it wrote, written by the device.
+Free descriptors are chained through their `next` field. The driver's own copies of the ring indexes
+(`available_index`, `last_used`) wrap at 2^16 like the device's.
+*/
+
+use core::sync::atomic::{fence, Ordering};
+use x86_64::PhysAddr;
+use super::VirtioError;
+use crate::memory::{self, phys_to_virt};
+
+const DESCRIPTOR_SIZE: u64 = 16;
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+const USED_ELEMENT_SIZE: u64 = 8;
+const RING_HEADER: u64 = 4; // flags and index
+const RING_ALIGN: u64 = 4096;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+// Part of a chain, in physical memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub length: u32,
+    pub writable: bool, // by the device
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    descriptors: PhysAddr,
+    available: PhysAddr,
+    used: PhysAddr,
+    free_head: u16,
+    free_count: u16,
+    available_index: u16,
+    last_used: u16,
+    notify_offset: u64, // of the notification register, modern transport only
+}
+
+impl Virtqueue {
+    // `size` as given by `Transport::queue_size`.
+    pub fn new(index: u16, size: u16) -> Result<Virtqueue, VirtioError> {
+        if size == 0 {
+            return Err(VirtioError::NoQueue(index));
+        }
+        let (available_offset, used_offset, total) = layout(size);
+        let descriptors = memory::allocate_dma(total).ok_or(VirtioError::OutOfMemory)?;
+        let queue = Virtqueue {
+            index,
+            size,
+            descriptors,
+            available: descriptors + available_offset,
+            used: descriptors + used_offset,
+            free_head: 0,
+            free_count: size,
+            available_index: 0,
+            last_used: 0,
+            notify_offset: 0,
+        };
+        for descriptor in 0..size {
+            queue.write_descriptor(descriptor, Descriptor { address: 0, length: 0, flags: 0, next: descriptor.wrapping_add(1) });
+        }
+        Ok(queue)
+    }
+
+    // Give the rings back. Unsafe because the device must not use the queue anymore: reset it first.
+    pub unsafe fn free(self) {
+        memory::free_dma(self.descriptors, layout(self.size).2);
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn descriptor_area(&self) -> PhysAddr {
+        self.descriptors
+    }
+
+    pub fn driver_area(&self) -> PhysAddr {
+        self.available
+    }
+
+    pub fn device_area(&self) -> PhysAddr {
+        self.used
+    }
+
+    pub fn free_descriptors(&self) -> u16 {
+        self.free_count
+    }
+
+    // Head the next chain added will get, while there are free descriptors.
+    pub fn next_head(&self) -> u16 {
+        self.free_head
+    }
+
+    pub(super) fn notify_offset(&self) -> u64 {
+        self.notify_offset
+    }
+
+    pub(super) fn set_notify_offset(&mut self, offset: u64) {
+        self.notify_offset = offset;
+    }
+
+    // Chain `buffers` and make the chain available, its head returned. None, with nothing done, when there
+    // are not enough free descriptors. The device sees it once notified.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.free_count) {
+            return None;
+        }
+        let head = self.free_head;
+        let mut descriptor = head;
+        for (position, buffer) in buffers.iter().enumerate() {
+            let next = self.read_descriptor(descriptor).next; // following free one
+            let last = position + 1 == buffers.len();
+            let flags = if buffer.writable { DESCRIPTOR_WRITE } else { 0 } | if last { 0 } else { DESCRIPTOR_NEXT };
+            self.write_descriptor(descriptor, Descriptor {
+                address: buffer.address.as_u64(),
+                length: buffer.length,
+                flags,
+                next: if last { 0 } else { next },
+            });
+            if last {
+                self.free_head = next;
+            }
+            descriptor = next;
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let slot = u64::from(self.available_index % self.size);
+        unsafe { ring_pointer::<u16>(self.available, RING_HEADER + 2 * slot).write_volatile(head) };
+        fence(Ordering::SeqCst); // the device must see the entry before the index
+        self.available_index = self.available_index.wrapping_add(1);
+        unsafe { ring_pointer::<u16>(self.available, 2).write_volatile(self.available_index) };
+        fence(Ordering::SeqCst); // and the index before the notification
+        Some(head)
+    }
+
+    // Head of the next chain the device is done with and the bytes it wrote, its descriptors freed.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_index = unsafe { ring_pointer::<u16>(self.used, 2).read_volatile() };
+        if used_index == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst); // the element is read after the index
+        let element = RING_HEADER + USED_ELEMENT_SIZE * u64::from(self.last_used % self.size);
+        let head = unsafe { ring_pointer::<u32>(self.used, element).read_volatile() } as u16;
+        let length = unsafe { ring_pointer::<u32>(self.used, element + 4).read_volatile() };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let mut last = head;
+        let mut freed = 1;
+        while self.read_descriptor(last).flags & DESCRIPTOR_NEXT != 0 {
+            last = self.read_descriptor(last).next;
+            freed += 1;
+        }
+        let mut tail = self.read_descriptor(last);
+        tail.next = self.free_head;
+        self.write_descriptor(last, tail);
+        self.free_head = head;
+        self.free_count += freed;
+        Some((head, length))
+    }
+
+    fn read_descriptor(&self, descriptor: u16) -> Descriptor {
+        unsafe { self.descriptor_pointer(descriptor).read_volatile() }
+    }
+
+    fn write_descriptor(&self, descriptor: u16, value: Descriptor) {
+        unsafe { self.descriptor_pointer(descriptor).write_volatile(value) };
+    }
+
+    fn descriptor_pointer(&self, descriptor: u16) -> *mut Descriptor {
+        assert!(descriptor < self.size, "descriptor {} out of queue {}", descriptor, self.index);
+        phys_to_virt(self.descriptors + u64::from(descriptor) * DESCRIPTOR_SIZE).as_mut_ptr()
+    }
+}
+
+fn ring_pointer<T>(ring: PhysAddr, offset: u64) -> *mut T {
+    phys_to_virt(ring + offset).as_mut_ptr()
+}
+
+// Offsets of the available and used rings, and the size of the whole queue.
+fn layout(size: u16) -> (u64, u64, u64) {
+    let entries = u64::from(size);
+    let available_offset = entries * DESCRIPTOR_SIZE;
+    let used_offset = align_up(available_offset + RING_HEADER + 2 * entries + 2, RING_ALIGN);
+    (available_offset, used_offset, used_offset + RING_HEADER + USED_ELEMENT_SIZE * entries + 2)
+}
+
+const fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rusty_os::acpi::{self, fadt::Fadt, hpet::HpetTable, SYSTEM_IO};
+use rusty_os::serial_println;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::memory;
+    use x86_64::VirtAddr;
+
+    rusty_os::init();
+    unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    acpi::init().expect("ACPI initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn every_table_is_listed_with_a_valid_checksum() {
+    for table in acpi::list() {
+        serial_println!("{}", table);
+        assert!(table.checksum_ok, "{}", table);
+    }
+    for signature in [b"FACP", b"APIC", b"HPET", b"DSDT"] {
+        assert!(acpi::list().any(|table| table.header.signature == *signature));
+    }
+}
+
+#[test_case]
+fn fadt_describes_the_power_management_registers() {
+    let fadt = Fadt::get().unwrap();
+    let control = fadt.pm1a_control.unwrap();
+    assert_eq!(control.address_space, SYSTEM_IO);
+    assert!(fadt.pm1_control_length >= 2);
+    assert!(fadt.sci_interrupt != 0);
+    assert_eq!(acpi::read_header(fadt.dsdt).signature, *b"DSDT");
+}
+
+#[test_case]
+fn hpet_table_points_to_the_registers() {
+    let hpet = HpetTable::get().unwrap();
+    assert_eq!(hpet.base_address.as_u64(), 0xfed0_0000); // fixed on QEMU
+    assert!(hpet.comparators >= 3);
+}
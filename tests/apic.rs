@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rusty_os::acpi::madt::{Madt, MadtEntry};
+use rusty_os::interrupts::apic;
+use rusty_os::thread;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::{acpi, allocator};
+    use rusty_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rusty_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+    thread::init();
+    acpi::init().expect("ACPI initialization failed");
+    apic::init().expect("APIC initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_describes_the_apics() {
+    let madt = Madt::get().unwrap();
+    assert!(madt.entries().any(|entry| matches!(entry, MadtEntry::IoApic { .. })));
+    let id = apic::local_apic_id();
+    assert!(madt.entries().any(|entry| matches!(entry, MadtEntry::LocalApic { apic_id, .. } if apic_id == id)));
+}
+
+#[test_case]
+fn timer_goes_through_the_io_apic() {
+    assert!(apic::is_enabled());
+    thread::sleep(3); // only returns if timer interrupts keep coming
+}
@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rusty_os::block::{self, ata, BlockError};
+use rusty_os::pci;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::allocator;
+    use rusty_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rusty_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+    pci::init();
+    ata::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+// QEMU's -hda is the boot image, on the primary master, only read here.
+// The primary slave is the sparse scratch disk of the test-args: 129 GiB, past what LBA28 addresses.
+const SCRATCH_SECTORS: u64 = 129 * 1024 * 1024 * 1024 / ata::SECTOR_SIZE as u64;
+const LBA28_SECTORS: u64 = 1 << 28;
+
+fn pattern(length: usize, seed: u8) -> Vec<u8> {
+    (0..length).map(|index| (index as u8).wrapping_mul(7).wrapping_add(seed)).collect()
+}
+
+#[test_case]
+fn boot_disk_is_found() {
+    let disk = block::find("ata0-master").expect("no primary master");
+    let disk = disk.lock();
+    assert_eq!(disk.sector_size(), ata::SECTOR_SIZE);
+    assert!(disk.sector_count() > 0);
+}
+
+#[test_case]
+fn boot_sector_has_signature() {
+    let disk = block::find("ata0-master").unwrap();
+    let mut sector = [0; ata::SECTOR_SIZE];
+    disk.lock().read_sectors(0, &mut sector).unwrap();
+    assert_eq!(&sector[510..], &[0x55, 0xaa]);
+}
+
+#[test_case]
+fn scratch_disk_is_found() {
+    let disk = block::find("ata0-slave").expect("no primary slave, see test-args");
+    assert_eq!(disk.lock().sector_count(), SCRATCH_SECTORS);
+}
+
+// Write `sectors` sectors at `sector` of the scratch disk and read them back.
+fn write_and_read_back(sector: u64, sectors: usize, seed: u8) {
+    let disk = block::find("ata0-slave").unwrap();
+    let mut disk = disk.lock();
+    let data = pattern(sectors * ata::SECTOR_SIZE, seed);
+    disk.write_sectors(sector, &data).unwrap();
+    let mut read = vec![0; data.len()];
+    disk.read_sectors(sector, &mut read).unwrap();
+    assert!(read == data);
+}
+
+#[test_case]
+fn write_is_read_back() {
+    write_and_read_back(100, 2, 1); // LBA28 commands
+    write_and_read_back(1000, 300, 2); // more sectors than one LBA28 command takes
+}
+
+#[test_case]
+fn sectors_past_lba28_are_read_back() {
+    write_and_read_back(LBA28_SECTORS + 10, 3, 3); // LBA48 commands
+    write_and_read_back(LBA28_SECTORS - 1, 2, 4); // crosses the LBA28 limit
+    write_and_read_back(SCRATCH_SECTORS - 1, 1, 5); // last sector
+    // the same sectors modulo 2^28 are untouched, a truncated LBA would have written them
+    let disk = block::find("ata0-slave").unwrap();
+    let mut sector = vec![0; ata::SECTOR_SIZE];
+    disk.lock().read_sectors(10, &mut sector).unwrap();
+    assert!(sector.iter().all(|&byte| byte == 0));
+}
+
+#[test_case]
+fn bad_requests_are_rejected() {
+    let disk = block::find("ata0-slave").unwrap();
+    let mut disk = disk.lock();
+    let count = disk.sector_count();
+    let mut sector = [0; ata::SECTOR_SIZE];
+    assert_eq!(disk.read_sectors(count, &mut sector), Err(BlockError::OutOfRange));
+    assert_eq!(disk.read_sectors(0, &mut sector[..100]), Err(BlockError::BadBufferSize));
+}
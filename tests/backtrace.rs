@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rusty_os::backtrace::{symbols, Backtrace};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::memory;
+    use x86_64::VirtAddr;
+
+    rusty_os::init();
+    unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) }; // frames are checked with the page table
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+#[inline(never)]
+fn outer() -> Backtrace {
+    inner()
+}
+
+#[inline(never)]
+fn inner() -> Backtrace {
+    Backtrace::capture()
+}
+
+#[test_case]
+fn symbols_are_embedded() {
+    assert!(symbols::count() > 0, "tools/embed_symbols.py did not run");
+    let symbol = symbols::resolve(outer as *const () as u64).expect("outer not found");
+    assert!(symbol.name.ends_with("outer"));
+}
+
+#[test_case]
+fn capture_walks_the_callers() {
+    let backtrace = outer();
+    let names = backtrace.addresses().iter().map(|&address| symbols::resolve(address - 1).map(|s| s.name));
+    let mut names = names.flatten();
+    assert!(names.next().unwrap().ends_with("inner"));
+    assert!(names.next().unwrap().ends_with("outer"));
+}
@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rusty_os::memory::{self, buddy::{BuddyFrameAllocator, HUGE_PAGE_ORDER, MAX_ORDER}};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+static BUDDY: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rusty_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    *BUDDY.lock() = Some(unsafe { BuddyFrameAllocator::init(&boot_info.memory_map) });
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+fn free_blocks(buddy: &BuddyFrameAllocator) -> [usize; MAX_ORDER + 1] {
+    let mut blocks = [0; MAX_ORDER + 1];
+    for (order, count) in blocks.iter_mut().enumerate() {
+        *count = buddy.free_blocks(order);
+    }
+    blocks
+}
+
+#[test_case]
+fn blocks_are_aligned() {
+    let mut buddy = BUDDY.lock();
+    let buddy = buddy.as_mut().unwrap();
+    for order in 0..=4 {
+        let block = buddy.allocate(order).expect("out of memory");
+        assert!(block.is_aligned(4096u64 << order));
+        unsafe { buddy.deallocate(block, order) };
+    }
+}
+
+#[test_case]
+fn free_coalesces_buddies() {
+    let mut buddy = BUDDY.lock();
+    let buddy = buddy.as_mut().unwrap();
+    let before = free_blocks(buddy);
+    let free = buddy.free_frames();
+
+    let a = buddy.allocate(0).unwrap();
+    let b = buddy.allocate(0).unwrap();
+    let c = buddy.allocate(2).unwrap();
+    assert_eq!(buddy.free_frames(), free - 6);
+    unsafe {
+        buddy.deallocate(b, 0);
+        buddy.deallocate(c, 2);
+        buddy.deallocate(a, 0);
+    }
+    assert_eq!(buddy.free_frames(), free);
+    assert_eq!(free_blocks(buddy), before); // split blocks were merged back
+}
+
+#[test_case]
+fn huge_page_frames() {
+    let mut buddy = BUDDY.lock();
+    let buddy = buddy.as_mut().unwrap();
+    let frame: PhysFrame<Size2MiB> = buddy.allocate_frame().expect("no 2 MiB frame");
+    assert!(frame.start_address().is_aligned(2u64 * 1024 * 1024));
+    assert_eq!(BuddyFrameAllocator::order_for_size(2 * 1024 * 1024), Some(HUGE_PAGE_ORDER));
+    unsafe { buddy.deallocate_frame(frame) };
+}
@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rusty_os::{QemuExitCode, shutdown, serial_println, serial_print};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::allocator;
+    use rusty_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rusty_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    double_free();
+    serial_println!("[test did not panic]");
+    shutdown(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    shutdown(QemuExitCode::Success);
+    loop {}
+}
+
+fn double_free() {
+    serial_print!("double_free::double_free...\t");
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+        dealloc(ptr, layout); // must panic, the block is already on its free list
+    }
+}
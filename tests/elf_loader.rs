@@ -0,0 +1,177 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rusty_os::elf::{loader, ElfError, ElfFile};
+use rusty_os::memory::{self, BootInfoFrameAllocator};
+use rusty_os::{thread, usermode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::allocator;
+
+    rusty_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    thread::init();
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+const HEADERS_SIZE: u64 = 64 + 56;
+
+// Program: write(argv[0], 5); exit()
+const CODE: &[u8] = &[
+    0x48, 0x8b, 0x7c, 0x24, 0x08, // mov rdi, [rsp + 8]
+    0xbe, 0x05, 0x00, 0x00, 0x00, // mov esi, 5
+    0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, SYS_WRITE
+    0xcd, 0x80,                   // int 0x80
+    0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, SYS_EXIT
+    0xcd, 0x80,                   // int 0x80
+];
+
+// Executable with one R+X segment covering the whole file, headers included.
+fn build_elf(base: u64) -> Vec<u8> {
+    let size = HEADERS_SIZE + CODE.len() as u64;
+    let mut image = Vec::new();
+    image.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    image.extend_from_slice(&2u16.to_le_bytes()); // e_type: ET_EXEC
+    image.extend_from_slice(&0x3eu16.to_le_bytes()); // e_machine: x86_64
+    image.extend_from_slice(&1u32.to_le_bytes()); // e_version
+    image.extend_from_slice(&(base + HEADERS_SIZE).to_le_bytes()); // e_entry
+    image.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+    image.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    image.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    image.extend_from_slice(&64u16.to_le_bytes()); // e_ehsize
+    image.extend_from_slice(&56u16.to_le_bytes()); // e_phentsize
+    image.extend_from_slice(&1u16.to_le_bytes()); // e_phnum
+    image.extend_from_slice(&[0; 6]); // e_shentsize, e_shnum, e_shstrndx
+
+    image.extend_from_slice(&1u32.to_le_bytes()); // p_type: PT_LOAD
+    image.extend_from_slice(&5u32.to_le_bytes()); // p_flags: R + X
+    image.extend_from_slice(&0u64.to_le_bytes()); // p_offset
+    image.extend_from_slice(&base.to_le_bytes()); // p_vaddr
+    image.extend_from_slice(&base.to_le_bytes()); // p_paddr
+    image.extend_from_slice(&size.to_le_bytes()); // p_filesz
+    image.extend_from_slice(&(size + 4096).to_le_bytes()); // p_memsz, with some .bss
+    image.extend_from_slice(&4096u64.to_le_bytes()); // p_align
+
+    image.extend_from_slice(CODE);
+    image
+}
+
+#[test_case]
+fn rejects_malformed_images() {
+    let image = build_elf(usermode::USER_SPACE_START);
+    assert!(matches!(ElfFile::parse(&image[..10]), Err(ElfError::TooShort)));
+
+    let mut bad_magic = image.clone();
+    bad_magic[1] = b'X';
+    assert!(matches!(ElfFile::parse(&bad_magic), Err(ElfError::BadMagic)));
+
+    let mut elf32 = image.clone();
+    elf32[4] = 1;
+    assert!(matches!(ElfFile::parse(&elf32), Err(ElfError::NotElf64)));
+
+    let truncated = &image[..(HEADERS_SIZE as usize + 4)];
+    assert!(matches!(ElfFile::parse(truncated), Err(ElfError::SegmentOutOfBounds)));
+
+    let kernel_space = build_elf(0xffff_8000_0000_0000);
+    assert!(matches!(ElfFile::parse(&kernel_space), Err(ElfError::SegmentOutsideUserSpace)));
+}
+
+#[test_case]
+fn load_and_run() {
+    rusty_os::allow_leaks(); // spawned threads stay in the scheduler until reaped
+    let image = build_elf(usermode::USER_SPACE_START);
+    let elf = ElfFile::parse(&image).expect("valid image rejected");
+    let program = memory::with_kernel_memory(|memory| {
+        loader::load(&elf, &["hello", "world"], &["TERM=vga"], &mut memory.mapper, &mut memory.frame_allocator)
+    }).expect("loading failed");
+
+    assert_eq!(program.entry.as_u64(), usermode::USER_SPACE_START + HEADERS_SIZE);
+    let stack = program.stack_pointer.as_u64();
+    assert_eq!(stack % 16, 0);
+    let argc = unsafe { *(stack as *const u64) };
+    assert_eq!(argc, 2);
+    let argv0 = unsafe { *((stack + 8) as *const u64) };
+    let argv0 = unsafe { core::slice::from_raw_parts(argv0 as *const u8, 6) };
+    assert_eq!(argv0, b"hello\0");
+
+    let id = thread::spawn(move || unsafe { usermode::enter_user_mode(program.entry, program.stack_pointer) })
+        .expect("spawning failed");
+    thread::join(id);
+    memory::with_kernel_memory(|memory| unsafe {
+        loader::unload(&program, &mut memory.mapper, &mut memory.frame_allocator)
+    });
+}
+
+fn free_frames() -> u64 {
+    memory::with_kernel_memory(|memory| memory.frame_allocator.free_frames())
+}
+
+fn is_loaded() -> bool {
+    memory::is_mapped(VirtAddr::new(usermode::USER_SPACE_START))
+        || memory::is_mapped(VirtAddr::new(loader::USER_STACK_BOTTOM))
+}
+
+#[test_case]
+fn second_load_is_rejected_until_unloaded() {
+    let image = build_elf(usermode::USER_SPACE_START);
+    let elf = ElfFile::parse(&image).unwrap();
+    let free = free_frames();
+    memory::with_kernel_memory(|memory| {
+        let program = loader::load(&elf, &[], &[], &mut memory.mapper, &mut memory.frame_allocator).unwrap();
+        let again = loader::load(&elf, &[], &[], &mut memory.mapper, &mut memory.frame_allocator);
+        assert!(matches!(again, Err(ElfError::AddressesInUse)));
+        unsafe { loader::unload(&program, &mut memory.mapper, &mut memory.frame_allocator) };
+    });
+    assert!(!is_loaded());
+    assert_eq!(free_frames(), free);
+}
+
+#[test_case]
+fn failed_load_is_rolled_back() {
+    let image = build_elf(usermode::USER_SPACE_START);
+    let elf = ElfFile::parse(&image).unwrap();
+    let free = free_frames();
+    let huge = [core::str::from_utf8(&[b'x'; 4096]).unwrap(); 16]; // more than the whole stack
+    let result = memory::with_kernel_memory(|memory| {
+        loader::load(&elf, &huge, &[], &mut memory.mapper, &mut memory.frame_allocator)
+    });
+    assert!(matches!(result, Err(ElfError::ArgumentsTooLarge)));
+    assert!(!is_loaded());
+    assert_eq!(free_frames(), free);
+}
+
+#[test_case]
+fn spawned_program_is_unloaded_on_exit() {
+    rusty_os::allow_leaks(); // spawned threads stay in the scheduler until reaped
+    let image = build_elf(usermode::USER_SPACE_START);
+    for _ in 0..2 { // the second spawn would fail if the first one left its pages
+        let id = loader::spawn(&image, &["hello"], &[]).expect("spawning failed");
+        thread::join(id);
+        assert!(!is_loaded());
+    }
+}
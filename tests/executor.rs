@@ -0,0 +1,93 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use rusty_os::task::{executor::Executor, Task};
+use spin::Mutex;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::allocator;
+    use rusty_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rusty_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn spawned_tasks_run() {
+    static RUNS: AtomicU64 = AtomicU64::new(0);
+    let mut executor = Executor::new();
+    for _ in 0..3 {
+        executor.spawn(Task::new(async { RUNS.fetch_add(1, Ordering::Relaxed); }));
+    }
+    executor.run_until_idle();
+    assert_eq!(RUNS.load(Ordering::Relaxed), 3);
+    assert_eq!(executor.task_count(), 0);
+}
+
+// Pending until `READY` is set, its waker kept in `WAKER`.
+struct Gate;
+
+static READY: AtomicBool = AtomicBool::new(false);
+static WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+
+impl Future for Gate {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if READY.load(Ordering::Relaxed) {
+            return Poll::Ready(());
+        }
+        *WAKER.lock() = Some(context.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn woken_task_is_polled_again() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+    let mut executor = Executor::default();
+    executor.spawn(Task::new(async {
+        Gate.await;
+        DONE.store(true, Ordering::Relaxed);
+    }));
+    executor.run_until_idle();
+    assert!(!DONE.load(Ordering::Relaxed));
+    assert_eq!(executor.task_count(), 1);
+
+    executor.run_until_idle(); // not woken: not polled
+    assert!(!DONE.load(Ordering::Relaxed));
+
+    READY.store(true, Ordering::Relaxed);
+    WAKER.lock().take().expect("no waker registered").wake();
+    executor.run_until_idle();
+    assert!(DONE.load(Ordering::Relaxed));
+    assert_eq!(executor.task_count(), 0);
+}
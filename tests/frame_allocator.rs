@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rusty_os::memory::{self, BootInfoFrameAllocator};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+static MEMORY: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rusty_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    *MEMORY.lock() = Some((mapper, frame_allocator));
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn counters() {
+    let mut memory = MEMORY.lock();
+    let (_, frame_allocator) = memory.as_mut().unwrap();
+    assert!(frame_allocator.total_frames() > 0);
+    assert!(frame_allocator.reserved_frames() > 0); // at least the kernel itself
+    let free = frame_allocator.free_frames();
+    frame_allocator.allocate_frame().unwrap();
+    assert_eq!(frame_allocator.free_frames(), free - 1);
+    assert_eq!(frame_allocator.used_frames() + frame_allocator.free_frames(), frame_allocator.total_frames());
+}
+
+#[test_case]
+fn freed_frames_are_reused() {
+    let mut memory = MEMORY.lock();
+    let (_, frame_allocator) = memory.as_mut().unwrap();
+    let a = frame_allocator.allocate_frame().unwrap();
+    let b = frame_allocator.allocate_frame().unwrap();
+    assert_ne!(a, b);
+    let free = frame_allocator.free_frames();
+    unsafe {
+        frame_allocator.deallocate_frame(a);
+        frame_allocator.deallocate_frame(b);
+    }
+    assert_eq!(frame_allocator.free_frames(), free + 2);
+    assert_eq!(frame_allocator.allocate_frame(), Some(b)); // last freed first
+    assert_eq!(frame_allocator.allocate_frame(), Some(a));
+}
+
+#[test_case]
+fn unmapping_returns_frames() {
+    let mut memory = MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().unwrap();
+    let page = Page::containing_address(VirtAddr::new(0x_5555_5555_0000));
+    let frame = frame_allocator.allocate_frame().unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator).unwrap().flush() };
+    let free = frame_allocator.free_frames();
+
+    unsafe { memory::unmap_region(page.start_address(), 4096, mapper, frame_allocator).unwrap() };
+    assert_eq!(frame_allocator.free_frames(), free + 1);
+    assert_eq!(frame_allocator.allocate_frame(), Some(frame));
+}
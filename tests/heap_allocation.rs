@@ -24,6 +24,7 @@ fn main(boot_info: &'static BootInfo) -> ! {
     };
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
 
     test_main();
     loop {}
@@ -64,4 +65,42 @@ fn many_boxes() {
         let x = Box::new(i);
         assert_eq!(*x, i);
     }
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn heap_grows_past_initial_size() {
+    let big = alloc::vec![0xabu8; 4 * HEAP_SIZE]; // more than the initially mapped heap
+    assert!(big.iter().all(|&b| b == 0xab));
+}
+
+#[test_case]
+fn many_large_allocations() {
+    let mut blocks = Vec::new();
+    for i in 0..16 {
+        blocks.push(alloc::vec![i as u8; 64 * 1024]);
+    }
+    for (i, block) in blocks.iter().enumerate() {
+        assert_eq!(block[block.len() - 1], i as u8);
+    }
+}
+
+#[test_case]
+fn stats_track_allocations() {
+    use rusty_os::allocator;
+
+    let before = allocator::stats();
+    let small = Box::new(1u64); // a size class
+    let large = alloc::vec![0u8; 8 * 1024]; // fallback heap
+    let during = allocator::stats();
+    assert_eq!(during.live_allocations(), before.live_allocations() + 2);
+    let class_allocations = |stats: &allocator::HeapStats| stats.classes.iter().map(|c| c.allocations).sum::<u64>();
+    assert_eq!(class_allocations(&during), class_allocations(&before) + 1);
+    assert_eq!(during.fallback.current_bytes, before.fallback.current_bytes + large.len());
+    assert!(during.peak_bytes >= during.current_bytes());
+
+    drop(small);
+    drop(large);
+    let after = allocator::stats();
+    assert_eq!(after.live_allocations(), before.live_allocations());
+    assert_eq!(after.fallback.frees, before.fallback.frees + 1);
+}
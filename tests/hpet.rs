@@ -0,0 +1,90 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rusty_os::acpi::hpet::HpetTable;
+use rusty_os::time::{self, hpet::{self, HpetError}, ClockSource, Duration, Instant};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::{acpi, allocator};
+    use rusty_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rusty_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+    acpi::init().expect("ACPI initialization failed");
+    time::init_hpet().expect("HPET initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn hpet_is_the_clock_source() {
+    let table = HpetTable::get().unwrap();
+    assert!(table.counter_64bit);
+    assert_eq!(time::clock_source(), ClockSource::Hpet);
+    assert!(hpet::frequency() >= 10_000_000); // required by the specification
+}
+
+#[test_case]
+fn main_counter_runs() {
+    let start = hpet::counter();
+    let instant = Instant::now();
+    time::delay_us(1000);
+    assert!(instant.elapsed() >= Duration::from_millis(1));
+    assert!(hpet::counter() - start >= hpet::frequency() / 1000);
+}
+
+static ONESHOT_AT: AtomicU64 = AtomicU64::new(0);
+
+fn record_oneshot() {
+    ONESHOT_AT.store(hpet::counter(), Ordering::Relaxed);
+}
+
+#[test_case]
+fn oneshot_interrupt_fires_after_the_delay() {
+    match hpet::init_oneshot() {
+        Ok(()) => {}
+        Err(HpetError::NoFreeLine) => { // i440FX: the comparators only offer IRQ 2, taken by the PIT
+            assert_eq!(hpet::oneshot_irq(), None);
+            assert!(!hpet::set_oneshot(Duration::from_millis(20), record_oneshot), "one-shot armed without a line");
+            assert!(!hpet::cancel_oneshot());
+            assert_eq!(time::clock_source(), ClockSource::Hpet); // the counter does not need a line
+            return;
+        }
+        Err(error) => panic!("one-shot setup failed: {:?}", error),
+    }
+    assert!(matches!(hpet::init_oneshot(), Err(HpetError::AlreadyInitialized)));
+    let start = hpet::counter();
+    assert!(hpet::set_oneshot(Duration::from_millis(20), record_oneshot));
+    let instant = Instant::now();
+    while ONESHOT_AT.load(Ordering::Relaxed) == 0 && instant.elapsed() < Duration::from_secs(1) {
+        x86_64::instructions::hlt();
+    }
+    let fired = ONESHOT_AT.load(Ordering::Relaxed);
+    assert!(fired != 0, "no one-shot interrupt on IRQ {:?}", hpet::oneshot_irq());
+    assert!(hpet::ticks_to_nanos(fired - start) >= 20_000_000);
+    assert!(!hpet::cancel_oneshot());
+}
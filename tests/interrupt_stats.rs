@@ -0,0 +1,39 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use rusty_os::interrupts::{stats, InterruptIndex};
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    rusty_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn timer_vector_is_counted() {
+    let vector = InterruptIndex::Timer.as_u8();
+    let before = stats::count(vector);
+    for _ in 0..3 {
+        x86_64::instructions::hlt();
+    }
+    assert!(stats::count(vector) >= before + 3);
+}
+
+#[test_case]
+fn breakpoint_is_counted() {
+    let before = stats::count(3);
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(stats::count(3), before + 1);
+    assert_eq!(stats::nested(), 0);
+    stats::dump_serial();
+}
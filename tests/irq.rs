@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rusty_os::interrupts::irq::{self, IrqError, IrqResult, MAX_HANDLERS_PER_LINE};
+use rusty_os::interrupts::InterruptIndex;
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    rusty_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+static SHARED_TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn shared_timer_handler() -> IrqResult {
+    SHARED_TICKS.fetch_add(1, Ordering::Relaxed);
+    IrqResult::NotMine
+}
+
+fn unused_handler() -> IrqResult {
+    IrqResult::NotMine
+}
+
+#[test_case]
+fn shared_line_runs_every_handler() {
+    let line = InterruptIndex::Timer.irq();
+    irq::register_irq(line, shared_timer_handler).unwrap();
+    let before = irq::stats(line).count;
+    while SHARED_TICKS.load(Ordering::Relaxed) < 3 {
+        x86_64::instructions::hlt();
+    }
+    let stats = irq::stats(line);
+    assert!(stats.count >= before + 3);
+    assert_eq!(stats.unhandled, 0); // the timer handler claims every tick
+}
+
+#[test_case]
+fn invalid_and_full_lines_are_rejected() {
+    assert!(matches!(irq::register_irq(16, unused_handler), Err(IrqError::InvalidLine(16))));
+    for _ in 0..MAX_HANDLERS_PER_LINE {
+        irq::register_irq(5, unused_handler).unwrap();
+    }
+    assert!(matches!(irq::register_irq(5, unused_handler), Err(IrqError::LineFull(5))));
+    assert!(irq::is_registered(5));
+}
+
+static REMOVED_TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn removed_timer_handler() -> IrqResult {
+    REMOVED_TICKS.fetch_add(1, Ordering::Relaxed);
+    IrqResult::NotMine
+}
+
+#[test_case]
+fn unregistered_handler_is_not_called() {
+    let line = InterruptIndex::Timer.irq();
+    irq::register_irq(line, removed_timer_handler).unwrap();
+    while REMOVED_TICKS.load(Ordering::Relaxed) == 0 {
+        x86_64::instructions::hlt();
+    }
+    irq::unregister_irq(line, removed_timer_handler).unwrap();
+    let ticks = REMOVED_TICKS.load(Ordering::Relaxed);
+    let count = irq::stats(line).count;
+    while irq::stats(line).count < count + 3 { // the line stays enabled for the timer
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(REMOVED_TICKS.load(Ordering::Relaxed), ticks);
+    assert!(matches!(irq::unregister_irq(line, removed_timer_handler), Err(IrqError::NotRegistered(_))));
+}
+
+#[test_case]
+fn line_without_handlers_is_unregistered() {
+    while irq::is_registered(5) { // filled by invalid_and_full_lines_are_rejected
+        irq::unregister_irq(5, unused_handler).unwrap();
+    }
+    irq::register_irq(5, unused_handler).unwrap();
+    irq::unregister_irq(5, unused_handler).unwrap();
+    assert!(!irq::is_registered(5));
+    assert!(matches!(irq::unregister_irq(16, unused_handler), Err(IrqError::InvalidLine(16))));
+}
@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use rusty_os::thread;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::allocator;
+    use rusty_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rusty_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn spawn_and_yield() {
+    rusty_os::allow_leaks(); // spawned threads stay in the scheduler until reaped
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    for _ in 0..4 {
+        thread::spawn(|| {
+            COUNTER.fetch_add(1, Ordering::SeqCst);
+        }).unwrap();
+    }
+    while COUNTER.load(Ordering::SeqCst) < 4 {
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn sleep_wakes_up() {
+    rusty_os::allow_leaks(); // spawned threads stay in the scheduler until reaped
+    static DONE: AtomicBool = AtomicBool::new(false);
+    thread::spawn(|| {
+        thread::sleep(2);
+        DONE.store(true, Ordering::SeqCst);
+    }).unwrap();
+    while !DONE.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+}
+
+#[test_case]
+fn preemption() {
+    rusty_os::allow_leaks(); // spawned threads stay in the scheduler until reaped
+    static SPINNING: AtomicBool = AtomicBool::new(true);
+    static SEEN: AtomicBool = AtomicBool::new(false);
+    thread::spawn(|| {
+        while SPINNING.load(Ordering::SeqCst) {} // never yields, only the timer can switch away
+    }).unwrap();
+    thread::spawn(|| {
+        SEEN.store(true, Ordering::SeqCst);
+    }).unwrap();
+    while !SEEN.load(Ordering::SeqCst) {
+        x86_64::instructions::hlt(); // wait for timer ticks without yielding
+    }
+    SPINNING.store(false, Ordering::SeqCst);
+}
+
+#[test_case]
+fn stack_has_a_guard_page() {
+    rusty_os::allow_leaks(); // spawned threads stay in the scheduler until reaped
+    use rusty_os::memory;
+    use rusty_os::thread::stack::{GUARD_SIZE, STACKS_START, STACK_SIZE};
+    use x86_64::VirtAddr;
+
+    static LOCAL: AtomicUsize = AtomicUsize::new(0);
+    let id = thread::spawn(|| {
+        let local = 0u8;
+        LOCAL.store(&local as *const u8 as usize, Ordering::SeqCst);
+    }).unwrap();
+    thread::join(id);
+    let local = LOCAL.load(Ordering::SeqCst) as u64;
+    assert!(local >= STACKS_START, "thread stack not in the stack region");
+    let slot = (local - STACKS_START) / (GUARD_SIZE + STACK_SIZE); // a guard page then the stack
+    let bottom = STACKS_START + slot * (GUARD_SIZE + STACK_SIZE) + GUARD_SIZE;
+    assert!(!memory::is_mapped(VirtAddr::new(bottom - 1)), "guard page is mapped");
+}
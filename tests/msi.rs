@@ -0,0 +1,109 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rusty_os::interrupts::{apic, stats, vectors};
+use rusty_os::pci::{self, config, ecam, msi, Bar, PciAddress, CAPABILITY_MSI, COMMAND_MEMORY};
+use rusty_os::time::{Duration, Instant};
+use rusty_os::memory;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::{acpi, allocator};
+    use rusty_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rusty_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+    acpi::init().expect("ACPI initialization failed");
+    apic::init().expect("APIC initialization failed");
+    pci::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+// QEMU's educational device (-device edu): writing to register 0x60 raises its interrupt, 0x64 acknowledges it.
+const EDU_VENDOR_ID: u16 = 0x1234;
+const EDU_DEVICE_ID: u16 = 0x11e8;
+const EDU_RAISE: u64 = 0x60;
+const EDU_ACK: u64 = 0x64;
+
+#[test_case]
+fn edu_device_has_an_msi_capability() {
+    let edu = pci::find(EDU_VENDOR_ID, EDU_DEVICE_ID).expect("no edu device, see test-args");
+    assert!(edu.capabilities().any(|capability| capability.id == CAPABILITY_MSI));
+}
+
+#[test_case]
+fn configuration_space_size_follows_ecam() {
+    let host_bridge = PciAddress { bus: 0, device: 0, function: 0 };
+    if ecam::is_enabled() { // q35
+        assert_eq!(config::config_size(host_bridge), 4096);
+    } else { // i440FX has no MCFG table
+        assert_eq!(config::config_size(host_bridge), 256);
+        assert_eq!(config::read_u32(host_bridge, 0x100), u32::MAX);
+    }
+}
+
+#[test_case]
+fn freed_vectors_are_reused() {
+    fn unused(_: usize) {}
+    let vector = vectors::allocate_vector(unused, 0).unwrap();
+    assert!(vector >= vectors::FIRST_VECTOR);
+    vectors::free_vector(vector);
+    assert_eq!(vectors::allocate_vector(unused, 0).unwrap(), vector);
+    vectors::free_vector(vector);
+}
+
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+static EDU_REGISTERS: AtomicU64 = AtomicU64::new(0);
+
+fn edu_interrupt(argument: usize) {
+    assert_eq!(argument, 42);
+    let registers = EDU_REGISTERS.load(Ordering::Relaxed);
+    unsafe { ((registers + EDU_ACK) as *mut u32).write_volatile(1) };
+    INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn msi_reaches_the_allocated_vector() {
+    let edu = pci::find(EDU_VENDOR_ID, EDU_DEVICE_ID).unwrap();
+    let bar = match edu.bars[0] {
+        Some(Bar::Memory { address, .. }) => address,
+        bar => panic!("unexpected BAR 0: {:?}", bar),
+    };
+    let registers = memory::map_mmio(x86_64::PhysAddr::new(bar), 0x100).unwrap().as_u64();
+    EDU_REGISTERS.store(registers, Ordering::Relaxed);
+    edu.enable(COMMAND_MEMORY);
+
+    let vector = msi::enable_msi(&edu, edu_interrupt, 42).unwrap();
+    unsafe { ((registers + EDU_RAISE) as *mut u32).write_volatile(1) };
+    let start = Instant::now();
+    while INTERRUPTS.load(Ordering::Relaxed) == 0 && start.elapsed() < Duration::from_secs(1) {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(INTERRUPTS.load(Ordering::Relaxed), 1);
+    assert_eq!(stats::count(vector), 1);
+    msi::disable_msi(&edu, vector);
+}
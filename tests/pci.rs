@@ -0,0 +1,128 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rusty_os::pci::{self, Bar, DeviceMatch, PciDevice, PciDriver, ProbeError};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::allocator;
+    use rusty_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rusty_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+    pci::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+// QEMU's default machine: i440FX host bridge, PIIX3 ISA bridge and IDE controller, standard VGA.
+
+#[test_case]
+fn host_bridge_and_ide_controller_are_found() {
+    let devices = pci::devices();
+    let host_bridge = devices.iter().find(|device| device.class == pci::CLASS_BRIDGE && device.subclass == pci::SUBCLASS_HOST_BRIDGE);
+    assert_eq!(host_bridge.unwrap().address.bus, 0);
+    assert!(devices.iter().any(|device| device.class == 0x01 && device.subclass == 0x01));
+}
+
+#[test_case]
+fn bar_sizes_are_decoded() {
+    let vga = pci::find(0x1234, 0x1111).expect("no standard VGA");
+    match vga.bars[0] {
+        Some(Bar::Memory { size, prefetchable, .. }) => {
+            assert_eq!(size, 16 << 20); // framebuffer
+            assert!(prefetchable);
+        }
+        bar => panic!("unexpected BAR 0: {:?}", bar),
+    }
+    let ide = pci::devices().into_iter().find(|device| device.class == 0x01 && device.subclass == 0x01).unwrap();
+    assert!(matches!(ide.bars[4], Some(Bar::Io { size: 16, .. }))); // bus master DMA registers
+}
+
+static PROBED: AtomicU64 = AtomicU64::new(0);
+
+fn probe_vga(device: &PciDevice) -> Result<(), ProbeError> {
+    assert_eq!(device.vendor_id, 0x1234);
+    PROBED.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+fn decline(_device: &PciDevice) -> Result<(), ProbeError> {
+    Err(ProbeError::NotSupported)
+}
+
+static DECLINING_DRIVER: PciDriver = PciDriver {
+    name: "declining",
+    matches: &[DeviceMatch::Class { class: 0x03, subclass: 0x00, prog_if: None }],
+    probe: decline,
+};
+
+static VGA_DRIVER: PciDriver = PciDriver {
+    name: "test-vga",
+    matches: &[DeviceMatch::Id { vendor_id: 0x1234, device_id: 0x1111 }],
+    probe: probe_vga,
+};
+
+#[test_case]
+fn drivers_are_probed_against_matching_devices() {
+    rusty_os::allow_leaks(); // registered drivers stay in the PCI registry
+    pci::register_driver(&DECLINING_DRIVER);
+    let vga = pci::find(0x1234, 0x1111).unwrap();
+    assert_eq!(pci::driver_of(vga.address), None);
+    pci::register_driver(&VGA_DRIVER);
+    pci::register_driver(&DECLINING_DRIVER); // the function is bound: no more probes
+    assert_eq!(PROBED.load(Ordering::Relaxed), 1);
+    assert_eq!(pci::driver_of(vga.address), Some("test-vga"));
+}
+
+static FAILED_PROBES: AtomicU64 = AtomicU64::new(0);
+
+fn fail(_device: &PciDevice) -> Result<(), ProbeError> {
+    FAILED_PROBES.fetch_add(1, Ordering::Relaxed);
+    Err(ProbeError::Failed("test"))
+}
+
+static FAILING_DRIVER: PciDriver = PciDriver {
+    name: "failing",
+    matches: &[DeviceMatch::Class { class: 0x06, subclass: 0x00, prog_if: None }], // host bridge
+    probe: fail,
+};
+
+static OTHER_DRIVER: PciDriver = PciDriver {
+    name: "other",
+    matches: &[DeviceMatch::Class { class: 0x06, subclass: 0x00, prog_if: None }],
+    probe: decline,
+};
+
+#[test_case]
+fn failed_probes_are_not_repeated() {
+    rusty_os::allow_leaks(); // registered drivers stay in the PCI registry
+    pci::register_driver(&FAILING_DRIVER);
+    let failed = FAILED_PROBES.load(Ordering::Relaxed);
+    assert!(failed > 0, "no host bridge probed");
+    pci::register_driver(&OTHER_DRIVER); // same functions, still unbound
+    assert_eq!(FAILED_PROBES.load(Ordering::Relaxed), failed);
+}
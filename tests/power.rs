@@ -0,0 +1,50 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rusty_os::acpi::{self, dsdt, fadt::Fadt};
+use rusty_os::power;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::memory;
+    use x86_64::VirtAddr;
+
+    rusty_os::init();
+    unsafe { memory::init(VirtAddr::new(boot_info.physical_memory_offset)) };
+    acpi::init().expect("ACPI initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+// Powering off or rebooting would end the test run with the wrong exit code: only what leads to them is tested.
+
+#[test_case]
+fn dsdt_has_the_soft_off_sleep_type() {
+    let (sleep_type_a, sleep_type_b) = dsdt::sleep_type_s5().unwrap();
+    assert!(sleep_type_a < 8 && sleep_type_b < 8); // 3-bit fields
+}
+
+#[test_case]
+fn chipset_switches_to_acpi_mode() {
+    power::enable_acpi_mode().unwrap();
+    let control = Fadt::get().unwrap().pm1a_control.unwrap();
+    assert!(control.read().unwrap() & 1 != 0); // SCI_EN
+}
+
+#[test_case]
+fn a_reset_method_is_available() {
+    let fadt = Fadt::get().unwrap();
+    assert!(fadt.supports_reset_register() || fadt.has_8042());
+}
@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+// Booted on QEMU's q35 machine by tools/runner.sh, for what the default i440FX lacks: the MCFG table and ECAM,
+// PCI Express extended capabilities, and MSI-X on the default network card, an e1000e.
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rusty_os::interrupts::apic;
+use rusty_os::memory;
+use rusty_os::pci::{self, config, ecam, msi::{self, MsiX}, Bar, PciAddress, PciDevice, CAPABILITY_MSI_X};
+use x86_64::PhysAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::{acpi, allocator};
+    use rusty_os::memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rusty_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+    acpi::init().expect("ACPI initialization failed");
+    apic::init().expect("APIC initialization failed"); // for MSI-X
+    pci::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+const E1000E_VENDOR_ID: u16 = 0x8086;
+const E1000E_DEVICE_ID: u16 = 0x10d3;
+const E1000E_MSI_X_ENTRIES: u16 = 5;
+const EXTENDED_CAPABILITY_AER: u16 = 0x0001;
+const EXTENDED_CAPABILITY_SERIAL_NUMBER: u16 = 0x0003;
+
+fn e1000e() -> PciDevice {
+    pci::find(E1000E_VENDOR_ID, E1000E_DEVICE_ID).expect("no e1000e, not booted on q35 by tools/runner.sh?")
+}
+
+#[test_case]
+fn configuration_goes_through_ecam() {
+    assert!(ecam::is_enabled(), "no MCFG table");
+    let host_bridge = PciAddress { bus: 0, device: 0, function: 0 };
+    assert_eq!(config::config_size(host_bridge), ecam::CONFIG_SIZE);
+    let e1000e = e1000e();
+    assert_eq!(config::read_u32(e1000e.address, 0), u32::from(E1000E_DEVICE_ID) << 16 | u32::from(E1000E_VENDOR_ID));
+}
+
+#[test_case]
+fn extended_capabilities_are_found() {
+    let e1000e = e1000e();
+    assert!(e1000e.extended_capabilities().any(|capability| capability.id == EXTENDED_CAPABILITY_AER));
+    assert!(e1000e.extended_capabilities().any(|capability| capability.id == EXTENDED_CAPABILITY_SERIAL_NUMBER));
+}
+
+// Read `register` of the MSI-X table entry `entry`, through a mapping of our own.
+fn read_table(device: &PciDevice, entry: u16, register: u64) -> u32 {
+    let capability = device.find_capability(CAPABILITY_MSI_X).unwrap().offset;
+    let table = device.read_u32(capability + 4);
+    let bar = match device.bars[(table & 0b111) as usize] {
+        Some(Bar::Memory { address, .. }) => address,
+        bar => panic!("MSI-X table in {:?}", bar),
+    };
+    let entries = memory::map_mmio(PhysAddr::new(bar + u64::from(table & !0b111)), 4096).unwrap();
+    unsafe { (entries + u64::from(entry) * 16 + register).as_ptr::<u32>().read_volatile() }
+}
+
+#[test_case]
+fn msi_x_table_is_set_up() {
+    fn unused(_: usize) {}
+    let e1000e = e1000e();
+    let msi_x = MsiX::enable(&e1000e).unwrap();
+    assert_eq!(msi_x.size(), E1000E_MSI_X_ENTRIES);
+    assert!(msi::is_msi_x_enabled(&e1000e));
+    assert!(matches!(msi_x.set_vector(E1000E_MSI_X_ENTRIES, unused, 0), Err(msi::MsiError::InvalidEntry(_))));
+
+    let vector = msi_x.set_vector(1, unused, 0).unwrap();
+    assert_eq!(read_table(&e1000e, 1, 0), 0xfee0_0000 | u32::from(apic::local_apic_id()) << 12);
+    assert_eq!(read_table(&e1000e, 1, 8), u32::from(vector));
+    assert_eq!(read_table(&e1000e, 1, 12) & 1, 0, "entry still masked");
+    assert_eq!(read_table(&e1000e, 0, 12) & 1, 1, "unused entry not masked");
+
+    msi_x.clear_vector(1, vector);
+    assert_eq!(read_table(&e1000e, 1, 12) & 1, 1);
+    msi_x.disable();
+    assert!(!msi::is_msi_x_enabled(&e1000e));
+}
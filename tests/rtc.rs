@@ -0,0 +1,66 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use rusty_os::time::{self, rtc, DateTime, Duration, Instant};
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    rusty_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn timestamps_convert_to_dates() {
+    let epoch = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0 };
+    assert_eq!(DateTime::from_timestamp(0), epoch);
+    let leap_day = DateTime { year: 2000, month: 2, day: 29, hour: 12, minute: 30, second: 15 };
+    assert_eq!(leap_day.timestamp(), 951_827_415);
+    assert_eq!(DateTime::from_timestamp(951_827_415), leap_day);
+    for timestamp in (0..4_102_444_800).step_by(86_400 * 37 + 3_607) {
+        assert_eq!(DateTime::from_timestamp(timestamp).timestamp(), timestamp);
+    }
+}
+
+#[test_case]
+fn invalid_dates_are_rejected() {
+    let date = DateTime { year: 2023, month: 2, day: 28, hour: 23, minute: 59, second: 59 };
+    assert!(date.is_valid());
+    assert!(!DateTime { day: 0, ..date }.is_valid());
+    assert!(!DateTime { day: 29, ..date }.is_valid()); // not a leap year
+    assert!(DateTime { year: 2024, day: 29, ..date }.is_valid());
+    assert!(!DateTime { year: 1900, day: 29, ..date }.is_valid());
+    assert!(!DateTime { month: 0, ..date }.is_valid());
+    assert!(!DateTime { month: 13, ..date }.is_valid());
+    assert!(!DateTime { hour: 24, ..date }.is_valid());
+    assert!(!DateTime { second: 60, ..date }.is_valid());
+}
+
+#[test_case]
+fn rtc_date_is_plausible() {
+    let date = rtc::read().expect("invalid RTC date");
+    assert!(date.year >= 2020 && date.year < 2100, "{}", date);
+    assert!((1..=12).contains(&date.month) && (1..=31).contains(&date.day));
+    assert!(date.hour < 24 && date.minute < 60 && date.second < 60);
+}
+
+#[test_case]
+fn wall_clock_follows_the_rtc() {
+    let rtc = rtc::read().unwrap().timestamp();
+    let now = time::now().timestamp();
+    assert!(now + 2 >= rtc && now <= rtc + 2, "{} {}", now, rtc);
+
+    let before = time::unix_time();
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(20) {}
+    assert!(time::unix_time() >= before + Duration::from_millis(20));
+}
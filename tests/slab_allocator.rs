@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rusty_os::allocator::slab::{ObjectCache, SLAB_SIZE};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::allocator;
+    use rusty_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rusty_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+static NODE_CACHE: ObjectCache = ObjectCache::new("page-table-node", 48, 16);
+
+#[test_case]
+fn objects_are_aligned_and_distinct() {
+    let a = NODE_CACHE.allocate().unwrap();
+    let b = NODE_CACHE.allocate().unwrap();
+    assert_ne!(a, b);
+    assert_eq!(a.as_ptr() as usize % 16, 0);
+    assert_eq!(b.as_ptr() as usize % 16, 0);
+    assert_eq!(NODE_CACHE.objects_in_use(), 2);
+    unsafe {
+        NODE_CACHE.deallocate(a);
+        NODE_CACHE.deallocate(b);
+    }
+    assert_eq!(NODE_CACHE.name(), "page-table-node");
+}
+
+#[test_case]
+fn empty_slabs_are_released() {
+    let count = 2 * SLAB_SIZE / 48; // more than one slab worth of objects
+    let objects: Vec<_> = (0..count).map(|_| NODE_CACHE.allocate().unwrap()).collect();
+    assert!(NODE_CACHE.slabs() >= 2);
+    for object in objects {
+        unsafe { NODE_CACHE.deallocate(object) };
+    }
+    assert_eq!(NODE_CACHE.objects_in_use(), 0);
+    assert_eq!(NODE_CACHE.slabs(), 0);
+}
+
+#[test_case]
+fn objects_keep_their_content() {
+    let objects: Vec<_> = (0..100u8).map(|i| {
+        let object = NODE_CACHE.allocate().unwrap();
+        unsafe { object.as_ptr().write_bytes(i, 48) };
+        object
+    }).collect();
+    for (i, object) in objects.iter().enumerate() {
+        let bytes = unsafe { core::slice::from_raw_parts(object.as_ptr(), 48) };
+        assert!(bytes.iter().all(|&b| b == i as u8));
+    }
+    for object in objects {
+        unsafe { NODE_CACHE.deallocate(object) };
+    }
+}
@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use rusty_os::time::{self, Duration, Instant};
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    rusty_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+fn wait_ticks(count: u64) {
+    let until = time::ticks() + count;
+    while time::ticks() < until {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn timer_runs_at_the_configured_frequency() {
+    assert_eq!(time::frequency(), time::DEFAULT_FREQUENCY);
+    let start = Instant::now();
+    wait_ticks(20); // 200 ms
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(150) && elapsed <= Duration::from_millis(400), "{:?}", elapsed);
+}
+
+#[test_case]
+fn instants_are_monotonic() {
+    let mut last = Instant::now();
+    for _ in 0..1000 {
+        let now = Instant::now();
+        assert!(now >= last);
+        last = now;
+    }
+    let uptime = time::uptime();
+    assert!(time::uptime() >= uptime);
+    assert_eq!((last + Duration::from_millis(5)) - last, Duration::from_millis(5));
+}
+
+#[test_case]
+fn frequency_can_be_changed() {
+    time::set_frequency(1000);
+    assert!(time::frequency() >= 995 && time::frequency() <= 1005);
+    let start = time::ticks();
+    let instant = Instant::now();
+    while instant.elapsed() < Duration::from_millis(50) {}
+    assert!(time::ticks() - start >= 25); // about 50 at 1 kHz, QEMU may lose some
+    time::set_frequency(time::DEFAULT_FREQUENCY);
+}
@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use rusty_os::time::{self, timer, Duration, Instant};
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    rusty_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+fn wait(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        x86_64::instructions::hlt();
+    }
+}
+
+static FIRED: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+fn count(index: usize) {
+    FIRED[index].fetch_add(1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn oneshot_timer_fires_once() {
+    timer::start_oneshot(Duration::from_millis(30), count, 0).unwrap();
+    let cancelled = timer::start_oneshot(Duration::from_millis(30), count, 0).unwrap();
+    assert!(timer::cancel(cancelled));
+    wait(Duration::from_millis(100));
+    assert_eq!(FIRED[0].load(Ordering::Relaxed), 1);
+    assert_eq!(timer::pending(), 0);
+    assert!(!timer::cancel(cancelled));
+}
+
+#[test_case]
+fn periodic_timer_fires_until_cancelled() {
+    let id = timer::start_periodic(Duration::from_millis(10), count, 1).unwrap();
+    wait(Duration::from_millis(100));
+    assert!(timer::cancel(id));
+    let fired = FIRED[1].load(Ordering::Relaxed);
+    assert!(fired >= 5 && fired <= 11, "{}", fired);
+    wait(Duration::from_millis(50));
+    assert_eq!(FIRED[1].load(Ordering::Relaxed), fired);
+}
+
+#[test_case]
+fn delay_us_waits_long_enough() {
+    let start = Instant::now();
+    time::delay_us(2000);
+    assert!(start.elapsed() >= Duration::from_micros(2000));
+}
+
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+fn flag_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    fn wake(_: *const ()) {
+        WOKEN.store(true, Ordering::Relaxed);
+    }
+    fn drop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+    unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) }
+}
+
+#[test_case]
+fn sleep_wakes_the_task_at_the_deadline() {
+    let waker = flag_waker();
+    let mut context = Context::from_waker(&waker);
+    let start = Instant::now();
+    let mut sleep = time::sleep(Duration::from_millis(50));
+    assert_eq!(Pin::new(&mut sleep).poll(&mut context), Poll::Pending);
+    while !WOKEN.load(Ordering::Relaxed) {
+        x86_64::instructions::hlt();
+    }
+    while Pin::new(&mut sleep).poll(&mut context).is_pending() {
+        x86_64::instructions::hlt(); // woken a little early: the tick rounding differs from the TSC
+    }
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert_eq!(timer::pending(), 0);
+}
@@ -0,0 +1,139 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rusty_os::memory::{self, BootInfoFrameAllocator};
+use rusty_os::{thread, usermode};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::allocator;
+
+    rusty_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    thread::init();
+    usermode::map_user_stack(4096, &mut mapper, &mut frame_allocator) // shared by the tests, they run one at a time
+        .expect("mapping user stack failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+const CODE_PAGE_SIZE: u64 = 4096;
+
+// Copy `code` to its own user page and run it in a new thread, return when the thread is gone.
+fn run_user_code(slot: u64, code: &'static [u8]) {
+    rusty_os::allow_leaks(); // spawned threads stay in the scheduler until reaped
+    let entry = VirtAddr::new(usermode::USER_SPACE_START + slot * CODE_PAGE_SIZE);
+    memory::with_kernel_memory(|memory| {
+        let flags = PageTableFlags::WRITABLE;
+        usermode::map_user_region(entry, CODE_PAGE_SIZE, flags, &mut memory.mapper, &mut memory.frame_allocator)
+            .expect("mapping user code failed");
+        unsafe { core::ptr::copy_nonoverlapping(code.as_ptr(), entry.as_mut_ptr(), code.len()) };
+    });
+
+    let stack_top = VirtAddr::new(usermode::USER_STACK_TOP);
+    let id = thread::spawn(move || unsafe { usermode::enter_user_mode(entry, stack_top) }).expect("spawning failed");
+    thread::join(id);
+}
+
+#[test_case]
+fn syscall_exit() {
+    run_user_code(0, &[
+        0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, SYS_YIELD
+        0xcd, 0x80,                   // int 0x80
+        0xb8, 0x00, 0x00, 0x00, 0x00, // mov eax, SYS_EXIT
+        0xcd, 0x80,                   // int 0x80
+    ]);
+}
+
+#[test_case]
+fn privileged_instruction_kills_thread() {
+    run_user_code(1, &[0xf4]); // hlt
+}
+
+#[test_case]
+fn kernel_address_kills_thread() {
+    run_user_code(2, &[
+        0x48, 0x8b, 0x04, 0x25, 0x00, 0x80, 0x0b, 0x00, // mov rax, [0xb8000]
+    ]);
+}
+
+// True if `text` is on one line of the VGA text buffer (a character byte then a color byte per cell).
+fn screen_contains(text: &str) -> bool {
+    let screen = 0xb8000 as *const u8;
+    (0..25).any(|row| {
+        let line: [u8; 80] = core::array::from_fn(|column| unsafe {
+            screen.add(2 * (row * 80 + column)).read_volatile()
+        });
+        line.windows(text.len()).any(|window| window == text.as_bytes())
+    })
+}
+
+#[test_case]
+fn user_fault_is_reported() {
+    run_user_code(3, &[0xf4]); // hlt
+    assert!(screen_contains("EXCEPTION: GENERAL PROTECTION FAULT (vector 13)"));
+    assert!(screen_contains("rip: 0x0000100000003000  cs: 0x23"), "no registers in the report");
+    assert!(screen_contains("USER FAULT: thread"));
+}
+
+#[test_case]
+fn single_step_does_not_kill_thread() {
+    use rusty_os::interrupts::{exceptions::DEBUG, stats};
+
+    let debug_traps = stats::count(DEBUG as u8);
+    run_user_code(4, &[
+        0x9c,                                           // pushfq
+        0x48, 0x81, 0x0c, 0x24, 0x00, 0x01, 0x00, 0x00, // or qword [rsp], 0x100 (trap flag)
+        0x9d,                                           // popfq, single steps from here
+        0x9c,                                           // pushfq
+        0x48, 0x81, 0x24, 0x24, 0xff, 0xfe, 0xff, 0xff, // and qword [rsp], !0x100
+        0x9d,                                           // popfq
+        0x48, 0xb8, 0x00, 0x48, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, // mov rax, marker
+        0xc6, 0x00, 0x01,                               // mov byte [rax], 1
+        0xb8, 0x00, 0x00, 0x00, 0x00,                   // mov eax, SYS_EXIT
+        0xcd, 0x80,                                     // int 0x80
+    ]);
+    assert!(stats::count(DEBUG as u8) > debug_traps, "no debug exception");
+    let marker = unsafe { (0x100000004800 as *const u8).read_volatile() }; // in the code page of slot 4
+    assert_eq!(marker, 1, "thread killed by the debug exception");
+}
+
+#[test_case]
+fn bad_syscall_pointer_kills_thread() {
+    run_user_code(5, &[
+        0xb8, 0x01, 0x00, 0x00, 0x00,                               // mov eax, SYS_WRITE
+        0x48, 0xbf, 0x00, 0x00, 0x10, 0x00, 0x00, 0x10, 0x00, 0x00, // mov rdi, unmapped user address
+        0xbe, 0x04, 0x00, 0x00, 0x00,                               // mov esi, 4
+        0xcd, 0x80,                                                 // int 0x80
+        0x48, 0xb8, 0x2a, 0x50, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, // mov rax, marker
+        0xc6, 0x00, 0x01,                                           // mov byte [rax], 1
+        0xb8, 0x00, 0x00, 0x00, 0x00,                               // mov eax, SYS_EXIT
+        0xcd, 0x80,                                                 // int 0x80
+        0x00,                                                       // marker
+    ]);
+    let marker = unsafe { (0x10000000502a as *const u8).read_volatile() }; // last byte of the code above
+    assert_eq!(marker, 0, "write returned after reading an unmapped page");
+}
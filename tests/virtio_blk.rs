@@ -0,0 +1,169 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rusty_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rusty_os::block::{virtio_blk::{self, Request, VirtioBlk, SECTOR_SIZE}, BlockDevice, BlockError};
+use rusty_os::pci;
+use spin::Mutex;
+use rusty_os::virtio::{Transport, VENDOR_ID};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rusty_os::{acpi, allocator, interrupts::apic};
+    use rusty_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rusty_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+    acpi::init().expect("ACPI initialization failed");
+    apic::init().expect("APIC initialization failed"); // for MSI-X
+    pci::init();
+    virtio_blk::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rusty_os::test_panic_handler(info)
+}
+
+// The 16 MiB scratch disk of the test-args, behind QEMU's transitional virtio-blk device.
+const SECTORS: u64 = 16 * 1024 * 1024 / SECTOR_SIZE as u64;
+// The 8 MiB one, behind a legacy-only device: the legacy transport with its queue and data path.
+const LEGACY_SECTORS: u64 = 8 * 1024 * 1024 / SECTOR_SIZE as u64;
+
+fn pattern(length: usize, seed: u8) -> Vec<u8> {
+    (0..length).map(|index| (index as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+}
+
+// The disks are named in PCI order, find them by transport instead.
+fn disk(modern: bool) -> Arc<Mutex<VirtioBlk>> {
+    virtio_blk::disks().into_iter().find(|disk| disk.lock().is_modern() == modern)
+        .expect("virtio disk missing, see test-args")
+}
+
+#[test_case]
+fn disks_are_registered() {
+    assert_eq!(virtio_blk::disks().len(), 2);
+    let (modern, legacy) = (disk(true), disk(false));
+    let modern = modern.lock();
+    assert_eq!(modern.sector_count(), SECTORS);
+    assert_eq!(modern.capacity(), 16 * 1024 * 1024);
+    assert!(modern.uses_interrupts());
+    assert_eq!(legacy.lock().sector_count(), LEGACY_SECTORS);
+}
+
+#[test_case]
+fn both_transports_read_the_capacity() {
+    let device = pci::devices().into_iter()
+        .find(|device| device.vendor_id == VENDOR_ID && Transport::modern(device).unwrap().is_some())
+        .expect("no transitional device");
+    let legacy = Transport::legacy(&device).expect("transitional device without an I/O BAR");
+    let modern = Transport::modern(&device).unwrap().unwrap();
+    assert_eq!(legacy.read_config_u64(0), SECTORS);
+    assert_eq!(modern.read_config_u64(0), SECTORS);
+}
+
+#[test_case]
+fn write_is_read_back() {
+    let disk = disk(true);
+    let mut disk = disk.lock();
+    let data = pattern(100 * SECTOR_SIZE, 1); // several pages and more than one request
+    disk.write_sectors(1000, &data).unwrap();
+    let mut read = vec![0; data.len()];
+    disk.read_sectors(1000, &mut read).unwrap();
+    assert!(read == data);
+}
+
+#[test_case]
+fn legacy_write_is_read_back() {
+    let disk = disk(false);
+    let mut disk = disk.lock();
+    let data = pattern(20 * SECTOR_SIZE, 3);
+    disk.write_sectors(LEGACY_SECTORS - 20, &data).unwrap(); // last sectors
+    let mut read = vec![0; data.len()];
+    disk.read_sectors(LEGACY_SECTORS - 20, &mut read).unwrap();
+    assert!(read == data);
+}
+
+#[test_case]
+fn unaligned_buffer_is_read() {
+    let disk = disk(true);
+    let mut disk = disk.lock();
+    let data = pattern(4 * SECTOR_SIZE, 2);
+    disk.write_sectors(SECTORS - 4, &data).unwrap(); // last sectors
+    let mut read = vec![0; data.len() + 3];
+    disk.read_sectors(SECTORS - 4, &mut read[3..]).unwrap(); // crosses pages at odd addresses
+    assert!(read[3..] == data[..]);
+}
+
+#[test_case]
+fn batch_completes_every_request() {
+    let disk = disk(true);
+    let mut disk = disk.lock();
+    let interrupts = disk.interrupts();
+    let data: Vec<Vec<u8>> = (0..40).map(|index| pattern(2 * SECTOR_SIZE, index)).collect();
+    let mut writes: Vec<Request> = data.iter().enumerate()
+        .map(|(index, buffer)| Request::Write { sector: 2000 + 2 * index as u64, buffer })
+        .collect();
+    writes.push(Request::Flush);
+    disk.batch(&mut writes).unwrap();
+    drop(writes);
+
+    let mut read: Vec<Vec<u8>> = (0..40).map(|_| vec![0; 2 * SECTOR_SIZE]).collect();
+    let mut reads: Vec<Request> = read.iter_mut().enumerate()
+        .map(|(index, buffer)| Request::Read { sector: 2000 + 2 * index as u64, buffer })
+        .collect();
+    disk.batch(&mut reads).unwrap();
+    drop(reads);
+    assert!(read == data);
+    assert!(disk.interrupts() > interrupts, "no used ring interrupt");
+}
+
+#[test_case]
+fn bad_requests_are_rejected() {
+    let disk = disk(true);
+    let mut disk = disk.lock();
+    let mut sector = [0; SECTOR_SIZE];
+    assert_eq!(disk.read_sectors(SECTORS, &mut sector), Err(BlockError::OutOfRange));
+    assert_eq!(disk.write_sectors(0, &sector[..10]), Err(BlockError::BadBufferSize));
+}
+
+// What a failed probe gives back: the DMA memory of the queue and the mappings of the modern transport.
+#[test_case]
+fn failed_setup_memory_is_given_back() {
+    use rusty_os::memory;
+    use rusty_os::virtio::queue::Virtqueue;
+    use x86_64::PhysAddr;
+
+    let free_frames = || memory::with_kernel_memory(|memory| memory.frame_allocator.free_frames());
+    let before = free_frames();
+    let queue = Virtqueue::new(0, 256).unwrap();
+    assert!(free_frames() < before);
+    unsafe { queue.free() };
+    assert_eq!(free_frames(), before);
+
+    let vga = memory::map_mmio(PhysAddr::new(0xb8000), 100).unwrap();
+    unsafe { memory::unmap_mmio(vga, 100) };
+    assert!(!memory::is_mapped(vga));
+    let again = memory::map_mmio(PhysAddr::new(0xb8000), 100).unwrap();
+    assert_eq!(again, vga, "the last range was not given back");
+    unsafe { memory::unmap_mmio(again, 100) };
+}
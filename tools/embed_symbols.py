@@ -0,0 +1,116 @@
+#!/usr/bin/env python3
+"""Write the function symbols of a kernel ELF into its `.rusty_symbols` section, in place.
+
+Usage: embed_symbols.py <kernel elf>
+
+The layout is described in src/backtrace/symbols.rs. Running it twice on the same file is fine.
+"""
+
+import re
+import struct
+import sys
+
+SECTION = b".rusty_symbols"
+MAGIC = b"RUSTYSYM"
+TABLE_SIZE = 512 * 1024  # SYMBOL_TABLE_SIZE in src/backtrace/symbols.rs
+HEADER_SIZE = 16
+ENTRY = struct.Struct("<QQII")
+MAX_NAME_LEN = 128
+
+SHT_SYMTAB = 2
+STT_FUNC = 2
+
+ESCAPES = {
+    "$SP$": "@", "$BP$": "*", "$RF$": "&", "$LT$": "<", "$GT$": ">",
+    "$LP$": "(", "$RP$": ")", "$C$": ",",
+}
+
+
+def demangle(name):
+    """Legacy Rust mangling (_ZN...E) without the hash, other names are kept as they are."""
+    if not name.startswith("_ZN") or not name.endswith("E"):
+        return name
+    body, parts = name[3:-1], []
+    while body:
+        match = re.match(r"(\d+)", body)
+        if not match:
+            return name
+        length = int(match.group(1))
+        start = len(match.group(1))
+        parts.append(body[start:start + length])
+        body = body[start + length:]
+    if parts and re.fullmatch(r"h[0-9a-f]{16}", parts[-1]):
+        parts.pop()
+    path = "::".join(parts)
+    for escape, char in ESCAPES.items():
+        path = path.replace(escape, char)
+    path = re.sub(r"\$u([0-9a-f]+)\$", lambda m: chr(int(m.group(1), 16)), path)
+    return path.replace("..", "::").replace("_$", "$")
+
+
+def sections(elf):
+    shoff, = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3a)
+    headers = []
+    for index in range(shnum):
+        name, kind, _, _, offset, size, link, _, _, entsize = struct.unpack_from(
+            "<IIQQQQIIQQ", elf, shoff + index * shentsize)
+        headers.append({"name": name, "type": kind, "offset": offset, "size": size, "link": link})
+    names = headers[shstrndx]
+    for header in headers:
+        start = names["offset"] + header["name"]
+        header["name"] = bytes(elf[start:elf.index(b"\0", start)])
+    return headers
+
+
+def function_symbols(elf, headers):
+    symtab = next((h for h in headers if h["type"] == SHT_SYMTAB), None)
+    if symtab is None:
+        return []
+    strtab = headers[symtab["link"]]
+    symbols = {}
+    for offset in range(symtab["offset"], symtab["offset"] + symtab["size"], 24):
+        name, info, _, _, value, size = struct.unpack_from("<IBBHQQ", elf, offset)
+        if info & 0xf != STT_FUNC or value == 0:
+            continue
+        start = strtab["offset"] + name
+        raw = bytes(elf[start:elf.index(b"\0", start)]).decode("utf-8", "replace")
+        symbols.setdefault(value, (size, demangle(raw)[:MAX_NAME_LEN]))
+    return sorted((address, size, name) for address, (size, name) in symbols.items())
+
+
+def build_table(symbols):
+    names_offset = HEADER_SIZE + len(symbols) * ENTRY.size
+    entries, names = bytearray(), bytearray()
+    for address, size, name in symbols:
+        encoded = name.encode()
+        entries += ENTRY.pack(address, size, names_offset + len(names), len(encoded))
+        names += encoded
+    table = MAGIC + struct.pack("<Q", len(symbols)) + entries + names
+    if len(table) > TABLE_SIZE:
+        sys.exit("embed_symbols: {} bytes of symbols do not fit in {} bytes, raise SYMBOL_TABLE_SIZE"
+                 .format(len(table), TABLE_SIZE))
+    return table + bytes(TABLE_SIZE - len(table))
+
+
+def main(path):
+    with open(path, "rb") as file:
+        elf = bytearray(file.read())
+    if elf[:4] != b"\x7fELF" or elf[4] != 2:
+        sys.exit("embed_symbols: {} is not an ELF64 file".format(path))
+    headers = sections(elf)
+    section = next((h for h in headers if h["name"] == SECTION), None)
+    if section is None:
+        return  # not a kernel image of this crate
+    if section["size"] != TABLE_SIZE or elf[section["offset"]:section["offset"] + 8] != MAGIC:
+        sys.exit("embed_symbols: unexpected {} section in {}".format(SECTION.decode(), path))
+    table = build_table(function_symbols(elf, headers))
+    elf[section["offset"]:section["offset"] + TABLE_SIZE] = table
+    with open(path, "wb") as file:
+        file.write(elf)
+
+
+if __name__ == "__main__":
+    if len(sys.argv) != 2:
+        sys.exit(__doc__)
+    main(sys.argv[1])
@@ -0,0 +1,13 @@
+#!/bin/sh
+# Cargo runner: embed the symbol table for backtraces, then boot the kernel as before.
+set -e
+python3 "$(dirname "$0")/embed_symbols.py" "$1"
+# scratch disks of the test-args, relative to the package directory like cargo runs us
+[ -f target/virtio-test.img ] || truncate -s 16M target/virtio-test.img
+[ -f target/virtio-legacy-test.img ] || truncate -s 8M target/virtio-legacy-test.img
+[ -f target/ata-test.img ] || truncate -s 129G target/ata-test.img # sparse: past the 128 GiB of LBA28
+case "$(basename "$1")" in
+    # tests/q35.rs: PCI Express machine, ECAM and the MSI-X of its default e1000e network card
+    q35-*) exec bootimage runner "$@" -machine q35 ;;
+esac
+exec bootimage runner "$@"